@@ -15,6 +15,9 @@
 
 use encoding;
 use cryptobuf::CryptoBuf;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std;
 
 /// Set of methods, represented by bit flags.
 bitflags! {
@@ -22,7 +25,8 @@ bitflags! {
         const NONE = 1,
         const PASSWORD = 2,
         const PUBLICKEY = 4,
-        const HOSTBASED = 8
+        const HOSTBASED = 8,
+        const KEYBOARD_INTERACTIVE = 16
     }
 }
 
@@ -45,19 +49,52 @@ impl Iterator for MethodSet {
         iter!(self, PASSWORD);
         iter!(self, PUBLICKEY);
         iter!(self, HOSTBASED);
+        iter!(self, KEYBOARD_INTERACTIVE);
         None
     }
 }
 
 #[derive(Debug)]
 pub enum Method<K> {
-    // None,
+    None,
     Password {
         password: String,
     },
     PublicKey {
         key: K,
-    }, // Hostbased,
+    },
+    Hostbased {
+        key: K,
+        host_name: String,
+        client_user: String,
+    },
+    KeyboardInteractive {
+        submethods: String,
+    },
+}
+
+impl<K> Method<K> {
+    /// The flag of this method in a `MethodSet`.
+    pub fn method_set(&self) -> MethodSet {
+        match *self {
+            Method::None => NONE,
+            Method::Password { .. } => PASSWORD,
+            Method::PublicKey { .. } => PUBLICKEY,
+            Method::Hostbased { .. } => HOSTBASED,
+            Method::KeyboardInteractive { .. } => KEYBOARD_INTERACTIVE,
+        }
+    }
+}
+
+/// An entry of the client's authentication chain, see
+/// `client::Session::set_auth_chain`.
+#[derive(Debug)]
+pub enum Authenticator<K> {
+    /// Try this method.
+    Method(Method<K>),
+    /// Get passwords from `client::Handler::auth_password`, and try
+    /// them until one succeeds or the handler gives up.
+    Password,
 }
 
 impl encoding::Bytes for MethodSet {
@@ -67,6 +104,7 @@ impl encoding::Bytes for MethodSet {
             PASSWORD => b"password",
             PUBLICKEY => b"publickey",
             HOSTBASED => b"hostbased",
+            KEYBOARD_INTERACTIVE => b"keyboard-interactive",
             _ => b"",
         }
     }
@@ -79,11 +117,92 @@ impl MethodSet {
             b"password" => Some(PASSWORD),
             b"publickey" => Some(PUBLICKEY),
             b"hostbased" => Some(HOSTBASED),
+            b"keyboard-interactive" => Some(KEYBOARD_INTERACTIVE),
             _ => None,
         }
     }
 }
 
+/// The answer of a server to an authentication request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Auth {
+    Accept,
+    Reject,
+    /// This method succeeded, but the client must still authenticate
+    /// with one of `methods`, as the same user, for instance to
+    /// require both a public key and a one-time password
+    /// ([RFC4252](https://tools.ietf.org/html/rfc4252#section-5.1)).
+    Partial { methods: MethodSet },
+    /// The decision will be made later, and given to
+    /// `server::Session::complete_auth`, or to the `complete_*_auth`
+    /// method of the request's method. Meanwhile, the connection
+    /// keeps processing packets, and the client's next requests are
+    /// answered after this one.
+    Pending,
+}
+
+/// The answer of a server to a password request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Password {
+    Accept,
+    Reject,
+    /// See `Auth::Partial`.
+    Partial { methods: MethodSet },
+    /// The password has expired, ask the client for a new one
+    /// ([RFC4252](https://tools.ietf.org/html/rfc4252#section-8)).
+    ChangeRequest { prompt: Cow<'static, str> },
+    /// See `Auth::Pending`. Only pending password requests can be
+    /// completed with `ChangeRequest`, using
+    /// `server::Session::complete_password_auth`.
+    Pending,
+}
+
+impl From<Auth> for Password {
+    fn from(auth: Auth) -> Password {
+        match auth {
+            Auth::Accept => Password::Accept,
+            Auth::Reject => Password::Reject,
+            Auth::Partial { methods } => Password::Partial { methods: methods },
+            Auth::Pending => Password::Pending,
+        }
+    }
+}
+
+/// A limit on authentication attempts, after which the server
+/// disconnects the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The client was rejected `max_auth_attempts` times.
+    MaxAuthAttempts,
+    /// The client was not authenticated within `login_grace_time`.
+    LoginGraceTime,
+}
+
+/// A prompt of a keyboard-interactive request
+/// ([RFC4256](https://tools.ietf.org/html/rfc4256#section-3.2)).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    pub prompt: Cow<'static, str>,
+    /// Whether the client should echo the answer.
+    pub echo: bool,
+}
+
+/// The answer of a server to a keyboard-interactive request.
+#[derive(Debug, Clone)]
+pub enum KeyboardInteractive {
+    Accept,
+    Reject,
+    /// See `Auth::Partial`.
+    Partial { methods: MethodSet },
+    /// Ask the client another round of questions. `name` and
+    /// `instructions` may be empty.
+    Prompts {
+        name: Cow<'static, str>,
+        instructions: Cow<'static, str>,
+        prompts: Vec<Prompt>,
+    },
+}
+
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct AuthRequest {
@@ -93,4 +212,67 @@ pub struct AuthRequest {
     pub public_key_algorithm: CryptoBuf,
     pub public_key_is_ok: bool,
     pub sent_pk_ok: bool,
+    /// The user of the last partially successful request. All
+    /// subsequent requests must be for the same user.
+    pub partial_user: Option<String>,
+    pub current: Option<CurrentRequest>,
+    /// Number of rejected requests.
+    pub failures: usize,
+    /// The last user for which the server asked for a banner.
+    pub banner_user: Option<String>,
+    /// The custom service requested by the last request, or `None`
+    /// for "ssh-connection".
+    pub service: Option<String>,
+    /// Requests received while a decision was pending.
+    pub queued: VecDeque<Vec<u8>>,
+}
+
+/// A multi-message authentication request in progress.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum CurrentRequest {
+    /// The server has sent `n_prompts` prompts, and is waiting for
+    /// `SSH_MSG_USERAUTH_INFO_RESPONSE`.
+    KeyboardInteractive {
+        submethods: String,
+        n_prompts: usize,
+    },
+    /// The handler returned `Pending` to a request received at `t0`.
+    Pending {
+        t0: std::time::Instant,
+        request: RequestKind,
+    },
+}
+
+/// The kind of an authentication request, which decides whether its
+/// rejection is a failure, and how a pending decision is completed.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    None,
+    Password,
+    /// A public key probe, answered with `SSH_MSG_USERAUTH_PK_OK`
+    /// unless rejected.
+    PublicKeyProbe,
+    /// A signed public key request.
+    PublicKey,
+    Hostbased,
+    /// The first keyboard-interactive request, before any prompt.
+    KeyboardInteractive,
+    KeyboardInteractiveResponse,
+}
+
+impl RequestKind {
+    /// Whether rejecting this request counts as a failure.
+    pub fn is_attempt(&self) -> bool {
+        match *self {
+            RequestKind::Password |
+            RequestKind::PublicKey |
+            RequestKind::Hostbased |
+            RequestKind::KeyboardInteractiveResponse => true,
+            RequestKind::None |
+            RequestKind::PublicKeyProbe |
+            RequestKind::KeyboardInteractive => false,
+        }
+    }
 }
@@ -0,0 +1,442 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Some parts of this module come from sodiumoxide, (c) 2013 Daniel Ashhami, under an MIT licence.
+
+use libsodium_sys;
+use libc::{c_int, c_ulonglong, c_void, size_t};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::dsa::{Dsa, DsaSig};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{self, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use openssl::symm;
+use super::{Cbc, Curve, Hash, RsaSecretKey};
+
+extern "C" {
+    fn sodium_mlock(p: *mut c_void, len: size_t) -> c_int;
+    fn sodium_munlock(p: *mut c_void, len: size_t) -> c_int;
+    fn crypto_stream_chacha20_xor_ic(c: *mut u8,
+                                     m: *const u8,
+                                     mlen: c_ulonglong,
+                                     n: *const u8,
+                                     ic: u64,
+                                     k: *const u8)
+                                     -> c_int;
+}
+
+/// Primitives implemented by libsodium, and by OpenSSL for those
+/// libsodium doesn't have.
+#[derive(Debug)]
+pub struct Libsodium;
+
+impl super::Backend for Libsodium {
+    fn init() -> bool {
+        unsafe { libsodium_sys::sodium_init() != -1 }
+    }
+
+    fn memcmp(x: &[u8], y: &[u8]) -> bool {
+        if x.len() != y.len() {
+            return false;
+        }
+        unsafe { libsodium_sys::sodium_memcmp(x.as_ptr(), y.as_ptr(), x.len()) == 0 }
+    }
+
+    unsafe fn mlock(p: *mut u8, len: usize) {
+        sodium_mlock(p as *mut c_void, len);
+    }
+
+    unsafe fn munlock(p: *mut u8, len: usize) {
+        sodium_munlock(p as *mut c_void, len);
+    }
+
+    fn randombytes(buf: &mut [u8]) {
+        unsafe {
+            libsodium_sys::randombytes_buf(buf.as_mut_ptr(), buf.len());
+        }
+    }
+
+    fn chacha20_xor(m: &mut [u8], nonce: &[u8; 8], ic: u64, key: &[u8; 32]) {
+        unsafe {
+            let p = m.as_mut_ptr();
+            crypto_stream_chacha20_xor_ic(p,
+                                          p,
+                                          m.len() as c_ulonglong,
+                                          nonce.as_ptr(),
+                                          ic,
+                                          key.as_ptr());
+        }
+    }
+
+    fn poly1305(tag: &mut [u8; 16], m: &[u8], key: &[u8; 32]) {
+        unsafe {
+            libsodium_sys::crypto_onetimeauth_poly1305(tag, m.as_ptr(), m.len() as c_ulonglong, key);
+        }
+    }
+
+    fn sha256(digest: &mut [u8; 32], m: &[u8]) {
+        unsafe {
+            libsodium_sys::crypto_hash_sha256(digest, m.as_ptr(), m.len() as c_ulonglong);
+        }
+    }
+
+    fn curve25519(q: &mut [u8; 32], n: &[u8; 32], p: &[u8; 32]) {
+        unsafe {
+            libsodium_sys::crypto_scalarmult_curve25519(q, n, p);
+        }
+    }
+
+    fn curve25519_base(q: &mut [u8; 32], n: &[u8; 32]) {
+        unsafe {
+            libsodium_sys::crypto_scalarmult_curve25519_base(q, n);
+        }
+    }
+
+    fn ed25519_keypair(pk: &mut [u8; 32], sk: &mut [u8; 64]) -> bool {
+        unsafe { libsodium_sys::crypto_sign_ed25519_keypair(pk, sk) == 0 }
+    }
+
+    fn ed25519_sign(sig: &mut [u8; 64], m: &[u8], sk: &[u8; 64]) {
+        unsafe {
+            let mut siglen: c_ulonglong = 0;
+            libsodium_sys::crypto_sign_ed25519_detached(sig,
+                                                        &mut siglen,
+                                                        m.as_ptr(),
+                                                        m.len() as c_ulonglong,
+                                                        sk);
+            assert_eq!(siglen, 64);
+        }
+    }
+
+    fn ed25519_verify(sig: &[u8; 64], m: &[u8], pk: &[u8; 32]) -> bool {
+        unsafe {
+            libsodium_sys::crypto_sign_ed25519_verify_detached(sig,
+                                                               m.as_ptr(),
+                                                               m.len() as c_ulonglong,
+                                                               pk) == 0
+        }
+    }
+
+    fn hash(h: Hash, m: &[u8]) -> Vec<u8> {
+        hash::hash(digest(h), m).unwrap().to_vec()
+    }
+
+    fn hmac(h: Hash, key: &[u8], m: &[&[u8]]) -> Vec<u8> {
+        let key = PKey::hmac(key).unwrap();
+        let mut signer = Signer::new(digest(h), &key).unwrap();
+        for m in m {
+            signer.update(m).unwrap();
+        }
+        signer.sign_to_vec().unwrap()
+    }
+
+    fn aes_ctr_xor(key: &[u8], counter: &mut [u8; 16], m: &mut [u8]) {
+        let cipher = match key.len() {
+            16 => symm::Cipher::aes_128_ctr(),
+            24 => symm::Cipher::aes_192_ctr(),
+            _ => symm::Cipher::aes_256_ctr(),
+        };
+        update_inplace(cipher, symm::Mode::Encrypt, key, counter, m);
+        super::increment_counter(counter, m.len() / 16)
+    }
+
+    fn cbc_encrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]) {
+        if m.is_empty() {
+            return;
+        }
+        update_inplace(cbc(c), symm::Mode::Encrypt, key, iv, m);
+        let len = m.len();
+        iv.clone_from_slice(&m[len - iv.len()..]);
+    }
+
+    fn cbc_decrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]) {
+        if m.is_empty() {
+            return;
+        }
+        let len = m.len();
+        let next_iv = m[len - iv.len()..].to_vec();
+        update_inplace(cbc(c), symm::Mode::Decrypt, key, iv, m);
+        iv.clone_from_slice(&next_iv);
+    }
+
+    fn aes_gcm_seal(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8]) -> [u8; 16] {
+        let mut crypter = symm::Crypter::new(gcm(key), symm::Mode::Encrypt, key, Some(nonce))
+            .unwrap();
+        let mut ciphertext = vec![0; m.len() + 16];
+        crypter.aad_update(aad).unwrap();
+        let n = crypter.update(m, &mut ciphertext).unwrap();
+        crypter.finalize(&mut ciphertext[n..]).unwrap();
+        m.clone_from_slice(&ciphertext[0..m.len()]);
+        let mut tag = [0; 16];
+        crypter.get_tag(&mut tag).unwrap();
+        tag
+    }
+
+    fn aes_gcm_open(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8], tag: &[u8; 16])
+                    -> bool {
+        let open = || -> Result<Vec<u8>, ErrorStack> {
+            let mut crypter = try!(symm::Crypter::new(gcm(key),
+                                                      symm::Mode::Decrypt,
+                                                      key,
+                                                      Some(nonce)));
+            let mut plaintext = vec![0; m.len() + 16];
+            try!(crypter.aad_update(aad));
+            let n = try!(crypter.update(m, &mut plaintext));
+            try!(crypter.set_tag(tag));
+            try!(crypter.finalize(&mut plaintext[n..]));
+            Ok(plaintext)
+        };
+        match open() {
+            Ok(plaintext) => {
+                let len = m.len();
+                m.clone_from_slice(&plaintext[0..len]);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn ec_keypair(c: Curve) -> Option<(Vec<u8>, Vec<u8>)> {
+        ec_keypair(c).ok()
+    }
+
+    fn ecdh(c: Curve, secret: &[u8], public: &[u8]) -> Option<Vec<u8>> {
+        ecdh(c, secret, public).ok()
+    }
+
+    fn ecdsa_sign(c: Curve, secret: &[u8], m: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        ecdsa_sign(c, secret, m).ok()
+    }
+
+    fn ecdsa_verify(c: Curve, public: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool {
+        ecdsa_verify(c, public, m, r, s).unwrap_or(false)
+    }
+
+    fn rsa_verify(h: Hash, e: &[u8], n: &[u8], m: &[u8], sig: &[u8]) -> bool {
+        let key = BigNum::from_slice(n)
+            .and_then(|n| BigNum::from_slice(e).and_then(|e| Rsa::from_public_components(n, e)))
+            .and_then(PKey::from_rsa);
+        match key {
+            Ok(key) => verify(h, &key, m, sig).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    fn rsa_keypair(bits: usize) -> Option<RsaSecretKey> {
+        let key = match Rsa::generate(bits as u32) {
+            Ok(key) => key,
+            Err(_) => return None,
+        };
+        match (key.d(), key.p(), key.q()) {
+            (d, Some(p), Some(q)) => {
+                Some(RsaSecretKey {
+                    n: key.n().to_vec(),
+                    e: key.e().to_vec(),
+                    d: d.to_vec(),
+                    p: p.to_vec(),
+                    q: q.to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn rsa_sign(h: Hash, key: &RsaSecretKey, m: &[u8]) -> Option<Vec<u8>> {
+        rsa_sign(h, key, m).ok()
+    }
+
+    fn dsa_verify(p: &[u8], q: &[u8], g: &[u8], y: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool {
+        let key = BigNum::from_slice(p).and_then(|p| {
+            BigNum::from_slice(q).and_then(|q| {
+                BigNum::from_slice(g).and_then(|g| {
+                    BigNum::from_slice(y).and_then(|y| Dsa::from_public_components(p, q, g, y))
+                })
+            })
+        });
+        let sig = BigNum::from_slice(r)
+            .and_then(|r| BigNum::from_slice(s).and_then(|s| DsaSig::from_private_components(r, s)))
+            .and_then(|sig| sig.to_der());
+        match (key.and_then(PKey::from_dsa), sig) {
+            (Ok(key), Ok(sig)) => verify(Hash::Sha1, &key, m, &sig).unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn modexp(base: &[u8], exp: &[u8], modulus: &[u8]) -> Vec<u8> {
+        let modexp = || -> Result<Vec<u8>, ErrorStack> {
+            let mut result = try!(BigNum::new());
+            let mut ctx = try!(BigNumContext::new());
+            try!(result.mod_exp(&*try!(BigNum::from_slice(base)),
+                                &*try!(BigNum::from_slice(exp)),
+                                &*try!(BigNum::from_slice(modulus)),
+                                &mut ctx));
+            Ok(result.to_vec())
+        };
+        modexp().unwrap()
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_keypair(pk: &mut [u8], sk: &mut [u8]) -> bool {
+        super::mlkem::keypair(pk, sk)
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_encapsulate(ct: &mut [u8], ss: &mut [u8], pk: &[u8]) -> bool {
+        super::mlkem::encapsulate(ct, ss, pk)
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_decapsulate(ss: &mut [u8], ct: &[u8], sk: &[u8]) -> bool {
+        super::mlkem::decapsulate(ss, ct, sk)
+    }
+}
+
+fn digest(h: Hash) -> MessageDigest {
+    match h {
+        Hash::Sha1 => MessageDigest::sha1(),
+        Hash::Sha256 => MessageDigest::sha256(),
+        Hash::Sha384 => MessageDigest::sha384(),
+        Hash::Sha512 => MessageDigest::sha512(),
+    }
+}
+
+fn cbc(c: Cbc) -> symm::Cipher {
+    match c {
+        Cbc::Aes128 => symm::Cipher::aes_128_cbc(),
+        Cbc::TripleDes => symm::Cipher::des_ede3_cbc(),
+    }
+}
+
+fn gcm(key: &[u8]) -> symm::Cipher {
+    if key.len() == 16 {
+        symm::Cipher::aes_128_gcm()
+    } else {
+        symm::Cipher::aes_256_gcm()
+    }
+}
+
+/// Encrypt or decrypt `m` in place, without padding.
+fn update_inplace(cipher: symm::Cipher, mode: symm::Mode, key: &[u8], iv: &[u8], m: &mut [u8]) {
+    let mut crypter = symm::Crypter::new(cipher, mode, key, Some(iv)).unwrap();
+    crypter.pad(false);
+    let mut out = vec![0; m.len() + cipher.block_size()];
+    let n = crypter.update(m, &mut out).unwrap();
+    debug_assert_eq!(n, m.len());
+    m.clone_from_slice(&out[0..n]);
+}
+
+fn verify(h: Hash, key: &PKey<::openssl::pkey::Public>, m: &[u8], sig: &[u8])
+          -> Result<bool, ErrorStack> {
+    let mut verifier = try!(Verifier::new(digest(h), key));
+    try!(verifier.update(m));
+    verifier.verify(sig)
+}
+
+fn curve(c: Curve) -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(match c {
+        Curve::P256 => Nid::X9_62_PRIME256V1,
+        Curve::P384 => Nid::SECP384R1,
+        Curve::P521 => Nid::SECP521R1,
+    })
+}
+
+fn ec_keypair(c: Curve) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let group = try!(curve(c));
+    let key = try!(EcKey::generate(&group));
+    let mut ctx = try!(BigNumContext::new());
+    let public =
+        try!(key.public_key().to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx));
+    let secret = try!(key.private_key().to_vec_padded(c.scalar_size() as i32));
+    Ok((secret, public))
+}
+
+fn ecdh(c: Curve, secret: &[u8], public: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let group = try!(curve(c));
+    let mut ctx = try!(BigNumContext::new());
+    let point = try!(EcPoint::from_bytes(&group, public, &mut ctx));
+    let remote = try!(EcKey::from_public_key(&group, &point));
+    // Reject points that are not on the curve.
+    try!(remote.check_key());
+    let secret = try!(BigNum::from_slice(secret));
+    let mut local_public = try!(EcPoint::new(&group));
+    try!(local_public.mul_generator2(&group, &secret, &mut ctx));
+    let local = try!(EcKey::from_private_components(&group, &secret, &local_public));
+    let local = try!(PKey::from_ec_key(local));
+    let remote = try!(PKey::from_ec_key(remote));
+    let mut deriver = try!(Deriver::new(&local));
+    try!(deriver.set_peer(&remote));
+    deriver.derive_to_vec()
+}
+
+fn ecdsa_sign(c: Curve, secret: &[u8], m: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let group = try!(curve(c));
+    let mut ctx = try!(BigNumContext::new());
+    let secret = try!(BigNum::from_slice(secret));
+    let mut public = try!(EcPoint::new(&group));
+    try!(public.mul_generator2(&group, &secret, &mut ctx));
+    let key = try!(EcKey::from_private_components(&group, &secret, &public));
+    let hash = try!(hash::hash(digest(c.hash()), m));
+    let sig = try!(EcdsaSig::sign(&hash, &key));
+    Ok((sig.r().to_vec(), sig.s().to_vec()))
+}
+
+fn ecdsa_verify(c: Curve, public: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> Result<bool, ErrorStack> {
+    let group = try!(curve(c));
+    let mut ctx = try!(BigNumContext::new());
+    let point = try!(EcPoint::from_bytes(&group, public, &mut ctx));
+    let key = try!(EcKey::from_public_key(&group, &point));
+    try!(key.check_key());
+    let sig = try!(EcdsaSig::from_private_components(try!(BigNum::from_slice(r)),
+                                                     try!(BigNum::from_slice(s))));
+    let hash = try!(hash::hash(digest(c.hash()), m));
+    sig.verify(&hash, &key)
+}
+
+fn rsa_sign(h: Hash, key: &RsaSecretKey, m: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut ctx = try!(BigNumContext::new());
+    let one = try!(BigNum::from_u32(1));
+    let (p, q, d) = (try!(BigNum::from_slice(&key.p)),
+                     try!(BigNum::from_slice(&key.q)),
+                     try!(BigNum::from_slice(&key.d)));
+    // The CRT parameters d mod (p-1), d mod (q-1) and q^-1 mod p.
+    let mut p_1 = try!(BigNum::new());
+    try!(p_1.checked_sub(&p, &one));
+    let mut q_1 = try!(BigNum::new());
+    try!(q_1.checked_sub(&q, &one));
+    let mut dmp1 = try!(BigNum::new());
+    try!(dmp1.nnmod(&d, &p_1, &mut ctx));
+    let mut dmq1 = try!(BigNum::new());
+    try!(dmq1.nnmod(&d, &q_1, &mut ctx));
+    let mut iqmp = try!(BigNum::new());
+    try!(iqmp.mod_inverse(&q, &p, &mut ctx));
+    let rsa = try!(Rsa::from_private_components(try!(BigNum::from_slice(&key.n)),
+                                                try!(BigNum::from_slice(&key.e)),
+                                                d,
+                                                p,
+                                                q,
+                                                dmp1,
+                                                dmq1,
+                                                iqmp));
+    let key = try!(PKey::from_rsa(rsa));
+    let mut signer = try!(Signer::new(digest(h), &key));
+    try!(signer.update(m));
+    signer.sign_to_vec()
+}
@@ -0,0 +1,133 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// ML-KEM-768, from the default provider of OpenSSL 3.5 or later,
+// used by both backends. The OpenSSL bindings don't wrap
+// encapsulation yet, hence the calls to openssl-sys.
+
+use libc::c_char;
+use openssl_sys as ffi;
+use std::ptr;
+use super::{MLKEM768_CIPHERTEXTBYTES, MLKEM768_PUBLICKEYBYTES, MLKEM768_SECRETKEYBYTES};
+
+const NAME: &'static [u8] = b"ML-KEM-768\0";
+
+struct Ctx(*mut ffi::EVP_PKEY_CTX);
+
+impl Drop for Ctx {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_PKEY_CTX_free(self.0) }
+    }
+}
+
+struct Key(*mut ffi::EVP_PKEY);
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        unsafe { ffi::EVP_PKEY_free(self.0) }
+    }
+}
+
+impl Key {
+    fn context(&self) -> Option<Ctx> {
+        let ctx = unsafe { ffi::EVP_PKEY_CTX_new(self.0, ptr::null_mut()) };
+        if ctx.is_null() { None } else { Some(Ctx(ctx)) }
+    }
+}
+
+pub fn keypair(pk: &mut [u8], sk: &mut [u8]) -> bool {
+    assert_eq!(pk.len(), MLKEM768_PUBLICKEYBYTES);
+    assert_eq!(sk.len(), MLKEM768_SECRETKEYBYTES);
+    unsafe {
+        ffi::init();
+        let ctx = Ctx(ffi::EVP_PKEY_CTX_new_from_name(ptr::null_mut(),
+                                                       NAME.as_ptr() as *const c_char,
+                                                       ptr::null()));
+        if ctx.0.is_null() || ffi::EVP_PKEY_keygen_init(ctx.0) <= 0 {
+            return false;
+        }
+        let mut key = ptr::null_mut();
+        if ffi::EVP_PKEY_generate(ctx.0, &mut key) <= 0 {
+            return false;
+        }
+        let key = Key(key);
+        let mut pk_len = pk.len();
+        let mut sk_len = sk.len();
+        ffi::EVP_PKEY_get_raw_public_key(key.0, pk.as_mut_ptr(), &mut pk_len) > 0 &&
+        ffi::EVP_PKEY_get_raw_private_key(key.0, sk.as_mut_ptr(), &mut sk_len) > 0 &&
+        pk_len == pk.len() && sk_len == sk.len()
+    }
+}
+
+pub fn encapsulate(ct: &mut [u8], ss: &mut [u8], pk: &[u8]) -> bool {
+    assert_eq!(ct.len(), MLKEM768_CIPHERTEXTBYTES);
+    assert_eq!(ss.len(), 32);
+    if pk.len() != MLKEM768_PUBLICKEYBYTES {
+        return false;
+    }
+    unsafe {
+        ffi::init();
+        // OpenSSL checks the encapsulation key (FIPS 203, section 7.2).
+        let key = Key(ffi::EVP_PKEY_new_raw_public_key_ex(ptr::null_mut(),
+                                                          NAME.as_ptr() as *const c_char,
+                                                          ptr::null(),
+                                                          pk.as_ptr(),
+                                                          pk.len()));
+        if key.0.is_null() {
+            return false;
+        }
+        let ctx = if let Some(ctx) = key.context() {
+            ctx
+        } else {
+            return false;
+        };
+        let mut ct_len = ct.len();
+        let mut ss_len = ss.len();
+        ffi::EVP_PKEY_encapsulate_init(ctx.0, ptr::null()) > 0 &&
+        ffi::EVP_PKEY_encapsulate(ctx.0,
+                                  ct.as_mut_ptr(),
+                                  &mut ct_len,
+                                  ss.as_mut_ptr(),
+                                  &mut ss_len) > 0 && ct_len == ct.len() &&
+        ss_len == ss.len()
+    }
+}
+
+pub fn decapsulate(ss: &mut [u8], ct: &[u8], sk: &[u8]) -> bool {
+    assert_eq!(ss.len(), 32);
+    if ct.len() != MLKEM768_CIPHERTEXTBYTES || sk.len() != MLKEM768_SECRETKEYBYTES {
+        return false;
+    }
+    unsafe {
+        ffi::init();
+        let key = Key(ffi::EVP_PKEY_new_raw_private_key_ex(ptr::null_mut(),
+                                                           NAME.as_ptr() as *const c_char,
+                                                           ptr::null(),
+                                                           sk.as_ptr(),
+                                                           sk.len()));
+        if key.0.is_null() {
+            return false;
+        }
+        let ctx = if let Some(ctx) = key.context() {
+            ctx
+        } else {
+            return false;
+        };
+        let mut ss_len = ss.len();
+        ffi::EVP_PKEY_decapsulate_init(ctx.0, ptr::null()) > 0 &&
+        ffi::EVP_PKEY_decapsulate(ctx.0, ss.as_mut_ptr(), &mut ss_len, ct.as_ptr(), ct.len()) >
+        0 && ss_len == ss.len()
+    }
+}
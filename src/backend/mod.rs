@@ -0,0 +1,570 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Implementations of the cryptographic primitives used by this
+//! crate.
+//!
+//! libsodium and OpenSSL are used by default (feature "libsodium").
+//! The "pure-rust" feature selects pure-Rust implementations instead,
+//! which makes it possible to build without C libraries, for
+//! instance for static musl builds, with `default-features = false`.
+//! Both backends give the same results (see the tests of this
+//! module).
+
+#[cfg(feature = "libsodium")]
+mod libsodium;
+#[cfg(feature = "libsodium")]
+pub use self::libsodium::Libsodium;
+#[cfg(feature = "pure-rust")]
+mod pure_rust;
+#[cfg(feature = "pure-rust")]
+pub use self::pure_rust::PureRust;
+#[cfg(feature = "mlkem")]
+mod mlkem;
+
+/// The backend used by this crate: `PureRust` if the "pure-rust"
+/// feature is enabled, `Libsodium` otherwise.
+#[cfg(feature = "pure-rust")]
+pub type Selected = PureRust;
+/// The backend used by this crate: `PureRust` if the "pure-rust"
+/// feature is enabled, `Libsodium` otherwise.
+#[cfg(all(feature = "libsodium", not(feature = "pure-rust")))]
+pub type Selected = Libsodium;
+
+/// A set of cryptographic primitives. Keys and signatures have the
+/// same formats as in libsodium, in particular ed25519 secret keys
+/// are the concatenation of the seed and the public key. Functions
+/// not provided by libsodium don't keep any state between calls.
+pub trait Backend {
+    /// Initialize the backend, return `false` on failure.
+    fn init() -> bool;
+    /// Compare `x` and `y` in constant time.
+    fn memcmp(x: &[u8], y: &[u8]) -> bool;
+    /// Lock `len` bytes starting at `p` in memory.
+    unsafe fn mlock(p: *mut u8, len: usize);
+    /// Zero the `len` bytes starting at `p`, and unlock them.
+    unsafe fn munlock(p: *mut u8, len: usize);
+    /// Fill `buf` with random bytes.
+    fn randombytes(buf: &mut [u8]);
+    /// Xor `m` with the ChaCha20 key stream (with a 64-bit nonce),
+    /// starting at block `ic`.
+    fn chacha20_xor(m: &mut [u8], nonce: &[u8; 8], ic: u64, key: &[u8; 32]);
+    fn poly1305(tag: &mut [u8; 16], m: &[u8], key: &[u8; 32]);
+    fn sha256(digest: &mut [u8; 32], m: &[u8]);
+    /// Multiply point `p` by scalar `n` (clamped).
+    fn curve25519(q: &mut [u8; 32], n: &[u8; 32], p: &[u8; 32]);
+    /// Multiply the base point by scalar `n` (clamped).
+    fn curve25519_base(q: &mut [u8; 32], n: &[u8; 32]);
+    fn ed25519_keypair(pk: &mut [u8; 32], sk: &mut [u8; 64]) -> bool;
+    fn ed25519_sign(sig: &mut [u8; 64], m: &[u8], sk: &[u8; 64]);
+    fn ed25519_verify(sig: &[u8; 64], m: &[u8], pk: &[u8; 32]) -> bool;
+
+    /// Hash `m` with `h`.
+    fn hash(h: Hash, m: &[u8]) -> Vec<u8>;
+    /// HMAC of the concatenation of `m`.
+    fn hmac(h: Hash, key: &[u8], m: &[&[u8]]) -> Vec<u8>;
+    /// Xor `m` with the AES key stream in counter mode, where
+    /// `counter` is a 128-bit big-endian integer, incremented after
+    /// each block. `key` is 16, 24 or 32 bytes long, and `m.len()`
+    /// is a multiple of 16.
+    fn aes_ctr_xor(key: &[u8], counter: &mut [u8; 16], m: &mut [u8]);
+    /// Encrypt `m` in place in CBC mode, and replace `iv` by the last
+    /// block of ciphertext. `m.len()` is a multiple of the block size.
+    fn cbc_encrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]);
+    /// Decrypt `m` in place in CBC mode, see `cbc_encrypt`.
+    fn cbc_decrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]);
+    /// Encrypt `m` in place with AES-GCM (with a 16 or 32 bytes key),
+    /// and return the tag authenticating `aad` and the ciphertext.
+    fn aes_gcm_seal(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8]) -> [u8; 16];
+    /// Decrypt `m` in place with AES-GCM, if `tag` is valid.
+    fn aes_gcm_open(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8], tag: &[u8; 16])
+                    -> bool;
+    /// Generate a key pair, made of a secret scalar and an
+    /// uncompressed public point.
+    fn ec_keypair(c: Curve) -> Option<(Vec<u8>, Vec<u8>)>;
+    /// The x coordinate of the product of `public` (an uncompressed
+    /// point) by `secret`, or `None` if `public` is not on the curve.
+    fn ecdh(c: Curve, secret: &[u8], public: &[u8]) -> Option<Vec<u8>>;
+    /// Sign `m` with ECDSA, hashed with the hash function of `c`, and
+    /// return the integers r and s (big-endian).
+    fn ecdsa_sign(c: Curve, secret: &[u8], m: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+    /// Verify an ECDSA signature `(r, s)` of `m` by `public` (an
+    /// uncompressed point).
+    fn ecdsa_verify(c: Curve, public: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool;
+    /// Verify a PKCS#1 v1.5 signature, with public exponent `e` and
+    /// modulus `n`.
+    fn rsa_verify(h: Hash, e: &[u8], n: &[u8], m: &[u8], sig: &[u8]) -> bool;
+    /// Generate an RSA key pair with a modulus of `bits` bits.
+    fn rsa_keypair(bits: usize) -> Option<RsaSecretKey>;
+    /// Sign `m` with PKCS#1 v1.5. The signature has the length of the
+    /// modulus.
+    fn rsa_sign(h: Hash, key: &RsaSecretKey, m: &[u8]) -> Option<Vec<u8>>;
+    /// Verify a DSA signature `(r, s)` with SHA-1, with parameters
+    /// `p`, `q` and `g`, and public key `y`.
+    fn dsa_verify(p: &[u8], q: &[u8], g: &[u8], y: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool;
+    /// `base` to the power `exp`, modulo `modulus`. All integers are
+    /// big-endian, and the result has no leading zeros.
+    fn modexp(base: &[u8], exp: &[u8], modulus: &[u8]) -> Vec<u8>;
+    /// Generate an ML-KEM-768 key pair (FIPS 203): the encapsulation
+    /// key `pk` and the decapsulation key `sk`.
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_keypair(pk: &mut [u8], sk: &mut [u8]) -> bool;
+    /// Encapsulate a 32-byte shared secret `ss` to `pk`, and write
+    /// the ciphertext to `ct`. This returns `false` if `pk` is
+    /// invalid.
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_encapsulate(ct: &mut [u8], ss: &mut [u8], pk: &[u8]) -> bool;
+    /// Decapsulate the shared secret of ciphertext `ct` with `sk`.
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_decapsulate(ss: &mut [u8], ct: &[u8], sk: &[u8]) -> bool;
+}
+
+/// Length of ML-KEM-768 encapsulation keys.
+#[cfg(feature = "mlkem")]
+pub const MLKEM768_PUBLICKEYBYTES: usize = 1184;
+/// Length of ML-KEM-768 decapsulation keys.
+#[cfg(feature = "mlkem")]
+pub const MLKEM768_SECRETKEYBYTES: usize = 2400;
+/// Length of ML-KEM-768 ciphertexts.
+#[cfg(feature = "mlkem")]
+pub const MLKEM768_CIPHERTEXTBYTES: usize = 1088;
+
+/// Hash functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+/// Block ciphers used in CBC mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cbc {
+    Aes128,
+    TripleDes,
+}
+
+impl Cbc {
+    pub fn block_size(&self) -> usize {
+        match *self {
+            Cbc::Aes128 => 16,
+            Cbc::TripleDes => 8,
+        }
+    }
+}
+
+/// NIST elliptic curves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    P256,
+    P384,
+    P521,
+}
+
+impl Curve {
+    /// Size of the secret scalars and of the coordinates.
+    pub fn scalar_size(&self) -> usize {
+        match *self {
+            Curve::P256 => 32,
+            Curve::P384 => 48,
+            Curve::P521 => 66,
+        }
+    }
+
+    /// The hash function used with this curve (RFC5656, section 6.2.1).
+    pub fn hash(&self) -> Hash {
+        match *self {
+            Curve::P256 => Hash::Sha256,
+            Curve::P384 => Hash::Sha384,
+            Curve::P521 => Hash::Sha512,
+        }
+    }
+}
+
+/// An RSA secret key. All integers are big-endian.
+#[derive(Clone)]
+pub struct RsaSecretKey {
+    pub n: Vec<u8>,
+    pub e: Vec<u8>,
+    pub d: Vec<u8>,
+    pub p: Vec<u8>,
+    pub q: Vec<u8>,
+}
+
+/// Add `blocks` to the big-endian integer `counter`, modulo 2^128.
+fn increment_counter(counter: &mut [u8; 16], blocks: usize) {
+    let mut carry = blocks as u64;
+    for x in counter.iter_mut().rev() {
+        if carry == 0 {
+            break;
+        }
+        let sum = *x as u64 + (carry & 0xff);
+        *x = sum as u8;
+        carry = (carry >> 8) + (sum >> 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    fn array32(s: &str) -> [u8; 32] {
+        let mut a = [0; 32];
+        a.clone_from_slice(&hex(s));
+        a
+    }
+
+    const P256_GX: &'static str = "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296";
+    const P256_GY: &'static str = "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5";
+
+    fn known_answers<B: Backend>() {
+        assert!(B::init());
+
+        let mut digest = [0; 32];
+        B::sha256(&mut digest, b"abc");
+        assert_eq!(&digest[..],
+                   &hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")[..]);
+        assert_eq!(B::hash(Hash::Sha256, b"abc"), digest.to_vec());
+        assert_eq!(B::hash(Hash::Sha1, b"abc"),
+                   hex("a9993e364706816aba3e25717850c26c9cd0d89d"));
+        assert_eq!(B::hash(Hash::Sha384, b"abc"),
+                   hex("cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+                        8086072ba1e7cc2358baeca134c825a7"));
+        assert_eq!(B::hash(Hash::Sha512, b"abc"),
+                   hex("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                        2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"));
+
+        // RFC4231, test case 1.
+        assert_eq!(B::hmac(Hash::Sha256, &[0x0b; 20], &[b"Hi ", b"There"]),
+                   hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"));
+
+        // Keystream blocks 0 and 1 of the zero key and nonce.
+        let mut stream = [0; 64];
+        B::chacha20_xor(&mut stream, &[0; 8], 1, &[0; 32]);
+        assert_eq!(&stream[..],
+                   &hex("9f07e7be5551387a98ba977c732d080dcb0f29a048e3656912c6533e32ee7aed\
+                         29b721769ce64e43d57133b074d839d531ed1f28510afb45ace10a1f4b794d6f")[..]);
+        let mut stream = [0; 32];
+        B::chacha20_xor(&mut stream, &[0; 8], 0, &[0; 32]);
+        assert_eq!(&stream[..],
+                   &hex("76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7")[..]);
+
+        // RFC8439, section 2.5.2.
+        let mut tag = [0; 16];
+        B::poly1305(&mut tag,
+                    b"Cryptographic Forum Research Group",
+                    &array32("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b"));
+        assert_eq!(&tag[..], &hex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+
+        // NIST SP800-38A, sections F.5.1 and F.2.1 (first two blocks).
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let plaintext = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        let mut counter = [0; 16];
+        counter.clone_from_slice(&hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+        let mut m = plaintext.clone();
+        B::aes_ctr_xor(&key, &mut counter, &mut m[..16]);
+        B::aes_ctr_xor(&key, &mut counter, &mut m[16..]);
+        assert_eq!(m,
+                   hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff"));
+        assert_eq!(&counter[..], &hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdff01")[..]);
+        // The counter wraps around.
+        let mut counter = [0xff; 16];
+        let mut m = [0; 32];
+        B::aes_ctr_xor(&key, &mut counter, &mut m);
+        assert_eq!(&m[..],
+                   &hex("8af2860142f786f409307c1a3f7eaaac7df76b0c1ab899b33e42f047b91b546f")[..]);
+        assert_eq!(counter, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let mut iv = hex("000102030405060708090a0b0c0d0e0f");
+        let mut m = plaintext.clone();
+        B::cbc_encrypt(Cbc::Aes128, &key, &mut iv, &mut m[..16]);
+        B::cbc_encrypt(Cbc::Aes128, &key, &mut iv, &mut m[16..]);
+        assert_eq!(m,
+                   hex("7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2"));
+        let mut iv = hex("000102030405060708090a0b0c0d0e0f");
+        B::cbc_decrypt(Cbc::Aes128, &key, &mut iv, &mut m[..16]);
+        B::cbc_decrypt(Cbc::Aes128, &key, &mut iv, &mut m[16..]);
+        assert_eq!(m, plaintext);
+
+        let key: Vec<u8> = (0..24).collect();
+        let mut m = [0; 16];
+        B::cbc_encrypt(Cbc::TripleDes, &key, &mut [0; 8], &mut m);
+        assert_eq!(&m[..], &hex("894bc3085426a441068edfb5f023abb4")[..]);
+        B::cbc_decrypt(Cbc::TripleDes, &key, &mut [0; 8], &mut m);
+        assert_eq!(m, [0; 16]);
+
+        // Test case 2 of the GCM specification.
+        let mut m = [0; 16];
+        let tag = B::aes_gcm_seal(&[0; 16], &[0; 12], b"", &mut m);
+        assert_eq!(&m[..], &hex("0388dace60b6a392f328c2b971b2fe78")[..]);
+        assert_eq!(&tag[..], &hex("ab6e47d42cec13bdf53a67b21257bddf")[..]);
+        assert!(!B::aes_gcm_open(&[0; 16], &[0; 12], b"aad", &mut m, &tag));
+        assert!(B::aes_gcm_open(&[0; 16], &[0; 12], b"", &mut m, &tag));
+        assert_eq!(m, [0; 16]);
+
+        // RFC7748, section 5.2.
+        let mut q = [0; 32];
+        B::curve25519(&mut q,
+                      &array32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                      &array32("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"));
+        assert_eq!(&q[..],
+                   &hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552")[..]);
+        let mut base = [0; 32];
+        B::curve25519_base(&mut base,
+                           &array32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"));
+        let mut nine = [0; 32];
+        nine[0] = 9;
+        B::curve25519(&mut q,
+                      &array32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                      &nine);
+        assert_eq!(base, q);
+
+        // The product of the generator of P-256 by 1.
+        let mut one = [0; 32];
+        one[31] = 1;
+        let generator = [&[4][..], &hex(P256_GX), &hex(P256_GY)].concat();
+        assert_eq!(B::ecdh(Curve::P256, &one, &generator), Some(hex(P256_GX)));
+        let mut off_curve = generator.clone();
+        off_curve[64] ^= 1;
+        assert_eq!(B::ecdh(Curve::P256, &one, &off_curve), None);
+        for &c in &[Curve::P256, Curve::P384, Curve::P521] {
+            let (a, a_pub) = B::ec_keypair(c).unwrap();
+            let (b, b_pub) = B::ec_keypair(c).unwrap();
+            assert_eq!(a.len(), c.scalar_size());
+            assert_eq!(a_pub.len(), 1 + 2 * c.scalar_size());
+            assert_eq!(B::ecdh(c, &a, &b_pub), B::ecdh(c, &b, &a_pub));
+            let (r, s) = B::ecdsa_sign(c, &a, b"message").unwrap();
+            assert!(B::ecdsa_verify(c, &a_pub, b"message", &r, &s));
+            assert!(!B::ecdsa_verify(c, &a_pub, b"massage", &r, &s));
+            assert!(!B::ecdsa_verify(c, &b_pub, b"message", &r, &s));
+            // Leading zeros are ignored.
+            assert!(B::ecdsa_verify(c, &a_pub, b"message", &[&[0][..], &r].concat(), &s));
+        }
+
+        // RFC8032, section 7.1, test 2.
+        let mut sk = [0; 64];
+        sk[..32].clone_from_slice(&hex("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb"));
+        sk[32..].clone_from_slice(&hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c"));
+        let mut pk = [0; 32];
+        pk.clone_from_slice(&sk[32..]);
+        let mut sig = [0; 64];
+        B::ed25519_sign(&mut sig, &[0x72], &sk);
+        assert_eq!(&sig[..],
+                   &hex("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                         085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00")[..]);
+        assert!(B::ed25519_verify(&sig, &[0x72], &pk));
+        assert!(!B::ed25519_verify(&sig, &[0x73], &pk));
+
+        let mut pk = [0; 32];
+        let mut sk = [0; 64];
+        assert!(B::ed25519_keypair(&mut pk, &mut sk));
+        B::ed25519_sign(&mut sig, b"message", &sk);
+        assert!(B::ed25519_verify(&sig, b"message", &pk));
+
+        // 3^200 mod 1000003.
+        assert_eq!(B::modexp(&[3], &[200], &[0x0f, 0x42, 0x43]), vec![0x05, 0x18, 0xa2]);
+        assert_eq!(B::modexp(&[0], &[5], &[7]), Vec::<u8>::new());
+
+        assert!(B::memcmp(b"abc", b"abc"));
+        assert!(!B::memcmp(b"abc", b"abd"));
+        assert!(!B::memcmp(b"abc", b"ab"));
+    }
+
+    #[cfg(feature = "libsodium")]
+    #[test]
+    fn libsodium_known_answers() {
+        known_answers::<Libsodium>()
+    }
+
+    #[cfg(feature = "pure-rust")]
+    #[test]
+    fn pure_rust_known_answers() {
+        known_answers::<PureRust>()
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn mlkem768() {
+        let mut pk = vec![0; MLKEM768_PUBLICKEYBYTES];
+        let mut sk = vec![0; MLKEM768_SECRETKEYBYTES];
+        let mut ct = vec![0; MLKEM768_CIPHERTEXTBYTES];
+        let (mut a, mut b) = ([0; 32], [0; 32]);
+        assert!(Selected::mlkem768_keypair(&mut pk, &mut sk));
+        assert!(Selected::mlkem768_encapsulate(&mut ct, &mut a, &pk));
+        assert!(Selected::mlkem768_decapsulate(&mut b, &ct, &sk));
+        assert_eq!(a, b);
+        // A modified ciphertext is implicitly rejected: it decapsulates
+        // to an unrelated secret.
+        ct[0] ^= 1;
+        assert!(Selected::mlkem768_decapsulate(&mut b, &ct, &sk));
+        assert!(a != b);
+        assert!(!Selected::mlkem768_encapsulate(&mut ct, &mut a, &pk[1..]));
+        assert!(!Selected::mlkem768_decapsulate(&mut b, &ct[1..], &sk));
+    }
+
+    /// A key exchange with fixed secrets, followed by a few packets
+    /// in each of the modes used by this crate, and signatures: the
+    /// bytes that would be sent on the wire.
+    #[cfg(all(feature = "libsodium", feature = "pure-rust"))]
+    fn transcript<B: Backend>() -> Vec<u8> {
+        let mut t = Vec::new();
+        let (a, b) = ([1; 32], [2; 32]);
+        let mut a_pub = [0; 32];
+        let mut b_pub = [0; 32];
+        let mut shared = [0; 32];
+        B::curve25519_base(&mut a_pub, &a);
+        B::curve25519_base(&mut b_pub, &b);
+        B::curve25519(&mut shared, &a, &b_pub);
+        t.extend(&a_pub);
+        t.extend(&b_pub);
+        t.extend(&shared);
+        let mut h = [0; 32];
+        B::sha256(&mut h, &t);
+        // Keys derived as in RFC4253, section 7.2.
+        let derive = |c: u8, hash: Hash| B::hash(hash, &[&shared[..], &h, &[c], &h].concat());
+        let keys: Vec<Vec<u8>> = [Hash::Sha1, Hash::Sha256, Hash::Sha384, Hash::Sha512]
+            .iter()
+            .enumerate()
+            .map(|(i, &hash)| derive(b'A' + i as u8, hash))
+            .collect();
+
+        let mut counter = [0; 16];
+        counter.clone_from_slice(&keys[0][..16]);
+        let mut cbc_iv = keys[1][..16].to_vec();
+        let mut des_iv = keys[1][16..24].to_vec();
+        for seqn in 0..3u8 {
+            let packet: Vec<u8> = (0..64).map(|i| i ^ seqn).collect();
+            for &len in &[16, 24, 32] {
+                let mut m = packet.clone();
+                B::aes_ctr_xor(&keys[3][..len], &mut counter, &mut m);
+                t.extend(&m);
+            }
+            let mut m = packet.clone();
+            B::cbc_encrypt(Cbc::Aes128, &keys[2][..16], &mut cbc_iv, &mut m);
+            t.extend(&m);
+            let mut m = packet.clone();
+            B::cbc_encrypt(Cbc::TripleDes, &keys[2][..24], &mut des_iv, &mut m);
+            t.extend(&m);
+            for &len in &[16, 32] {
+                let mut nonce = [seqn; 12];
+                nonce[..4].clone_from_slice(&keys[0][..4]);
+                let mut m = packet.clone();
+                let tag = B::aes_gcm_seal(&keys[3][..len], &nonce, &[seqn], &mut m);
+                t.extend(&m);
+                t.extend(&tag);
+            }
+            let mut m = packet.clone();
+            let mut chacha_key = [0; 32];
+            chacha_key.clone_from_slice(&keys[1]);
+            B::chacha20_xor(&mut m, &[seqn; 8], 1, &chacha_key);
+            let mut tag = [0; 16];
+            B::poly1305(&mut tag, &m, &chacha_key);
+            t.extend(&m);
+            t.extend(&tag);
+            for &hash in &[Hash::Sha1, Hash::Sha256, Hash::Sha512] {
+                t.extend(B::hmac(hash, &keys[2], &[&[0, 0, 0, seqn], &packet]));
+            }
+        }
+
+        // RFC8032, section 7.1, test 1.
+        let mut sk = [0; 64];
+        sk[..32].clone_from_slice(&hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60"));
+        sk[32..].clone_from_slice(&hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"));
+        let mut sig = [0; 64];
+        B::ed25519_sign(&mut sig, &h, &sk);
+        t.extend(&sig[..]);
+
+        let mut modulus = keys[3].clone();
+        *modulus.last_mut().unwrap() |= 1;
+        t.extend(B::modexp(&shared, &h, &modulus));
+        t
+    }
+
+    #[cfg(all(feature = "libsodium", feature = "pure-rust"))]
+    #[test]
+    fn backends_agree() {
+        assert_eq!(transcript::<Libsodium>(), transcript::<PureRust>());
+
+        // ECDH between key pairs of both backends.
+        for &c in &[Curve::P256, Curve::P384, Curve::P521] {
+            let (a, a_pub) = Libsodium::ec_keypair(c).unwrap();
+            let (b, b_pub) = PureRust::ec_keypair(c).unwrap();
+            let shared = Libsodium::ecdh(c, &a, &b_pub);
+            assert!(shared.is_some());
+            assert_eq!(shared, PureRust::ecdh(c, &a, &b_pub));
+            assert_eq!(shared, Libsodium::ecdh(c, &b, &a_pub));
+            assert_eq!(shared, PureRust::ecdh(c, &b, &a_pub));
+
+            let (r, s) = Libsodium::ecdsa_sign(c, &a, b"message").unwrap();
+            assert!(PureRust::ecdsa_verify(c, &a_pub, b"message", &r, &s));
+            let (r, s) = PureRust::ecdsa_sign(c, &a, b"message").unwrap();
+            assert!(Libsodium::ecdsa_verify(c, &a_pub, b"message", &r, &s));
+        }
+
+        // PKCS#1 v1.5 signatures are deterministic.
+        let key = Libsodium::rsa_keypair(2048).unwrap();
+        for &hash in &[Hash::Sha256, Hash::Sha512] {
+            let sig = Libsodium::rsa_sign(hash, &key, b"message");
+            assert!(sig.is_some());
+            assert_eq!(sig, PureRust::rsa_sign(hash, &key, b"message"));
+            let sig = sig.unwrap();
+            assert!(PureRust::rsa_verify(hash, &key.e, &key.n, b"message", &sig));
+            assert!(Libsodium::rsa_verify(hash, &key.e, &key.n, b"message", &sig));
+        }
+    }
+
+    #[cfg(all(feature = "libsodium", feature = "pure-rust", feature = "legacy"))]
+    #[test]
+    fn backends_agree_on_legacy_signatures() {
+        use openssl::dsa::Dsa;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::sign::Signer;
+
+        let rsa = Rsa::generate(2048).unwrap();
+        let (e, n) = (rsa.e().to_vec(), rsa.n().to_vec());
+        let key = PKey::from_rsa(rsa).unwrap();
+        for &(hash, digest) in &[(Hash::Sha1, MessageDigest::sha1()),
+                                 (Hash::Sha256, MessageDigest::sha256()),
+                                 (Hash::Sha512, MessageDigest::sha512())] {
+            let mut signer = Signer::new(digest, &key).unwrap();
+            signer.update(b"message").unwrap();
+            let sig = signer.sign_to_vec().unwrap();
+            assert!(Libsodium::rsa_verify(hash, &e, &n, b"message", &sig));
+            assert!(PureRust::rsa_verify(hash, &e, &n, b"message", &sig));
+            assert!(!Libsodium::rsa_verify(hash, &e, &n, b"massage", &sig));
+            assert!(!PureRust::rsa_verify(hash, &e, &n, b"massage", &sig));
+        }
+
+        let dsa = Dsa::generate(1024).unwrap();
+        let (p, q, g, y) = (dsa.p().to_vec(), dsa.q().to_vec(), dsa.g().to_vec(), dsa.pub_key().to_vec());
+        let key = PKey::from_dsa(dsa).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha1(), &key).unwrap();
+        signer.update(b"message").unwrap();
+        let der = signer.sign_to_vec().unwrap();
+        let sig = openssl::dsa::DsaSig::from_der(&der).unwrap();
+        let (r, s) = (sig.r().to_vec(), sig.s().to_vec());
+        for &(m, valid) in &[(&b"message"[..], true), (&b"massage"[..], false)] {
+            assert_eq!(Libsodium::dsa_verify(&p, &q, &g, &y, m, &r, &s), valid);
+            assert_eq!(PureRust::dsa_verify(&p, &q, &g, &y, m, &r, &s), valid);
+        }
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use chacha20::ChaCha20Legacy;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use poly1305::Poly1305;
+use poly1305::universal_hash::KeyInit;
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512, Digest};
+use hmac::{Hmac, Mac};
+use aes::{Aes128, Aes192, Aes256};
+use ctr::Ctr128BE;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut};
+use cbc::cipher::block_padding::NoPadding;
+use des::TdesEde3;
+use aes_gcm::{AeadInPlace, Aes128Gcm, Aes256Gcm};
+use p256;
+use p384;
+use p521;
+use rsa::{self, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use rsa::rand_core::OsRng;
+use dsa;
+use dsa::signature::hazmat::PrehashVerifier;
+use super::{Cbc, Curve, Hash, RsaSecretKey};
+use ed25519_dalek::{SigningKey, VerifyingKey, Signature, Signer, Verifier};
+use getrandom;
+use x25519_dalek;
+use libc::{self, c_void};
+use std::ptr;
+
+// A random key pair on curve `$curve`, whose secret scalar is drawn
+// from random bytes until it is valid.
+macro_rules! ec_keypair (($curve:ident, $c:expr) => {{
+    use $curve::elliptic_curve::sec1::ToEncodedPoint;
+    let mut bytes = vec![0; $c.scalar_size()];
+    loop {
+        if getrandom::getrandom(&mut bytes).is_err() {
+            return None;
+        }
+        if $c == Curve::P521 {
+            // The order of P-521 is a 521-bit integer.
+            bytes[0] &= 1
+        }
+        if let Ok(secret) = $curve::SecretKey::from_slice(&bytes) {
+            let public = secret.public_key().to_encoded_point(false);
+            return Some((secret.to_bytes().to_vec(), public.as_bytes().to_vec()));
+        }
+    }
+}});
+
+macro_rules! ecdh (($curve:ident, $secret:expr, $public:expr) => {{
+    match ($curve::SecretKey::from_slice($secret), $curve::PublicKey::from_sec1_bytes($public)) {
+        (Ok(secret), Ok(public)) => {
+            let shared = $curve::ecdh::diffie_hellman(secret.to_nonzero_scalar(),
+                                                       public.as_affine());
+            Some(shared.raw_secret_bytes().to_vec())
+        }
+        _ => None,
+    }
+}});
+
+macro_rules! ecdsa_sign (($curve:ident, $secret:expr, $m:expr) => {{
+    use $curve::ecdsa::signature::Signer;
+    match $curve::ecdsa::SigningKey::from_slice($secret) {
+        Ok(key) => {
+            let sig: $curve::ecdsa::Signature = key.sign($m);
+            let (r, s) = sig.split_bytes();
+            Some((r.to_vec(), s.to_vec()))
+        }
+        Err(_) => None,
+    }
+}});
+
+macro_rules! ecdsa_verify (($curve:ident, $c:expr, $public:expr, $m:expr, $r:expr, $s:expr) => {{
+    use $curve::ecdsa::signature::Verifier;
+    let sig = match (fixed_size($r, $c.scalar_size()), fixed_size($s, $c.scalar_size())) {
+        (Some(r), Some(s)) => $curve::ecdsa::Signature::from_slice(&[r, s].concat()),
+        _ => return false,
+    };
+    match (sig, $curve::ecdsa::VerifyingKey::from_sec1_bytes($public)) {
+        (Ok(sig), Ok(key)) => key.verify($m, &sig).is_ok(),
+        _ => false,
+    }
+}});
+
+/// Primitives implemented in Rust, from the RustCrypto and dalek
+/// crates. Random bytes come from the operating system.
+#[derive(Debug)]
+pub struct PureRust;
+
+impl super::Backend for PureRust {
+    fn init() -> bool {
+        true
+    }
+
+    fn memcmp(x: &[u8], y: &[u8]) -> bool {
+        if x.len() != y.len() {
+            return false;
+        }
+        let mut d = 0;
+        for (a, b) in x.iter().zip(y.iter()) {
+            d |= a ^ b;
+        }
+        // Keep the compiler from short-circuiting the loop above.
+        unsafe { ptr::read_volatile(&d) == 0 }
+    }
+
+    unsafe fn mlock(p: *mut u8, len: usize) {
+        libc::mlock(p as *const c_void, len);
+    }
+
+    unsafe fn munlock(p: *mut u8, len: usize) {
+        for i in 0..len {
+            ptr::write_volatile(p.offset(i as isize), 0)
+        }
+        libc::munlock(p as *const c_void, len);
+    }
+
+    fn randombytes(buf: &mut [u8]) {
+        getrandom::getrandom(buf).expect("no random source available")
+    }
+
+    fn chacha20_xor(m: &mut [u8], nonce: &[u8; 8], ic: u64, key: &[u8; 32]) {
+        let mut c = ChaCha20Legacy::new(key.into(), nonce.into());
+        c.seek(ic * 64);
+        c.apply_keystream(m)
+    }
+
+    fn poly1305(tag: &mut [u8; 16], m: &[u8], key: &[u8; 32]) {
+        let t = Poly1305::new(key.into()).compute_unpadded(m);
+        tag.clone_from_slice(&t)
+    }
+
+    fn sha256(digest: &mut [u8; 32], m: &[u8]) {
+        digest.clone_from_slice(&Sha256::digest(m))
+    }
+
+    fn curve25519(q: &mut [u8; 32], n: &[u8; 32], p: &[u8; 32]) {
+        *q = x25519_dalek::x25519(*n, *p)
+    }
+
+    fn curve25519_base(q: &mut [u8; 32], n: &[u8; 32]) {
+        *q = x25519_dalek::x25519(*n, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+
+    fn ed25519_keypair(pk: &mut [u8; 32], sk: &mut [u8; 64]) -> bool {
+        let mut seed = [0; 32];
+        if getrandom::getrandom(&mut seed).is_err() {
+            return false;
+        }
+        let key = SigningKey::from_bytes(&seed);
+        *sk = key.to_keypair_bytes();
+        *pk = key.verifying_key().to_bytes();
+        true
+    }
+
+    fn ed25519_sign(sig: &mut [u8; 64], m: &[u8], sk: &[u8; 64]) {
+        let mut seed = [0; 32];
+        seed.clone_from_slice(&sk[..32]);
+        let key = SigningKey::from_bytes(&seed);
+        *sig = key.sign(m).to_bytes()
+    }
+
+    fn ed25519_verify(sig: &[u8; 64], m: &[u8], pk: &[u8; 32]) -> bool {
+        match VerifyingKey::from_bytes(pk) {
+            Ok(pk) => pk.verify(m, &Signature::from_bytes(sig)).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn hash(h: Hash, m: &[u8]) -> Vec<u8> {
+        match h {
+            Hash::Sha1 => Sha1::digest(m).to_vec(),
+            Hash::Sha256 => Sha256::digest(m).to_vec(),
+            Hash::Sha384 => Sha384::digest(m).to_vec(),
+            Hash::Sha512 => Sha512::digest(m).to_vec(),
+        }
+    }
+
+    fn hmac(h: Hash, key: &[u8], m: &[&[u8]]) -> Vec<u8> {
+        match h {
+            Hash::Sha1 => hmac::<Hmac<Sha1>>(key, m),
+            Hash::Sha256 => hmac::<Hmac<Sha256>>(key, m),
+            Hash::Sha384 => hmac::<Hmac<Sha384>>(key, m),
+            Hash::Sha512 => hmac::<Hmac<Sha512>>(key, m),
+        }
+    }
+
+    fn aes_ctr_xor(key: &[u8], counter: &mut [u8; 16], m: &mut [u8]) {
+        match key.len() {
+            16 => Ctr128BE::<Aes128>::new_from_slices(key, counter).unwrap().apply_keystream(m),
+            24 => Ctr128BE::<Aes192>::new_from_slices(key, counter).unwrap().apply_keystream(m),
+            _ => Ctr128BE::<Aes256>::new_from_slices(key, counter).unwrap().apply_keystream(m),
+        }
+        super::increment_counter(counter, m.len() / 16)
+    }
+
+    fn cbc_encrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]) {
+        if m.is_empty() {
+            return;
+        }
+        let len = m.len();
+        match c {
+            Cbc::Aes128 => {
+                cbc::Encryptor::<Aes128>::new_from_slices(key, iv)
+                    .unwrap()
+                    .encrypt_padded_mut::<NoPadding>(m, len)
+                    .unwrap();
+            }
+            Cbc::TripleDes => {
+                cbc::Encryptor::<TdesEde3>::new_from_slices(key, iv)
+                    .unwrap()
+                    .encrypt_padded_mut::<NoPadding>(m, len)
+                    .unwrap();
+            }
+        }
+        iv.clone_from_slice(&m[len - iv.len()..]);
+    }
+
+    fn cbc_decrypt(c: Cbc, key: &[u8], iv: &mut [u8], m: &mut [u8]) {
+        if m.is_empty() {
+            return;
+        }
+        let len = m.len();
+        let next_iv = m[len - iv.len()..].to_vec();
+        match c {
+            Cbc::Aes128 => {
+                cbc::Decryptor::<Aes128>::new_from_slices(key, iv)
+                    .unwrap()
+                    .decrypt_padded_mut::<NoPadding>(m)
+                    .unwrap();
+            }
+            Cbc::TripleDes => {
+                cbc::Decryptor::<TdesEde3>::new_from_slices(key, iv)
+                    .unwrap()
+                    .decrypt_padded_mut::<NoPadding>(m)
+                    .unwrap();
+            }
+        }
+        iv.clone_from_slice(&next_iv);
+    }
+
+    fn aes_gcm_seal(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8]) -> [u8; 16] {
+        let tag = if key.len() == 16 {
+            Aes128Gcm::new_from_slice(key).unwrap().encrypt_in_place_detached(nonce.into(), aad, m)
+        } else {
+            Aes256Gcm::new_from_slice(key).unwrap().encrypt_in_place_detached(nonce.into(), aad, m)
+        };
+        let mut result = [0; 16];
+        result.clone_from_slice(&tag.unwrap());
+        result
+    }
+
+    fn aes_gcm_open(key: &[u8], nonce: &[u8; 12], aad: &[u8], m: &mut [u8], tag: &[u8; 16])
+                    -> bool {
+        if key.len() == 16 {
+            Aes128Gcm::new_from_slice(key)
+                .unwrap()
+                .decrypt_in_place_detached(nonce.into(), aad, m, tag.into())
+                .is_ok()
+        } else {
+            Aes256Gcm::new_from_slice(key)
+                .unwrap()
+                .decrypt_in_place_detached(nonce.into(), aad, m, tag.into())
+                .is_ok()
+        }
+    }
+
+    fn ec_keypair(c: Curve) -> Option<(Vec<u8>, Vec<u8>)> {
+        match c {
+            Curve::P256 => ec_keypair!(p256, c),
+            Curve::P384 => ec_keypair!(p384, c),
+            Curve::P521 => ec_keypair!(p521, c),
+        }
+    }
+
+    fn ecdh(c: Curve, secret: &[u8], public: &[u8]) -> Option<Vec<u8>> {
+        match c {
+            Curve::P256 => ecdh!(p256, secret, public),
+            Curve::P384 => ecdh!(p384, secret, public),
+            Curve::P521 => ecdh!(p521, secret, public),
+        }
+    }
+
+    fn ecdsa_sign(c: Curve, secret: &[u8], m: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        match c {
+            Curve::P256 => ecdsa_sign!(p256, secret, m),
+            Curve::P384 => ecdsa_sign!(p384, secret, m),
+            Curve::P521 => ecdsa_sign!(p521, secret, m),
+        }
+    }
+
+    fn ecdsa_verify(c: Curve, public: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool {
+        match c {
+            Curve::P256 => ecdsa_verify!(p256, c, public, m, r, s),
+            Curve::P384 => ecdsa_verify!(p384, c, public, m, r, s),
+            Curve::P521 => ecdsa_verify!(p521, c, public, m, r, s),
+        }
+    }
+
+    fn rsa_verify(h: Hash, e: &[u8], n: &[u8], m: &[u8], sig: &[u8]) -> bool {
+        let key = match RsaPublicKey::new(rsa::BigUint::from_bytes_be(n),
+                                          rsa::BigUint::from_bytes_be(e)) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        key.verify(pkcs1v15(h), &Self::hash(h, m), sig).is_ok()
+    }
+
+    fn rsa_keypair(bits: usize) -> Option<RsaSecretKey> {
+        use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+        let key = match RsaPrivateKey::new(&mut OsRng, bits) {
+            Ok(key) => key,
+            Err(_) => return None,
+        };
+        let primes = key.primes();
+        Some(RsaSecretKey {
+            n: key.n().to_bytes_be(),
+            e: key.e().to_bytes_be(),
+            d: key.d().to_bytes_be(),
+            p: primes[0].to_bytes_be(),
+            q: primes[1].to_bytes_be(),
+        })
+    }
+
+    fn rsa_sign(h: Hash, key: &RsaSecretKey, m: &[u8]) -> Option<Vec<u8>> {
+        let int = rsa::BigUint::from_bytes_be;
+        let key = match RsaPrivateKey::from_components(int(&key.n),
+                                                       int(&key.e),
+                                                       int(&key.d),
+                                                       vec![int(&key.p), int(&key.q)]) {
+            Ok(key) => key,
+            Err(_) => return None,
+        };
+        // With a random number generator, the signature is blinded.
+        key.sign_with_rng(&mut OsRng, pkcs1v15(h), &Self::hash(h, m)).ok()
+    }
+
+    fn dsa_verify(p: &[u8], q: &[u8], g: &[u8], y: &[u8], m: &[u8], r: &[u8], s: &[u8]) -> bool {
+        let int = dsa::BigUint::from_bytes_be;
+        let key = dsa::Components::from_components(int(p), int(q), int(g))
+            .and_then(|components| dsa::VerifyingKey::from_components(components, int(y)));
+        match (key, dsa::Signature::from_components(int(r), int(s))) {
+            (Ok(key), Ok(sig)) => key.verify_prehash(&Sha1::digest(m), &sig).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn modexp(base: &[u8], exp: &[u8], modulus: &[u8]) -> Vec<u8> {
+        let int = rsa::BigUint::from_bytes_be;
+        let result = int(base).modpow(&int(exp), &int(modulus)).to_bytes_be();
+        if result == [0] {
+            Vec::new()
+        } else {
+            result
+        }
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_keypair(pk: &mut [u8], sk: &mut [u8]) -> bool {
+        super::mlkem::keypair(pk, sk)
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_encapsulate(ct: &mut [u8], ss: &mut [u8], pk: &[u8]) -> bool {
+        super::mlkem::encapsulate(ct, ss, pk)
+    }
+
+    #[cfg(feature = "mlkem")]
+    fn mlkem768_decapsulate(ss: &mut [u8], ct: &[u8], sk: &[u8]) -> bool {
+        super::mlkem::decapsulate(ss, ct, sk)
+    }
+}
+
+/// The big-endian integer `x` on exactly `size` bytes, if it fits.
+fn fixed_size(x: &[u8], size: usize) -> Option<Vec<u8>> {
+    let start = x.iter().position(|&b| b != 0).unwrap_or(x.len());
+    let x = &x[start..];
+    if x.len() > size {
+        return None;
+    }
+    let mut result = vec![0; size - x.len()];
+    result.extend_from_slice(x);
+    Some(result)
+}
+
+fn pkcs1v15(h: Hash) -> Pkcs1v15Sign {
+    match h {
+        Hash::Sha1 => Pkcs1v15Sign::new::<Sha1>(),
+        Hash::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+        Hash::Sha384 => Pkcs1v15Sign::new::<Sha384>(),
+        Hash::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+    }
+}
+
+fn hmac<M: Mac + KeyInit>(key: &[u8], m: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).unwrap();
+    for m in m {
+        mac.update(m)
+    }
+    mac.finalize().into_bytes().to_vec()
+}
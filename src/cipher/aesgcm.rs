@@ -0,0 +1,247 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// AES-GCM, as specified for SSH by https://tools.ietf.org/html/rfc5647,
+// and used by OpenSSH under the names aes128-gcm@openssh.com and
+// aes256-gcm@openssh.com (the MAC is implicit with these names).
+
+use byteorder::{ByteOrder, BigEndian};
+use super::super::Error;
+use std::io::BufRead;
+use std::cell::Cell;
+use sshbuffer::SSHBuffer;
+use backend::{Backend, Selected};
+
+use super::super::sodium::randombytes;
+
+pub const TAGBYTES: usize = 16;
+pub const NONCEBYTES: usize = 12;
+const BLOCK_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub struct Cipher {
+    key: Vec<u8>,
+    // The first four bytes of the nonce (RFC5647, section 7.1).
+    fixed: [u8; 4],
+    // The last eight bytes of the nonce, incremented after each packet.
+    invocation_counter: Cell<u64>,
+}
+
+impl Cipher {
+    /// `key` must be 16 bytes long for AES-128, or 32 bytes long for
+    /// AES-256. `iv` is the initial nonce.
+    pub fn init(key: &[u8], iv: &[u8]) -> Cipher {
+        debug_assert!(key.len() == 16 || key.len() == 32);
+        let mut fixed = [0; 4];
+        fixed.clone_from_slice(&iv[0..4]);
+        Cipher {
+            key: key.to_vec(),
+            fixed: fixed,
+            invocation_counter: Cell::new(BigEndian::read_u64(&iv[4..NONCEBYTES])),
+        }
+    }
+
+    fn nonce(&self) -> [u8; NONCEBYTES] {
+        let mut nonce = [0; NONCEBYTES];
+        nonce[0..4].clone_from_slice(&self.fixed);
+        BigEndian::write_u64(&mut nonce[4..], self.invocation_counter.get());
+        nonce
+    }
+
+    fn increment_counter(&self) {
+        self.invocation_counter.set(self.invocation_counter.get().wrapping_add(1))
+    }
+
+    /// Decrypt `ciphertext` in place, authenticating it along with
+    /// `aad` against `tag`.
+    fn open(&self, aad: &[u8], ciphertext: &mut [u8], tag: &[u8]) -> Result<(), Error> {
+        let mut tag_ = [0; TAGBYTES];
+        tag_.clone_from_slice(tag);
+        if !Selected::aes_gcm_open(&self.key, &self.nonce(), aad, ciphertext, &tag_) {
+            return Err(Error::PacketAuth);
+        }
+        self.increment_counter();
+        Ok(())
+    }
+
+    /// Encrypt `plaintext` in place, and return the authentication
+    /// tag of `aad` and the ciphertext.
+    fn seal(&self, aad: &[u8], plaintext: &mut [u8]) -> [u8; TAGBYTES] {
+        let tag = Selected::aes_gcm_seal(&self.key, &self.nonce(), aad, plaintext);
+        self.increment_counter();
+        tag
+    }
+}
+
+impl super::CipherT for Cipher {
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                read_buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error> {
+
+        // The packet length is sent in the clear, and authenticated
+        // as additional data.
+        if read_buffer.len == 0 {
+            read_buffer.buffer.clear();
+            let mut len = [0; 4];
+            try!(stream.read_exact(&mut len));
+            read_buffer.buffer.extend(&len);
+            let len = BigEndian::read_u32(&len) as usize;
+            if len < BLOCK_SIZE || len % BLOCK_SIZE != 0 || len > super::MAXIMUM_PACKET_LEN {
+                return Err(Error::PacketAuth);
+            }
+            read_buffer.len = len + TAGBYTES;
+            debug!("buffer len: {:?}", read_buffer.len);
+        }
+        if try!(super::read(stream,
+                            &mut read_buffer.buffer,
+                            read_buffer.len,
+                            &mut read_buffer.bytes)) {
+
+            let packet_len = read_buffer.len - TAGBYTES;
+            {
+                let (aad, rest) = read_buffer.buffer.split_at_mut(4);
+                let (ciphertext, tag) = rest.split_at_mut(packet_len);
+                try!(self.open(aad, ciphertext, &tag[0..TAGBYTES]));
+            }
+            let padding = read_buffer.buffer[4] as usize;
+            if padding + 1 > packet_len {
+                return Err(Error::PacketAuth);
+            }
+            let result = Some(&read_buffer.buffer[5..(4 + packet_len - padding)]);
+            read_buffer.seqn += 1;
+            read_buffer.len = 0;
+            Ok(result)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Append an encrypted packet with contents `packet_content` at the end of `buffer`.
+    fn write(&self, packet_content: &[u8], buffer: &mut SSHBuffer) {
+
+        let offset = buffer.buffer.len();
+
+        // The padding length, the payload and the padding must be a
+        // multiple of the block size.
+        let padding_len = BLOCK_SIZE - ((1 + packet_content.len()) % BLOCK_SIZE);
+        let padding_len = if padding_len < 4 {
+            padding_len + BLOCK_SIZE
+        } else {
+            padding_len
+        };
+
+        buffer.buffer.push_u32_be((packet_content.len() + padding_len + 1) as u32);
+        buffer.buffer.push(padding_len as u8);
+        buffer.buffer.extend(packet_content);
+
+        let mut padding = [0; 256];
+        randombytes::into(&mut padding[0..padding_len]);
+        buffer.buffer.extend(&padding[0..padding_len]);
+
+        let tag = {
+            let (aad, plaintext) = buffer.buffer[offset..].split_at_mut(4);
+            self.seal(aad, plaintext)
+        };
+        buffer.buffer.extend(&tag);
+        buffer.seqn += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cipher;
+    use cipher::CipherT;
+    use sshbuffer::SSHBuffer;
+    use byteorder::{ByteOrder, BigEndian};
+    use std::borrow::Cow;
+    use {cipher, client, server, testing, Error, Preferred};
+
+    fn pair(key_len: usize) -> (Cipher, Cipher) {
+        let key = vec![7; key_len];
+        let mut iv = [1; super::NONCEBYTES];
+        // Make the invocation counter wrap around.
+        for x in iv[4..].iter_mut() {
+            *x = 0xff
+        }
+        (Cipher::init(&key, &iv), Cipher::init(&key, &iv))
+    }
+
+    #[test]
+    fn round_trip() {
+        for &key_len in &[16, 32] {
+            let (sender, receiver) = pair(key_len);
+            let mut written = SSHBuffer::new();
+            let packets: Vec<Vec<u8>> = (0..20).map(|i| vec![i as u8; 1 + 37 * i]).collect();
+            for p in packets.iter() {
+                sender.write(p, &mut written);
+            }
+            assert_eq!(written.seqn, packets.len());
+            let mut stream = &written.buffer[..written.buffer.len()];
+            let mut read = SSHBuffer::new();
+            for p in packets.iter() {
+                assert_eq!(receiver.read(&mut stream, &mut read).unwrap(), Some(&p[..]));
+            }
+            assert_eq!(read.seqn, packets.len());
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let (sender, receiver) = pair(16);
+        let mut written = SSHBuffer::new();
+        sender.write(b"payload", &mut written);
+        // The length, in the clear, is authenticated too.
+        for &i in &[3, 4, 20, written.buffer.len() - 1] {
+            let mut tampered = written.buffer[..written.buffer.len()].to_vec();
+            tampered[i] ^= 1;
+            let mut stream = &tampered[..];
+            let mut read = SSHBuffer::new();
+            match receiver.read(&mut stream, &mut read) {
+                Err(Error::PacketAuth) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn long_packets_are_rejected() {
+        let (_, receiver) = pair(16);
+        let mut len = [0; 4];
+        BigEndian::write_u32(&mut len, (super::super::MAXIMUM_PACKET_LEN + 16) as u32);
+        let mut stream = &len[..];
+        let mut read = SSHBuffer::new();
+        match receiver.read(&mut stream, &mut read) {
+            Err(Error::PacketAuth) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn connection() {
+        for &c in &[cipher::AES128GCM, cipher::AES256GCM] {
+            let mut config = client::Config::default();
+            config.preferred = Preferred { cipher: Cow::Owned(vec![c]), ..Default::default() };
+            let mut sconfig = server::Config::default();
+            sconfig.preferred = ::LEGACY;
+            let mut p = testing::pair(sconfig, config, testing::client_key());
+            p.authenticate();
+            for i in 0..10 {
+                p.echo(&vec![i as u8; 1 + 9 * i]);
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Block ciphers with a separate MAC, either computed on the plaintext
+// (RFC4253) or on the ciphertext (OpenSSH's -etm@openssh.com MACs):
+// AES in counter mode (https://tools.ietf.org/html/rfc4344), and, with
+// the "legacy" feature, AES and triple DES in CBC mode (RFC4253).
+
+use super::super::Error;
+use std::io::BufRead;
+use std::cell::{Cell, RefCell};
+use std;
+use sshbuffer::SSHBuffer;
+use backend::{Backend, Selected};
+#[cfg(feature = "legacy")]
+use backend::Cbc;
+use mac;
+
+use super::super::sodium::randombytes;
+
+pub const IVBYTES: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Ctr,
+    #[cfg(feature = "legacy")]
+    Cbc(Cbc),
+}
+
+pub struct Cipher {
+    mode: Mode,
+    key: Vec<u8>,
+    // The counter (or the last ciphertext block in CBC mode), kept
+    // between packets. Only one of them is used, depending on the
+    // direction of this cipher.
+    encrypt_iv: RefCell<[u8; IVBYTES]>,
+    decrypt_iv: RefCell<[u8; IVBYTES]>,
+    block_size: usize,
+    mac: mac::Mac,
+    // Whether the first block of the next packet is being read.
+    reading_first_block: Cell<bool>,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Cipher {{ mac: {:?} }}", self.mac)
+    }
+}
+
+impl Cipher {
+    /// AES in counter mode. `key` must be 16, 24 or 32 bytes long,
+    /// for AES-128, AES-192 and AES-256 respectively. `iv` is the
+    /// initial counter block.
+    pub fn init_ctr(key: &[u8], iv: &[u8], mac: mac::Mac) -> Cipher {
+        // The block size is that of AES (RFC4344, section 4).
+        Cipher::init(Mode::Ctr, 16, key, iv, mac)
+    }
+
+    /// AES-128 (if `key` is 16 bytes long) or triple DES (if `key` is
+    /// 24 bytes long) in CBC mode.
+    #[cfg(feature = "legacy")]
+    pub fn init_cbc(key: &[u8], iv: &[u8], mac: mac::Mac) -> Cipher {
+        let cipher = if key.len() == 16 {
+            Cbc::Aes128
+        } else {
+            Cbc::TripleDes
+        };
+        Cipher::init(Mode::Cbc(cipher), cipher.block_size(), key, iv, mac)
+    }
+
+    fn init(mode: Mode, block_size: usize, key: &[u8], iv: &[u8], mac: mac::Mac) -> Cipher {
+        let mut iv_ = [0; IVBYTES];
+        iv_[0..block_size].clone_from_slice(&iv[0..block_size]);
+        Cipher {
+            mode: mode,
+            key: key.to_vec(),
+            encrypt_iv: RefCell::new(iv_),
+            decrypt_iv: RefCell::new(iv_),
+            block_size: block_size,
+            mac: mac,
+            reading_first_block: Cell::new(false),
+        }
+    }
+
+    /// Encrypt `data` in place. `data.len()` must be a multiple of
+    /// the block size.
+    fn encrypt_inplace(&self, data: &mut [u8]) {
+        let mut iv = self.encrypt_iv.borrow_mut();
+        match self.mode {
+            Mode::Ctr => Selected::aes_ctr_xor(&self.key, &mut iv, data),
+            #[cfg(feature = "legacy")]
+            Mode::Cbc(c) => Selected::cbc_encrypt(c, &self.key, &mut iv[0..self.block_size], data),
+        }
+    }
+
+    /// Decrypt `data` in place, see `encrypt_inplace`.
+    fn decrypt_inplace(&self, data: &mut [u8]) {
+        let mut iv = self.decrypt_iv.borrow_mut();
+        match self.mode {
+            // Encryption and decryption are the same operation in CTR
+            // mode.
+            Mode::Ctr => Selected::aes_ctr_xor(&self.key, &mut iv, data),
+            #[cfg(feature = "legacy")]
+            Mode::Cbc(c) => Selected::cbc_decrypt(c, &self.key, &mut iv[0..self.block_size], data),
+        }
+    }
+}
+
+impl super::CipherT for Cipher {
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                read_buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error> {
+
+        let etm = self.mac.is_etm();
+        if read_buffer.len == 0 {
+            // Read the packet length, which is the first four bytes
+            // of the first block, or the first four bytes in the
+            // clear with encrypt-then-MAC.
+            if !self.reading_first_block.get() {
+                read_buffer.buffer.clear();
+                self.reading_first_block.set(true)
+            }
+            let first_len = if etm {
+                4
+            } else {
+                self.block_size
+            };
+            if !try!(super::read(stream,
+                                 &mut read_buffer.buffer,
+                                 first_len - 4,
+                                 &mut read_buffer.bytes)) {
+                return Ok(None);
+            }
+            self.reading_first_block.set(false);
+            if !etm {
+                self.decrypt_inplace(&mut read_buffer.buffer[0..self.block_size]);
+            }
+            let len = read_buffer.buffer.read_u32_be(0) as usize;
+            let encrypted_len = if etm {
+                len
+            } else {
+                len + 4
+            };
+            if len < self.block_size - 4 || encrypted_len % self.block_size != 0 ||
+               len > super::MAXIMUM_PACKET_LEN {
+                return Err(Error::PacketAuth);
+            }
+            read_buffer.len = len + self.mac.size();
+            debug!("buffer len: {:?}", read_buffer.len);
+        }
+        if try!(super::read(stream,
+                            &mut read_buffer.buffer,
+                            read_buffer.len,
+                            &mut read_buffer.bytes)) {
+
+            let packet_len = read_buffer.len - self.mac.size();
+            let authenticated = {
+                let (packet, tag) = read_buffer.buffer.split_at_mut(4 + packet_len);
+                if etm {
+                    let auth = self.mac.verify(read_buffer.seqn, packet, tag);
+                    if auth {
+                        self.decrypt_inplace(&mut packet[4..]);
+                    }
+                    auth
+                } else {
+                    self.decrypt_inplace(&mut packet[self.block_size..]);
+                    self.mac.verify(read_buffer.seqn, packet, tag)
+                }
+            };
+            if !authenticated {
+                return Err(Error::PacketAuth);
+            }
+            let padding = read_buffer.buffer[4] as usize;
+            if padding + 1 > packet_len {
+                return Err(Error::PacketAuth);
+            }
+            let result = Some(&read_buffer.buffer[5..(4 + packet_len - padding)]);
+            read_buffer.seqn += 1;
+            read_buffer.len = 0;
+            Ok(result)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Append an encrypted packet with contents `packet_content` at the end of `buffer`.
+    fn write(&self, packet_content: &[u8], buffer: &mut SSHBuffer) {
+
+        let etm = self.mac.is_etm();
+        let offset = buffer.buffer.len();
+
+        // The encrypted part of the packet must be a multiple of the
+        // block size. It includes the packet length, unless the MAC
+        // is computed after encryption.
+        let encrypted_len = if etm {
+            1 + packet_content.len()
+        } else {
+            5 + packet_content.len()
+        };
+        let padding_len = self.block_size - (encrypted_len % self.block_size);
+        let padding_len = if padding_len < 4 {
+            padding_len + self.block_size
+        } else {
+            padding_len
+        };
+
+        buffer.buffer.push_u32_be((packet_content.len() + padding_len + 1) as u32);
+        buffer.buffer.push(padding_len as u8);
+        buffer.buffer.extend(packet_content);
+
+        let mut padding = [0; 256];
+        randombytes::into(&mut padding[0..padding_len]);
+        buffer.buffer.extend(&padding[0..padding_len]);
+
+        let mut tag = Vec::with_capacity(self.mac.size());
+        if etm {
+            self.encrypt_inplace(&mut buffer.buffer[offset + 4..]);
+            self.mac.compute(buffer.seqn, &buffer.buffer[offset..], &mut tag);
+        } else {
+            self.mac.compute(buffer.seqn, &buffer.buffer[offset..], &mut tag);
+            self.encrypt_inplace(&mut buffer.buffer[offset..]);
+        }
+        buffer.buffer.extend(&tag);
+        buffer.seqn += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cipher;
+    use cipher::{CipherT, MAXIMUM_PACKET_LEN};
+    use sshbuffer::SSHBuffer;
+    use byteorder::{ByteOrder, BigEndian};
+    use std::borrow::Cow;
+    use {cipher, client, mac, server, testing, Error, Preferred};
+
+    /// Send packets of various lengths from `sender` to `receiver`.
+    fn round_trip(sender: Cipher, receiver: Cipher) {
+        let mut written = SSHBuffer::new();
+        let packets: Vec<Vec<u8>> = (0..20).map(|i| vec![i as u8; 1 + 37 * i]).collect();
+        for p in packets.iter() {
+            sender.write(p, &mut written);
+        }
+        let mut stream = &written.buffer[..written.buffer.len()];
+        let mut read = SSHBuffer::new();
+        for p in packets.iter() {
+            assert_eq!(receiver.read(&mut stream, &mut read).unwrap(), Some(&p[..]));
+        }
+        assert_eq!(read.seqn, packets.len());
+        assert!(stream.is_empty());
+
+        // Flip one bit of the next packet.
+        let mut written = SSHBuffer::new();
+        sender.write(b"payload", &mut written);
+        let mut written = written.buffer[..written.buffer.len()].to_vec();
+        let i = written.len() - 1;
+        written[i] ^= 1;
+        let mut stream = &written[..];
+        match receiver.read(&mut stream, &mut read) {
+            Err(Error::PacketAuth) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    fn ctr(key_len: usize, m: mac::Name) -> Cipher {
+        let key = vec![3; key_len];
+        // Make the counter wrap around.
+        let iv = [0xff; super::IVBYTES];
+        let mac_key = vec![5; mac::key_size(m)];
+        Cipher::init_ctr(&key, &iv, mac::Mac::init(m, &mac_key))
+    }
+
+    #[test]
+    fn ctr_round_trip() {
+        for &key_len in &[16, 24, 32] {
+            for &m in &[mac::HMAC_SHA256, mac::HMAC_SHA512] {
+                round_trip(ctr(key_len, m), ctr(key_len, m))
+            }
+        }
+    }
+
+    #[test]
+    fn etm_round_trip() {
+        for &m in &[mac::HMAC_SHA256_ETM, mac::HMAC_SHA512_ETM] {
+            round_trip(ctr(16, m), ctr(16, m))
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn cbc_round_trip() {
+        for &key_len in &[16, 24] {
+            for &m in &[mac::HMAC_SHA1, mac::HMAC_SHA256_ETM] {
+                let cbc = || {
+                    let key = vec![3; key_len];
+                    let iv = [4; 16];
+                    let mac_key = vec![5; mac::key_size(m)];
+                    Cipher::init_cbc(&key, &iv, mac::Mac::init(m, &mac_key))
+                };
+                round_trip(cbc(), cbc())
+            }
+        }
+    }
+
+    #[test]
+    fn long_packets_are_rejected() {
+        for &m in &[mac::HMAC_SHA256, mac::HMAC_SHA256_ETM] {
+            // A first block claiming a length above the maximum.
+            let mut first_block = [0; 16];
+            BigEndian::write_u32(&mut first_block, (MAXIMUM_PACKET_LEN + 12) as u32);
+            let sender = ctr(16, m);
+            if !sender.mac.is_etm() {
+                sender.encrypt_inplace(&mut first_block)
+            }
+            let mut stream = &first_block[..];
+            let mut read = SSHBuffer::new();
+            match ctr(16, m).read(&mut stream, &mut read) {
+                Err(Error::PacketAuth) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn connection() {
+        for &(c, m) in &[(cipher::AES128CTR, mac::HMAC_SHA256),
+                         (cipher::AES192CTR, mac::HMAC_SHA512),
+                         (cipher::AES256CTR, mac::HMAC_SHA256_ETM)] {
+            let mut config = client::Config::default();
+            config.preferred = Preferred {
+                cipher: Cow::Owned(vec![c]),
+                mac: Cow::Owned(vec![m]),
+                ..Default::default()
+            };
+            let mut sconfig = server::Config::default();
+            sconfig.preferred = ::LEGACY;
+            let mut p = testing::pair(sconfig, config, testing::client_key());
+            p.authenticate();
+            for i in 0..10 {
+                p.echo(&vec![i as u8; 1 + 9 * i]);
+            }
+        }
+    }
+}
@@ -15,16 +15,33 @@
 use {Error, Disconnect};
 use std::io::{Read, BufRead};
 use std;
+use std::cell::RefCell;
+use std::sync::Arc;
 use cryptobuf::CryptoBuf;
 use sshbuffer::SSHBuffer;
 use rand::{thread_rng, Rng};
 pub mod chacha20poly1305;
+pub mod aesgcm;
+pub mod block;
 use msg;
+use mac;
+use compression;
+use negociation;
+use registry::Registry;
+
+/// Maximum length of the packets read, excluding the length field
+/// and the MAC. Packets of at least 35000 bytes must be accepted
+/// ([RFC4253](https://tools.ietf.org/html/rfc4253#section-6.1)).
+pub const MAXIMUM_PACKET_LEN: usize = 256 * 1024;
 
 #[derive(Debug)]
 pub enum Cipher {
     Clear,
     Chacha20Poly1305(chacha20poly1305::Cipher),
+    AesGcm(aesgcm::Cipher),
+    Block(block::Cipher),
+    /// A cipher registered with `register`.
+    Custom(Box<CipherT + Send>),
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -36,11 +53,143 @@ impl AsRef<str> for Name {
 }
 
 pub const CHACHA20POLY1305: Name = Name("chacha20-poly1305@openssh.com");
+pub const AES128GCM: Name = Name("aes128-gcm@openssh.com");
+pub const AES256GCM: Name = Name("aes256-gcm@openssh.com");
+pub const AES128CTR: Name = Name("aes128-ctr");
+pub const AES192CTR: Name = Name("aes192-ctr");
+pub const AES256CTR: Name = Name("aes256-ctr");
+/// AES-128 in CBC mode. **Insecure**, only for compatibility with old
+/// equipment (requires the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const AES128CBC: Name = Name("aes128-cbc");
+/// Triple DES in CBC mode. **Insecure**, only for compatibility with
+/// old equipment (requires the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const TRIPLE_DES_CBC: Name = Name("3des-cbc");
+
+/// All the ciphers implemented by this crate.
+#[cfg(not(feature = "legacy"))]
+pub const ALL: &'static [Name] = &[CHACHA20POLY1305, AES128GCM, AES256GCM, AES128CTR, AES192CTR, AES256CTR];
+/// All the ciphers implemented by this crate.
+#[cfg(feature = "legacy")]
+pub const ALL: &'static [Name] = &[CHACHA20POLY1305,
+                                   AES128GCM,
+                                   AES256GCM,
+                                   AES128CTR,
+                                   AES192CTR,
+                                   AES256CTR,
+                                   AES128CBC,
+                                   TRIPLE_DES_CBC];
+
+impl std::str::FromStr for Name {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Name, Error> {
+        negociation::parse_name(ALL, s).or_else(|e| {
+            if let Some((name, _)) = REGISTRY.get(s) {
+                Ok(Name(name))
+            } else {
+                Err(e)
+            }
+        })
+    }
+}
+
+/// A cipher implemented outside of this crate, see `register`.
+pub trait CipherAlgorithm: Send + Sync {
+    /// Length of the key derived for this cipher.
+    fn key_size(&self) -> usize;
+    /// Length of the initial IV derived for this cipher (zero if it
+    /// doesn't use one).
+    fn iv_size(&self) -> usize;
+    /// Whether this cipher needs a separate MAC.
+    fn needs_mac(&self) -> bool;
+    /// Initialize the cipher for one direction. `mac` is `Some` if
+    /// and only if this cipher needs a separate MAC.
+    fn init(&self, key: &[u8], iv: &[u8], mac: Option<mac::Mac>) -> Box<CipherT + Send>;
+}
+
+static REGISTRY: Registry<CipherAlgorithm> = Registry::new();
+
+/// Make `algorithm` available to all connections of this process,
+/// under `name` (which should be of the form `name@domain`), and
+/// return the corresponding `Name`, to be added to
+/// `Preferred::cipher`. Ciphers implemented by this crate cannot be
+/// overridden.
+pub fn register(name: &'static str, algorithm: Box<CipherAlgorithm>) -> Name {
+    REGISTRY.register(name, Arc::from(algorithm));
+    Name(name)
+}
 
 pub fn key_size(c: Name) -> usize {
     match c {
+        #[cfg(feature = "legacy")]
+        AES128CBC => 16,
+        #[cfg(feature = "legacy")]
+        TRIPLE_DES_CBC => 24,
         CHACHA20POLY1305 => 64,
-        _ => 0,
+        AES128GCM | AES128CTR => 16,
+        AES192CTR => 24,
+        AES256GCM | AES256CTR => 32,
+        _ => REGISTRY.get(c.0).map(|(_, c)| c.key_size()).unwrap_or(0),
+    }
+}
+
+/// Length of the initial IV derived for cipher `c` (zero if `c` doesn't use one).
+pub fn iv_size(c: Name) -> usize {
+    match c {
+        AES128GCM | AES256GCM => aesgcm::NONCEBYTES,
+        AES128CTR | AES192CTR | AES256CTR => block::IVBYTES,
+        #[cfg(feature = "legacy")]
+        AES128CBC => 16,
+        #[cfg(feature = "legacy")]
+        TRIPLE_DES_CBC => 8,
+        _ => REGISTRY.get(c.0).map(|(_, c)| c.iv_size()).unwrap_or(0),
+    }
+}
+
+/// Whether cipher `c` needs a separate MAC, i.e. doesn't authenticate
+/// packets itself.
+pub fn needs_mac(c: Name) -> bool {
+    match c {
+        AES128CTR | AES192CTR | AES256CTR => true,
+        #[cfg(feature = "legacy")]
+        AES128CBC | TRIPLE_DES_CBC => true,
+        CHACHA20POLY1305 | AES128GCM | AES256GCM => false,
+        _ => REGISTRY.get(c.0).map(|(_, c)| c.needs_mac()).unwrap_or(false),
+    }
+}
+
+impl Cipher {
+    /// Initialize cipher `c` with key and IV material computed during
+    /// the key exchange. `mac` and `mac_key` are only used if `c`
+    /// needs a separate MAC. Fails if `c` is neither implemented by
+    /// this crate nor registered.
+    pub fn init(c: Name,
+                key: &[u8],
+                iv: &[u8],
+                mac: mac::Name,
+                mac_key: &[u8])
+                -> Result<Cipher, Error> {
+        Ok(match c {
+            CHACHA20POLY1305 => Cipher::Chacha20Poly1305(chacha20poly1305::Cipher::init(key)),
+            AES128GCM | AES256GCM => Cipher::AesGcm(aesgcm::Cipher::init(key, iv)),
+            AES128CTR | AES192CTR | AES256CTR => {
+                Cipher::Block(block::Cipher::init_ctr(key, iv, mac::Mac::init(mac, mac_key)))
+            }
+            #[cfg(feature = "legacy")]
+            AES128CBC | TRIPLE_DES_CBC => {
+                Cipher::Block(block::Cipher::init_cbc(key, iv, mac::Mac::init(mac, mac_key)))
+            }
+            _ => {
+                let (_, algorithm) = try!(REGISTRY.get(c.0).ok_or(Error::Kex));
+                let mac = if algorithm.needs_mac() {
+                    Some(mac::Mac::init(mac, mac_key))
+                } else {
+                    None
+                };
+                Cipher::Custom(algorithm.init(key, iv, mac))
+            }
+        })
     }
 }
 
@@ -48,33 +197,59 @@ pub fn key_size(c: Name) -> usize {
 pub struct CipherPair {
     pub local_to_remote: Cipher,
     pub remote_to_local: Cipher,
+    pub compress: RefCell<compression::Compress>,
+    pub decompress: RefCell<compression::Decompress>,
 }
 
 pub const CLEAR_PAIR: CipherPair = CipherPair {
     local_to_remote: Cipher::Clear,
     remote_to_local: Cipher::Clear,
+    compress: RefCell::new(compression::Compress::None),
+    decompress: RefCell::new(compression::Decompress::None),
 };
 
-pub trait CipherT {
-    /// Replace the buffer's content with the next deciphered packet from `stream`.
-    fn read<'a, R: BufRead>(&self,
-                            stream: &mut R,
-                            buffer: &'a mut SSHBuffer)
-                            -> Result<Option<&'a [u8]>, Error>;
-    /// Extend the buffer with the encrypted packet.
+impl CipherPair {
+    /// Start delayed compression (zlib@openssh.com) in both
+    /// directions, if it was negotiated.
+    pub fn start_delayed_compression(&self) {
+        self.compress.borrow_mut().start_delayed();
+        self.decompress.borrow_mut().start_delayed();
+    }
+}
+
+/// The encryption of packets in one direction. Ciphers implemented
+/// outside of this crate implement this trait, and are made available
+/// to the negotiation with `register`.
+pub trait CipherT: std::fmt::Debug {
+    /// Replace the buffer's content with the next deciphered packet
+    /// from `stream`, and return its payload, or `None` if `stream`
+    /// doesn't contain a full packet yet. `buffer.len` is the length
+    /// of the packet being read (excluding its length field), or 0
+    /// if no packet has been started. `buffer.seqn` must be
+    /// incremented after each packet (see the `read` function of
+    /// this module to fill the buffer).
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error>;
+    /// Extend the buffer with the encrypted packet, and increment
+    /// `buffer.seqn`.
     fn write(&self, packet: &[u8], buffer: &mut SSHBuffer);
 }
 
 
 impl CipherT for Cipher {
-    fn read<'a, R: BufRead>(&self,
-                            stream: &mut R,
-                            buffer: &'a mut SSHBuffer)
-                            -> Result<Option<&'a [u8]>, Error> {
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error> {
 
         match *self {
             Cipher::Clear => Clear.read(stream, buffer),
             Cipher::Chacha20Poly1305(ref cipher) => cipher.read(stream, buffer),
+            Cipher::AesGcm(ref cipher) => cipher.read(stream, buffer),
+            Cipher::Block(ref cipher) => cipher.read(stream, buffer),
+            Cipher::Custom(ref cipher) => cipher.read(stream, buffer),
         }
     }
     fn write(&self, packet: &[u8], buffer: &mut SSHBuffer) {
@@ -82,17 +257,21 @@ impl CipherT for Cipher {
         match *self {
             Cipher::Clear => Clear.write(packet, buffer),
             Cipher::Chacha20Poly1305(ref cipher) => cipher.write(packet, buffer),
+            Cipher::AesGcm(ref cipher) => cipher.write(packet, buffer),
+            Cipher::Block(ref cipher) => cipher.write(packet, buffer),
+            Cipher::Custom(ref cipher) => cipher.write(packet, buffer),
         }
     }
 }
 
+#[derive(Debug)]
 pub struct Clear;
 
 impl CipherT for Clear {
-    fn read<'a, R: BufRead>(&self,
-                            stream: &mut R,
-                            buffer: &'a mut SSHBuffer)
-                            -> Result<Option<&'a [u8]>, Error> {
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error> {
 
         debug!("clear buffer: {:?}", buffer);
         if buffer.len == 0 {
@@ -177,8 +356,10 @@ impl Clear {
 
 
 
-/// Fills the read buffer, and returns whether a complete message has been read.
-fn read<R: BufRead>(stream: &mut R,
+/// Fills the read buffer, and returns whether a complete message has
+/// been read, i.e. whether `read_buffer` contains at least `4 +
+/// read_len` bytes.
+pub fn read<R: BufRead + ?Sized>(stream: &mut R,
                     read_buffer: &mut CryptoBuf,
                     read_len: usize,
                     bytes_read: &mut usize)
@@ -187,6 +368,10 @@ fn read<R: BufRead>(stream: &mut R,
     loop {
         let consumed_len = match stream.fill_buf() {
             Ok(buf) => {
+                if buf.is_empty() {
+                    // End of the stream, wait for more bytes.
+                    return Ok(false);
+                }
                 if read_buffer.len() + buf.len() < read_len + 4 {
 
                     read_buffer.extend(buf);
@@ -216,16 +401,47 @@ fn read<R: BufRead>(stream: &mut R,
 
 
 impl CipherT for CipherPair {
-    fn read<'a, R: BufRead>(&self,
-                            stream: &mut R,
-                            buffer: &'a mut SSHBuffer)
-                            -> Result<Option<&'a [u8]>, Error> {
-
-        self.remote_to_local.read(stream, buffer)
+    fn read<'a>(&self,
+                stream: &mut BufRead,
+                buffer: &'a mut SSHBuffer)
+                -> Result<Option<&'a [u8]>, Error> {
+
+        let mut decompress = self.decompress.borrow_mut();
+        if !decompress.is_enabled() {
+            return self.remote_to_local.read(stream, buffer);
+        }
+        let payload = if let Some(packet) = try!(self.remote_to_local.read(stream, buffer)) {
+            let mut payload = Vec::new();
+            try!(decompress.decompress(packet, &mut payload));
+            payload
+        } else {
+            return Ok(None);
+        };
+        // The packet has been entirely read, we can reuse the buffer.
+        buffer.buffer.clear();
+        buffer.buffer.extend(&payload);
+        Ok(Some(&buffer.buffer))
     }
     fn write(&self, packet: &[u8], buffer: &mut SSHBuffer) {
 
+        let mut compressed = Vec::new();
+        let packet = self.compress.borrow_mut().compress(packet, &mut compressed);
         self.local_to_remote.write(packet, buffer)
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unregistered_ciphers_are_an_error() {
+        let unregistered = Name("unregistered@example.com");
+        assert_eq!(key_size(unregistered), 0);
+        match Cipher::init(unregistered, &[], &[], mac::NONE, &[]) {
+            Err(Error::Kex) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+}
@@ -13,8 +13,10 @@
 // limitations under the License.
 //
 use cryptobuf::CryptoBuf;
-use {Sig, Error, ChannelOpenFailure};
+use {Sig, Error, Channel, ChannelOpenFailure};
+use rand::{thread_rng, Rng};
 use std;
+use std::collections::VecDeque;
 use auth;
 use session::*;
 use msg;
@@ -24,6 +26,7 @@ use key;
 use key::PubKey;
 use negociation;
 use negociation::Select;
+use std::borrow::Cow;
 
 const SSH_CONNECTION: &'static [u8] = b"ssh-connection";
 
@@ -56,9 +59,15 @@ impl super::Session {
                 return Ok(());
             }
         }
+        if buf[0] == msg::EXT_INFO {
+            if let Some(ref mut enc) = self.0.encrypted {
+                return enc.read_ext_info(buf);
+            }
+        }
         // If we've successfully read a packet.
         // debug!("state = {:?}, buf = {:?}", self.0.state, buf);
         let mut is_authenticated = false;
+        let mut service = None;
         if let Some(ref mut enc) = self.0.encrypted {
 
             let state = std::mem::replace(&mut enc.state, None);
@@ -66,7 +75,8 @@ impl super::Session {
                 Some(EncryptedState::WaitingServiceRequest) => {
                     if buf[0] == msg::SERVICE_ACCEPT {
                         let mut r = buf.reader(1);
-                        if try!(r.read_string()) == b"ssh-userauth" {
+                        let accepted = try!(r.read_string());
+                        if accepted == b"ssh-userauth" {
                             let auth_request = auth::AuthRequest {
                                 methods: auth::MethodSet::all(),
                                 partial_success: false,
@@ -74,16 +84,32 @@ impl super::Session {
                                 public_key_algorithm: CryptoBuf::new(),
                                 public_key_is_ok: false,
                                 sent_pk_ok: false,
+                                partial_user: None,
+                                current: None,
+                                failures: 0,
+                                banner_user: None,
+                                service: None,
+                                queued: VecDeque::new(),
                             };
 
                             if let Some(ref meth) = self.0.auth_method {
-                                if enc.write_auth_request(&self.0.auth_user, meth) {
+                                if enc.write_auth_request(&self.0.auth_user,
+                                                          auth_service(&self.0.config),
+                                                          meth) {
                                     enc.state =
                                         Some(EncryptedState::WaitingAuthRequest(auth_request));
                                     return Ok(());
                                 }
                             }
                             enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                        } else if let Some(ref s) = self.0.config.service {
+                            if s.authenticated || s.name.as_bytes() != accepted {
+                                return Err(Error::Inconsistent);
+                            }
+                            enc.state = Some(EncryptedState::Service {
+                                name: s.name.clone(),
+                                authenticated: false,
+                            })
                         } else {
                             enc.state = Some(EncryptedState::WaitingServiceRequest)
                         }
@@ -95,25 +121,101 @@ impl super::Session {
                 Some(EncryptedState::WaitingAuthRequest(mut auth_request)) => {
                     if buf[0] == msg::USERAUTH_SUCCESS {
 
-                        enc.state = Some(EncryptedState::Authenticated);
+                        enc.state = Some(match self.0.config.service {
+                            Some(ref s) if s.authenticated => {
+                                EncryptedState::Service {
+                                    name: s.name.clone(),
+                                    authenticated: true,
+                                }
+                            }
+                            _ => EncryptedState::Authenticated,
+                        });
+                        self.0.cipher.start_delayed_compression();
 
                     } else if buf[0] == msg::USERAUTH_FAILURE {
 
                         let mut r = buf.reader(1);
                         let remaining_methods = try!(r.read_string());
+                        auth_request.methods = auth::MethodSet::empty();
                         for method in remaining_methods.split(|&c| c == b',') {
                             if let Some(m) = auth::MethodSet::from_bytes(method) {
-                                auth_request.methods &= m
+                                auth_request.methods |= m
                             }
                         }
+                        auth_request.partial_success = try!(r.read_byte()) != 0;
                         self.0.auth_method = None;
                         enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
 
+                    } else if buf[0] == msg::USERAUTH_INFO_REQUEST &&
+                              is_keyboard_interactive(&self.0.auth_method) {
+
+                        // https://tools.ietf.org/html/rfc4256#section-3.2
+                        let mut r = buf.reader(1);
+                        let name = try!(std::str::from_utf8(try!(r.read_string())));
+                        let instructions = try!(std::str::from_utf8(try!(r.read_string())));
+                        try!(r.read_string()); // language tag, deprecated.
+                        let n_prompts = try!(r.read_u32());
+                        let mut prompts = Vec::new();
+                        for _ in 0..n_prompts {
+                            let prompt = try!(std::str::from_utf8(try!(r.read_string())));
+                            let echo = try!(r.read_byte()) != 0;
+                            prompts.push(auth::Prompt {
+                                prompt: Cow::Owned(prompt.to_string()),
+                                echo: echo,
+                            })
+                        }
+                        let responses = try!(client.auth_keyboard_interactive(name,
+                                                                              instructions,
+                                                                              &prompts));
+                        if responses.len() != prompts.len() {
+                            return Err(Error::WrongResponses);
+                        }
+                        push_packet!(enc.write, {
+                            enc.write.push(msg::USERAUTH_INFO_RESPONSE);
+                            enc.write.push_u32_be(responses.len() as u32);
+                            for response in responses.iter() {
+                                enc.write.extend_ssh_string(response.as_bytes());
+                            }
+                        });
+                        enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+
+                    } else if buf[0] == msg::USERAUTH_PASSWD_CHANGEREQ &&
+                              is_password(&self.0.auth_method) {
+
+                        // https://tools.ietf.org/html/rfc4252#section-8
+                        let mut r = buf.reader(1);
+                        let prompt = try!(std::str::from_utf8(try!(r.read_string())));
+                        if let Some((old, new)) = try!(client.auth_password_change(prompt)) {
+                            push_packet!(enc.write, {
+                                enc.write.push(msg::USERAUTH_REQUEST);
+                                enc.write.extend_ssh_string(self.0.auth_user.as_bytes());
+                                enc.write.extend_ssh_string(auth_service(&self.0.config));
+                                enc.write.extend_ssh_string(b"password");
+                                enc.write.push(1);
+                                enc.write.extend_ssh_string(old.as_bytes());
+                                enc.write.extend_ssh_string(new.as_bytes());
+                            });
+                        } else {
+                            self.0.auth_method = None;
+                        }
+                        enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+
+                    } else if buf[0] == msg::USERAUTH_BANNER {
+
+                        // https://tools.ietf.org/html/rfc4252#section-5.4
+                        let mut r = buf.reader(1);
+                        let banner = try!(std::str::from_utf8(try!(r.read_string())));
+                        client.auth_banner(banner);
+                        enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+
                     } else if buf[0] == msg::USERAUTH_PK_OK {
 
                         auth_request.public_key_is_ok = true;
                         if let Some(ref auth_method) = self.0.auth_method {
-                            enc.client_send_signature(&self.0.auth_user, auth_method, buffer);
+                            enc.client_send_signature(&self.0.auth_user,
+                                                      auth_service(&self.0.config),
+                                                      auth_method,
+                                                      buffer);
                         }
                         enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
                     } else {
@@ -125,11 +227,33 @@ impl super::Session {
                     enc.state = Some(EncryptedState::Authenticated);
                     is_authenticated = true
                 }
+                Some(EncryptedState::Service { name, authenticated }) => {
+                    service = Some(name.clone());
+                    enc.state = Some(EncryptedState::Service {
+                        name: name,
+                        authenticated: authenticated,
+                    })
+                }
                 None => unreachable!(),
             }
         }
+        if let Some(service) = service {
+            if buf[0] >= msg::USERAUTH_REQUEST {
+                return client.service_message(&service, buf, self);
+            }
+            info!("Unhandled packet: {:?}", buf);
+            return Ok(());
+        } else if let Some(service) = self.service().map(|s| s.to_string()) {
+            // The service has just started.
+            return client.service_started(&service, self);
+        }
+        // After a failure, or on the first request.
+        try!(self.next_auth_method(client));
         if is_authenticated {
             match buf[0] {
+                msg::CHANNEL_OPEN => {
+                    try!(self.client_handle_channel_open(client, buf));
+                }
                 msg::CHANNEL_OPEN_CONFIRMATION => {
                     debug!("channel_confirmation? {:?}", buf);
                     let mut reader = buf.reader(1);
@@ -204,13 +328,6 @@ impl super::Session {
                     let channel_num = try!(r.read_u32());
                     let req = try!(r.read_string());
                     match req {
-                        b"forwarded_tcpip" => {
-                            let a = try!(std::str::from_utf8(try!(r.read_string())));
-                            let b = try!(r.read_u32());
-                            let c = try!(std::str::from_utf8(try!(r.read_string())));
-                            let d = try!(r.read_u32());
-                            client.channel_open_forwarded_tcpip(channel_num, a, b, c, d, self);
-                        }
                         b"xon-xoff" => {
                             try!(r.read_byte()); // should be 0.
                             let client_can_do = try!(r.read_byte());
@@ -234,7 +351,24 @@ impl super::Session {
                                                     lang_tag,
                                                     self));
                         }
-                        _ => unimplemented!(),
+                        x => {
+                            debug!("unknown channel request: {:?}", std::str::from_utf8(x));
+                            let want_reply = try!(r.read_byte());
+                            if let Some(ref mut enc) = self.0.encrypted {
+                                if want_reply != 0 {
+                                    let recipient_channel = if let Some(channel) = enc.channels
+                                        .get(&channel_num) {
+                                        channel.recipient_channel
+                                    } else {
+                                        return Err(Error::WrongChannel);
+                                    };
+                                    push_packet!(enc.write, {
+                                        enc.write.push(msg::CHANNEL_FAILURE);
+                                        enc.write.push_u32_be(recipient_channel);
+                                    });
+                                }
+                            }
+                        }
                     }
                 }
                 msg::CHANNEL_WINDOW_ADJUST => {
@@ -257,36 +391,167 @@ impl super::Session {
         }
         Ok(())
     }
+
+    fn client_handle_channel_open<C: super::Handler>(&mut self,
+                                                     client: &mut C,
+                                                     buf: &[u8])
+                                                     -> Result<(), Error> {
+        // https://tools.ietf.org/html/rfc4254#section-5.1
+        let mut r = buf.reader(1);
+        let typ = try!(r.read_string());
+        let sender = try!(r.read_u32());
+        let window = try!(r.read_u32());
+        let maxpacket = try!(r.read_u32());
+
+        let failure = if typ == b"forwarded-tcpip" {
+            // https://tools.ietf.org/html/rfc4254#section-7.2
+            let connected_address = try!(std::str::from_utf8(try!(r.read_string())));
+            let connected_port = try!(r.read_u32());
+            let originator_address = try!(std::str::from_utf8(try!(r.read_string())));
+            let originator_port = try!(r.read_u32());
+            let mut sender_channel = 0;
+            let mut requested = false;
+            if let Some(ref enc) = self.0.encrypted {
+                requested = enc.tcpip_forwards.iter().any(|&(ref address, port)| {
+                    address == connected_address && (port == connected_port || port == 0)
+                });
+                while enc.channels.contains_key(&sender_channel) || sender_channel == 0 {
+                    sender_channel = thread_rng().gen()
+                }
+            }
+            if !requested {
+                debug!("unrequested forwarding: {:?} {:?}", connected_address, connected_port);
+                Some((ChannelOpenFailure::AdministrativelyProhibited, "Port not forwarded"))
+            } else if try!(client.channel_open_forwarded_tcpip(sender_channel,
+                                                               connected_address,
+                                                               connected_port,
+                                                               originator_address,
+                                                               originator_port,
+                                                               self)) {
+                if let Some(ref mut enc) = self.0.encrypted {
+                    enc.channels.insert(sender_channel,
+                                        Channel {
+                                            recipient_channel: sender,
+                                            sender_channel: sender_channel,
+                                            recipient_window_size: window,
+                                            sender_window_size: self.0.config.window_size,
+                                            recipient_maximum_packet_size: maxpacket,
+                                            sender_maximum_packet_size: self.0
+                                                .config
+                                                .maximum_packet_size,
+                                            confirmed: true,
+                                            wants_reply: false,
+                                        });
+                    push_packet!(enc.write, {
+                        enc.write.push(msg::CHANNEL_OPEN_CONFIRMATION);
+                        enc.write.push_u32_be(sender); // remote channel number.
+                        enc.write.push_u32_be(sender_channel); // our channel number.
+                        enc.write.push_u32_be(self.0.config.window_size);
+                        enc.write.push_u32_be(self.0.config.maximum_packet_size);
+                    });
+                }
+                None
+            } else {
+                Some((ChannelOpenFailure::ConnectFailed, "Connection refused"))
+            }
+        } else {
+            debug!("unknown channel type: {:?}", typ);
+            Some((ChannelOpenFailure::UnknownChannelType, "Unknown channel type"))
+        };
+        if let Some((reason, description)) = failure {
+            if let Some(ref mut enc) = self.0.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_OPEN_FAILURE);
+                    enc.write.push_u32_be(sender);
+                    enc.write.push_u32_be(reason as u32);
+                    enc.write.extend_ssh_string(description.as_bytes());
+                    enc.write.extend_ssh_string(b"en");
+                });
+            }
+        }
+        Ok(())
+    }
 }
 impl Encrypted {
-    pub fn write_auth_request(&mut self, user:&str, auth_method: &auth::Method<key::Algorithm>) -> bool {
+    pub fn write_auth_request(&mut self,
+                              user: &str,
+                              service: &[u8],
+                              auth_method: &auth::Method<key::Algorithm>)
+                              -> bool {
         // The server is waiting for our USERAUTH_REQUEST.
         push_packet!(self.write, {
             self.write.push(msg::USERAUTH_REQUEST);
             match *auth_method {
+                auth::Method::None => {
+                    self.write.extend_ssh_string(user.as_bytes());
+                    self.write.extend_ssh_string(service);
+                    self.write.extend_ssh_string(b"none");
+                    true
+                }
                 auth::Method::Password { ref password } => {
                     self.write.extend_ssh_string(user.as_bytes());
-                    self.write.extend_ssh_string(SSH_CONNECTION);
+                    self.write.extend_ssh_string(service);
                     self.write.extend_ssh_string(b"password");
-                    self.write.push(1);
+                    self.write.push(0);
                     self.write.extend_ssh_string(password.as_bytes());
                     true
                 }
                 auth::Method::PublicKey { ref key } => {
                     self.write.extend_ssh_string(user.as_bytes());
-                    self.write.extend_ssh_string(SSH_CONNECTION);
+                    self.write.extend_ssh_string(service);
                     self.write.extend_ssh_string(b"publickey");
                     self.write.push(0); // This is a probe
-                    self.write.extend_ssh_string(key.name().as_bytes());
+                    let algo = self.signature_algorithm(key);
+                    self.write.extend_ssh_string(algo.as_ref().as_bytes());
                     key.push_to(&mut self.write);
                     true
                 }
+                auth::Method::KeyboardInteractive { ref submethods } => {
+                    self.write.extend_ssh_string(user.as_bytes());
+                    self.write.extend_ssh_string(service);
+                    self.write.extend_ssh_string(b"keyboard-interactive");
+                    self.write.extend_ssh_string(b""); // language tag, deprecated.
+                    self.write.extend_ssh_string(submethods.as_bytes());
+                    true
+                }
+                auth::Method::Hostbased { ref key, ref host_name, ref client_user } => {
+                    // There is no probe for hostbased requests, they
+                    // are signed right away
+                    // (https://tools.ietf.org/html/rfc4252#section-9).
+                    let mut buffer = CryptoBuf::new();
+                    buffer.extend_ssh_string(&self.session_id);
+                    let i0 = buffer.len();
+                    buffer.push(msg::USERAUTH_REQUEST);
+                    buffer.extend_ssh_string(user.as_bytes());
+                    buffer.extend_ssh_string(service);
+                    buffer.extend_ssh_string(b"hostbased");
+                    let algo = self.signature_algorithm(key);
+                    buffer.extend_ssh_string(algo.as_ref().as_bytes());
+                    key.push_to(&mut buffer);
+                    buffer.extend_ssh_string(host_name.as_bytes());
+                    buffer.extend_ssh_string(client_user.as_bytes());
+                    key.add_self_signature(algo, &mut buffer);
+                    // The message number is already in self.write.
+                    self.write.extend(&buffer[i0 + 1..]);
+                    true
+                }
             }
         })
     }
 
+    /// The signature algorithm of `key`, chosen according to the
+    /// server's `server-sig-algs`.
+    fn signature_algorithm(&self, key: &key::Algorithm) -> key::Name {
+        let server_sig_algs = self.ext_info
+            .as_ref()
+            .and_then(|e| e.server_sig_algs.as_ref())
+            .map(|a| &a[..]);
+        key.signature_algorithm(server_sig_algs)
+    }
+
     pub fn client_send_signature(&mut self,
                                  user: &str,
+                                 service: &[u8],
                                  method: &auth::Method<key::Algorithm>,
                                  buffer: &mut CryptoBuf) {
         debug!("sending signature {:?}", method);
@@ -298,13 +563,14 @@ impl Encrypted {
                 let i0 = buffer.len();
                 buffer.push(msg::USERAUTH_REQUEST);
                 buffer.extend_ssh_string(user.as_bytes());
-                buffer.extend_ssh_string(SSH_CONNECTION);
+                buffer.extend_ssh_string(service);
                 buffer.extend_ssh_string(b"publickey");
                 buffer.push(1);
-                buffer.extend_ssh_string(key.name().as_bytes());
+                let algo = self.signature_algorithm(key);
+                buffer.extend_ssh_string(algo.as_ref().as_bytes());
                 key.push_to(buffer);
                 // Extend with self-signature.
-                key.add_self_signature(buffer);
+                key.add_self_signature(algo, buffer);
                 debug!("packet : {:?}", &buffer[i0..]);
                 push_packet!(self.write, {
                     self.write.extend(&buffer[i0..]);
@@ -314,3 +580,27 @@ impl Encrypted {
         }
     }
 }
+
+/// The service requested by authentication requests.
+pub fn auth_service(config: &super::Config) -> &[u8] {
+    match config.service {
+        Some(ref service) if service.authenticated => service.name.as_bytes(),
+        _ => SSH_CONNECTION,
+    }
+}
+
+fn is_password(method: &Option<auth::Method<key::Algorithm>>) -> bool {
+    if let Some(auth::Method::Password { .. }) = *method {
+        true
+    } else {
+        false
+    }
+}
+
+fn is_keyboard_interactive(method: &Option<auth::Method<key::Algorithm>>) -> bool {
+    if let Some(auth::Method::KeyboardInteractive { .. }) = *method {
+        true
+    } else {
+        false
+    }
+}
@@ -14,11 +14,11 @@
 //
 
 use std::sync::Arc;
+use std::collections::VecDeque;
 use std::io::{Write, BufRead};
 use std;
 
-use {Disconnect, Error, Limits, Sig, ChannelOpenFailure, parse_public_key};
-use sodium;
+use {Disconnect, Error, Limits, Sig, ChannelOpenFailure, Service, parse_public_key};
 use encoding::Reader;
 use key;
 use msg;
@@ -26,7 +26,7 @@ use auth;
 use cipher::CipherT;
 use negociation;
 use cryptobuf::CryptoBuf;
-use negociation::Select;
+use negociation::{Select, Named};
 use session::*;
 use sshbuffer::*;
 use cipher;
@@ -49,6 +49,14 @@ pub struct Config {
     pub maximum_packet_size: u32,
     /// Lists of preferred algorithms.
     pub preferred: negociation::Preferred,
+    /// Send the first key exchange packet right after KEXINIT,
+    /// guessing that the server's preferred algorithms are the same
+    /// as ours. This saves a round trip if the guess is correct.
+    pub guess_kex: bool,
+    /// A custom service to request instead of the standard
+    /// "ssh-connection" service. Its messages are handled by
+    /// `Handler::service_message`.
+    pub service: Option<Service>,
 }
 
 impl std::default::Default for Config {
@@ -61,6 +69,8 @@ impl std::default::Default for Config {
             window_size: 200000,
             maximum_packet_size: 200000,
             preferred: Default::default(),
+            guess_kex: false,
+            service: None,
         }
     }
 }
@@ -88,17 +98,87 @@ impl std::ops::DerefMut for Connection {
 #[derive(Debug)]
 pub struct Session(CommonSession<Config>);
 
+/// The answer of a client to the server's public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    Accept,
+    Reject,
+    /// The decision will be made later, and given to
+    /// `Session::complete_check_server_key`. Meanwhile, the key
+    /// exchange is suspended, and `Connection::read` does not read
+    /// anything.
+    Pending,
+}
+
 pub trait Handler {
-    /// Called when the server sends us an authentication banner. This is usually meant to be shown to the user, see [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.4) for more details.
+    /// Called when the server sends us an authentication banner. This is usually meant to be shown to the user, see [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.4) for more details. Servers may send several banners at any time during authentication.
     #[allow(unused_variables)]
     fn auth_banner(&mut self, banner: &str) {}
 
+    /// Called when the server asks questions during
+    /// keyboard-interactive authentication
+    /// ([RFC4256](https://tools.ietf.org/html/rfc4256)). This must
+    /// return one answer per prompt, in the same order, or else
+    /// `Connection::read` returns `Error::WrongResponses`. `name` and
+    /// `instructions` are meant to be shown to the user, and may be
+    /// empty. The default implementation answers no prompts.
+    #[allow(unused_variables)]
+    fn auth_keyboard_interactive(&mut self,
+                                 name: &str,
+                                 instructions: &str,
+                                 prompts: &[auth::Prompt])
+                                 -> Result<Vec<String>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Called when the server asks the client to change its password
+    /// during password authentication
+    /// ([RFC4252](https://tools.ietf.org/html/rfc4252#section-8)),
+    /// usually because it has expired. `prompt` is meant to be shown
+    /// to the user. Returning the old and new passwords sends them to
+    /// the server, whereas returning `None` gives up on this method,
+    /// after which the client may choose another one. The default
+    /// implementation returns `None`.
+    #[allow(unused_variables)]
+    fn auth_password_change(&mut self, prompt: &str) -> Result<Option<(String, String)>, Error> {
+        Ok(None)
+    }
+
+    /// Called when the authentication chain reaches
+    /// `auth::Authenticator::Password`, and the server accepts
+    /// passwords for `user`. This is called again after each failed
+    /// password, until it returns `None`, which moves on to the next
+    /// entry of the chain. The default implementation returns `None`.
+    #[allow(unused_variables)]
+    fn auth_password(&mut self, user: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
     /// Called to check the server's public key. This is a very important
     /// step to help prevent man-in-the-middle attacks. The default
     /// implementation rejects all keys.
     #[allow(unused_variables)]
-    fn check_server_key(&mut self, server_public_key: &key::PublicKey) -> Result<bool, Error> {
-        Ok(false)
+    fn check_server_key(&mut self, server_public_key: &key::PublicKey) -> Result<KeyCheck, Error> {
+        Ok(KeyCheck::Reject)
+    }
+
+    /// Called when the custom service of `Config::service` starts,
+    /// after which its messages can be sent with
+    /// `Session::send_service_message`.
+    #[allow(unused_variables)]
+    fn service_started(&mut self, service: &str, session: &mut Session) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when the server sends a message of the custom service
+    /// (see `Config::service`), including its message number.
+    #[allow(unused_variables)]
+    fn service_message(&mut self,
+                       service: &str,
+                       message: &[u8],
+                       session: &mut Session)
+                       -> Result<(), Error> {
+        Ok(())
     }
 
     /// Called when the server confirmed our request to open a channel. A channel can only be written to after receiving this message (this library panics otherwise).
@@ -134,7 +214,14 @@ pub trait Handler {
         Ok(())
     }
 
-    /// Called when a new channel is created.
+    /// Called when the server opens a channel for a connection to a
+    /// port forwarded with `Session::tcpip_forward`
+    /// ([RFC4254](https://tools.ietf.org/html/rfc4254#section-7.2)).
+    /// Returning `true` accepts the channel, which can be written to
+    /// after this method returns, whereas returning `false` rejects
+    /// it. Channels for ports that were not requested are rejected
+    /// without calling this method. The default implementation
+    /// rejects all channels.
     #[allow(unused_variables)]
     fn channel_open_forwarded_tcpip(&mut self,
                                     channel: u32,
@@ -142,7 +229,9 @@ pub trait Handler {
                                     connected_port: u32,
                                     originator_address: &str,
                                     originator_port: u32,
-                                    session: &mut Session) {
+                                    session: &mut Session)
+                                    -> Result<bool, Error> {
+        Ok(false)
     }
 
     /// Called when the server sends us data. The `extended_code` parameter is a stream identifier, `None` is usually the standard output, and `Some(1)` is the standard error. See [RFC4254](https://tools.ietf.org/html/rfc4254#section-5.2).
@@ -227,19 +316,26 @@ impl KexInit {
             self.client_write(config, cipher, write_buffer)
         }
 
-        // This function is called from the public API.
-        //
-        // In order to simplify the public API, we reuse the
-        // self.exchange.client_kex buffer to send an extra packet,
-        // then truncate that buffer. Without that, we would need an
-        // extra buffer.
-        let i0 = self.exchange.client_kex_init.len();
-        let kex = try!(kex::Algorithm::client_dh(algo.kex,
-                                                 &mut self.exchange.client_ephemeral,
-                                                 &mut self.exchange.client_kex_init));
-
-        cipher.write(&self.exchange.client_kex_init[i0..], write_buffer);
-        self.exchange.client_kex_init.truncate(i0);
+        let kex = match self.guessed.take() {
+            // Our guess was right, the server will answer the packet we've already sent.
+            Some(kex) if algo.guess_correct => kex,
+            _ => {
+                // This function is called from the public API.
+                //
+                // In order to simplify the public API, we reuse the
+                // self.exchange.client_kex buffer to send an extra packet,
+                // then truncate that buffer. Without that, we would need an
+                // extra buffer.
+                let i0 = self.exchange.client_kex_init.len();
+                let kex = try!(kex::Algorithm::client_dh(algo.kex,
+                                                         &mut self.exchange.client_ephemeral,
+                                                         &mut self.exchange.client_kex_init));
+
+                cipher.write(&self.exchange.client_kex_init[i0..], write_buffer);
+                self.exchange.client_kex_init.truncate(i0);
+                kex
+            }
+        };
 
 
         Ok(KexDhDone {
@@ -256,9 +352,33 @@ impl KexInit {
                                         cipher: &mut C,
                                         write_buffer: &mut SSHBuffer) {
         self.exchange.client_kex_init.clear();
-        negociation::write_kex(&config.preferred, &mut self.exchange.client_kex_init);
+        // Only guess during the first key exchange, when we don't
+        // know the server's algorithms yet.
+        let guess = if config.guess_kex && self.session_id.is_none() && self.algo.is_none() &&
+                       !config.preferred.key.is_empty() {
+            config.preferred.kex.first()
+        } else {
+            None
+        };
+        // Compute the guessed packet first: first_kex_packet_follows
+        // must only be set if it is actually sent.
+        let mut guessed = CryptoBuf::new();
+        if let Some(&name) = guess {
+            if let Ok(kex) = kex::Algorithm::client_dh(name,
+                                                       &mut self.exchange.client_ephemeral,
+                                                       &mut guessed) {
+                self.guessed = Some(kex)
+            }
+        }
+        negociation::Client::write_kex(&config.preferred,
+                                       &mut self.exchange.client_kex_init,
+                                       self.session_id.is_none(),
+                                       self.guessed.is_some());
         self.sent = true;
-        cipher.write(&self.exchange.client_kex_init, write_buffer)
+        cipher.write(&self.exchange.client_kex_init, write_buffer);
+        if self.guessed.is_some() {
+            cipher.write(&guessed, write_buffer);
+        }
     }
 }
 
@@ -280,13 +400,10 @@ impl KexDhDone {
             debug!("kexdhdone");
             // We've sent ECDH_INIT, waiting for ECDH_REPLY
             if buf[0] == msg::KEX_ECDH_REPLY {
-                let hash = {
+                let (hash, check) = {
                     let mut reader = buf.reader(1);
                     let pubkey = try!(reader.read_string()); // server public key.
                     let pubkey = try!(parse_public_key(pubkey));
-                    if !try!(client.check_server_key(&pubkey)) {
-                        return Err(Error::UnknownKey);
-                    }
                     let server_ephemeral = try!(reader.read_string());
                     self.exchange.server_ephemeral.extend(server_ephemeral);
                     let signature = try!(reader.read_string());
@@ -295,26 +412,32 @@ impl KexDhDone {
                     
                     let hash = try!(self.kex.compute_exchange_hash(&pubkey, &self.exchange, buffer));
 
-                    let signature = {
+                    let (sig_type, signature) = {
                         let mut sig_reader = signature.reader(0);
                         let sig_type = try!(sig_reader.read_string());
-                        assert_eq!(sig_type, b"ssh-ed25519");
-                        let signature = try!(sig_reader.read_string());
-                        sodium::ed25519::Signature::copy_from_slice(signature)
-                    };
-
-                    match pubkey {
-                        key::PublicKey::Ed25519(ref pubkey) => {
-                            assert!(sodium::ed25519::verify_detached(&signature, &hash, pubkey))
+                        if sig_type != self.names.key.as_ref().as_bytes() {
+                            return Err(Error::Kex);
                         }
+                        (sig_type, try!(sig_reader.read_string()))
                     };
+
+                    if !pubkey.verify_signature(sig_type, &hash, signature) {
+                        return Err(Error::Kex);
+                    }
                     debug!("signature = {:?}", signature);
                     debug!("exchange = {:?}", self.exchange);
-                    hash
+                    (hash, try!(client.check_server_key(&pubkey)))
                 };
+                if let KeyCheck::Reject = check {
+                    return Err(Error::UnknownKey);
+                }
                 let mut newkeys = try!(self.compute_keys(hash, buffer, buffer2, false));
-                cipher.write(&[msg::NEWKEYS], write_buffer);
-                newkeys.sent = true;
+                // If the check is pending, NEWKEYS is sent by
+                // `Session::complete_check_server_key`.
+                if let KeyCheck::Accept = check {
+                    cipher.write(&[msg::NEWKEYS], write_buffer);
+                    newkeys.sent = true;
+                }
                 Ok(Kex::NewKeys(newkeys))
             } else {
                 return Err(Error::Inconsistent);
@@ -337,12 +460,14 @@ impl Connection {
                 write_buffer: write_buffer,
                 auth_user: String::new(),
                 auth_method: None,
+                auth_chain: VecDeque::new(),
                 kex: None,
                 cipher: cipher::CLEAR_PAIR,
                 encrypted: None,
                 config: config,
                 wants_reply: false,
                 disconnected: false,
+                strict_kex: false,
             }),
         };
         session
@@ -360,6 +485,8 @@ impl Connection {
         if self.session.0.disconnected {
             return Err(Error::Disconnect);
         }
+        // In case the chain was set while the server was waiting.
+        try!(self.session.next_auth_method(client));
         let mut at_least_one_was_read = false;
         loop {
             match self.read_one_packet(client, stream, buffer, buffer2) {
@@ -404,6 +531,7 @@ impl Connection {
                 algo: None,
                 sent: false,
                 session_id: None,
+                guessed: None,
             };
             kexinit.client_write(self.session.0.config.as_ref(),
                                  &mut self.session.0.cipher,
@@ -414,6 +542,14 @@ impl Connection {
 
 
 
+        // While the server key check is pending, the server's NEWKEYS
+        // and the packets encrypted with the new keys are left unread.
+        if let Some(Kex::NewKeys(ref newkeys)) = self.session.0.kex {
+            if !newkeys.sent {
+                return Ok(false);
+            }
+        }
+
         // In all other cases:
         if let Some(buf) = try!(self.session.0.cipher.read(stream, &mut self.read_buffer)) {
             debug!("read buf = {:?}", buf);
@@ -422,6 +558,7 @@ impl Connection {
                 // transport
                 return Err(Error::Disconnect);
             }
+            try!(self.session.0.check_strict_kex(buf[0]));
             if buf[0] <= 4 {
                 return Ok(true);
             }
@@ -438,6 +575,8 @@ impl Connection {
 
                         match kexdhdone {
                             Ok(kexdhdone) => {
+                                try!(self.session.0.set_strict_kex(&kexdhdone.names,
+                                                                   self.read_buffer.seqn));
                                 self.session.0.kex = Some(Kex::KexDhDone(kexdhdone));
                                 return Ok(true);
                             }
@@ -456,6 +595,12 @@ impl Connection {
                                                      &mut self.session.0.write_buffer);
                     match kex {
                         Ok(kex) => {
+                            if let Kex::NewKeys(ref newkeys) = kex {
+                                // We've just sent NEWKEYS.
+                                if newkeys.sent && self.session.0.strict_kex {
+                                    self.session.0.write_buffer.seqn = 0
+                                }
+                            }
                             self.session.0.kex = Some(kex);
                             return Ok(true);
                         }
@@ -466,11 +611,23 @@ impl Connection {
                     if buf[0] != msg::NEWKEYS {
                         return Err(Error::NewKeys);
                     }
+                    if self.session.0.strict_kex {
+                        self.read_buffer.seqn = 0
+                    }
+                    let first_kex = self.session.0.encrypted.is_none();
                     self.session.0.encrypted(EncryptedState::WaitingServiceRequest, newkeys);
                     // Ok, NEWKEYS received, now encrypted.
                     // We can't use flush here, because self.buffers is borrowed.
-                    let p = b"\x05\0\0\0\x0Cssh-userauth";
-                    self.session.0.cipher.write(p, &mut self.session.0.write_buffer);
+                    if first_kex {
+                        let service: &[u8] = match self.session.0.config.service {
+                            Some(ref service) if !service.authenticated => service.name.as_bytes(),
+                            _ => b"ssh-userauth",
+                        };
+                        buffer.clear();
+                        buffer.push(msg::SERVICE_REQUEST);
+                        buffer.extend_ssh_string(service);
+                        self.session.0.cipher.write(&buffer, &mut self.session.0.write_buffer);
+                    }
                 }
                 Some(kex) => self.session.0.kex = Some(kex),
                 None => {
@@ -513,6 +670,41 @@ impl Session {
         &self.0.config
     }
 
+    /// Complete the check of the server's public key, for which the
+    /// handler returned `KeyCheck::Pending`. If the key is rejected,
+    /// the session is disconnected and this returns
+    /// `Error::UnknownKey`. This returns `Error::Inconsistent` if no
+    /// check is pending.
+    pub fn complete_check_server_key(&mut self, accept: bool) -> Result<(), Error> {
+        match self.0.kex {
+            Some(Kex::NewKeys(ref mut newkeys)) if !newkeys.sent => {
+                if accept {
+                    self.0.cipher.write(&[msg::NEWKEYS], &mut self.0.write_buffer);
+                    newkeys.sent = true;
+                    if self.0.strict_kex {
+                        self.0.write_buffer.seqn = 0
+                    }
+                    return Ok(());
+                }
+            }
+            _ => return Err(Error::Inconsistent),
+        }
+        self.0.disconnect(Disconnect::HostKeyNotVerifiable, "Unknown host key", "en");
+        Err(Error::UnknownKey)
+    }
+
+    /// Extensions sent by the server in `SSH_MSG_EXT_INFO`, if any
+    /// (see [RFC8308](https://tools.ietf.org/html/rfc8308)). This
+    /// includes the `server-sig-algs` list of public key algorithms
+    /// accepted by the server.
+    pub fn ext_info(&self) -> Option<&negociation::ExtInfo> {
+        if let Some(ref enc) = self.0.encrypted {
+            enc.ext_info.as_ref()
+        } else {
+            None
+        }
+    }
+
     /// Retrieves the current user.
     pub fn auth_user(&self) -> &str {
         &self.0.auth_user
@@ -529,18 +721,133 @@ impl Session {
         self.0.auth_user.push_str(user)
     }
 
-    /// Set the authentication method.
+    /// Probe the server with the "none" method, which usually fails
+    /// and lets the client know the methods it can use before
+    /// sending any credentials. Once the server has answered,
+    /// `has_auth_method` returns `false`, and these methods are
+    /// returned by `valid_auth_methods`.
+    pub fn set_auth_none(&mut self) {
+        self.set_auth_method(auth::Method::None)
+    }
+
+    /// Set the authentication method. If the previous method failed
+    /// or was only partially successful, the request is sent
+    /// immediately. If this method fails, the client moves on to the
+    /// authentication chain, see `set_auth_chain`.
     pub fn set_auth_public_key(&mut self, key: key::Algorithm) {
-        self.0.auth_method = Some(auth::Method::PublicKey {
-            key: key,
-        });
+        self.set_auth_method(auth::Method::PublicKey { key: key })
     }
 
-    /// Set the authentication method.
+    /// Set the authentication method, see `set_auth_public_key`.
     pub fn set_auth_password(&mut self, password: String) {
-        self.0.auth_method = Some(auth::Method::Password {
-            password: password,
-        });
+        self.set_auth_method(auth::Method::Password { password: password })
+    }
+
+    /// Set the authentication method to "keyboard-interactive". The
+    /// server's prompts are answered by
+    /// `Handler::auth_keyboard_interactive`. `submethods` is a
+    /// comma-separated list of hints for the server, usually empty.
+    pub fn set_auth_keyboard_interactive(&mut self, submethods: &str) {
+        self.set_auth_method(auth::Method::KeyboardInteractive {
+            submethods: submethods.to_string(),
+        })
+    }
+
+    /// Set the authentication method to "hostbased", where `key` is
+    /// the host key of this machine, `host_name` its fully qualified
+    /// domain name, and `client_user` the name of the user on this
+    /// machine.
+    pub fn set_auth_hostbased(&mut self,
+                              key: key::Algorithm,
+                              host_name: &str,
+                              client_user: &str) {
+        self.set_auth_method(auth::Method::Hostbased {
+            key: key,
+            host_name: host_name.to_string(),
+            client_user: client_user.to_string(),
+        })
+    }
+
+    /// Set a list of authentication methods, tried in order until the
+    /// server accepts one, or the list is exhausted, after which
+    /// `has_auth_method` returns `false`. Methods that the server
+    /// does not accept anymore (see `valid_auth_methods`) are
+    /// skipped, and after a partial success, the client continues
+    /// with the next entry of the list. For instance, several keys
+    /// can be tried, followed by a password from
+    /// `Handler::auth_password`:
+    ///
+    /// ```
+    /// use thrussh::{auth, key, client};
+    /// # fn chain(session: &mut client::Session, keys: Vec<key::Algorithm>) {
+    /// let mut chain: Vec<_> = keys.into_iter()
+    ///     .map(|key| auth::Authenticator::Method(auth::Method::PublicKey { key: key }))
+    ///     .collect();
+    /// chain.push(auth::Authenticator::Password);
+    /// session.set_auth_chain(chain);
+    /// # }
+    /// ```
+    ///
+    /// Once authenticated, `auth_method` returns the method that
+    /// succeeded.
+    pub fn set_auth_chain(&mut self, chain: Vec<auth::Authenticator<key::Algorithm>>) {
+        self.0.auth_chain = chain.into_iter().collect()
+    }
+
+    /// The method currently tried, or after authentication, the one
+    /// that succeeded.
+    pub fn auth_method(&self) -> Option<&auth::Method<key::Algorithm>> {
+        self.0.auth_method.as_ref()
+    }
+
+    /// If the server is waiting for a request and no method is set,
+    /// send the next method of the chain that the server accepts.
+    fn next_auth_method<C: Handler>(&mut self, client: &mut C) -> Result<(), Error> {
+        if self.0.auth_method.is_some() {
+            return Ok(());
+        }
+        let methods = if let Some(methods) = self.valid_auth_methods() {
+            methods
+        } else {
+            return Ok(());
+        };
+        while let Some(authenticator) = self.0.auth_chain.pop_front() {
+            let method = match authenticator {
+                auth::Authenticator::Method(method) => method,
+                auth::Authenticator::Password => {
+                    if !methods.contains(auth::PASSWORD) {
+                        continue;
+                    }
+                    if let Some(password) = try!(client.auth_password(&self.0.auth_user)) {
+                        // Ask again if this password fails.
+                        self.0.auth_chain.push_front(auth::Authenticator::Password);
+                        auth::Method::Password { password: password }
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            if methods.contains(method.method_set()) {
+                self.set_auth_method(method);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_auth_method(&mut self, method: auth::Method<key::Algorithm>) {
+        // If the server is waiting for our next request, send it now.
+        if self.0.auth_method.is_none() {
+            if let Some(ref mut enc) = self.0.encrypted {
+                if let Some(EncryptedState::WaitingAuthRequest(_)) = enc.state {
+                    enc.write_auth_request(&self.0.auth_user,
+                                           encrypted::auth_service(&self.0.config),
+                                           &method);
+                }
+            }
+            self.flush()
+        }
+        self.0.auth_method = Some(method)
     }
 
     /// Whether the client is authenticated.
@@ -553,6 +860,33 @@ impl Session {
         false
     }
 
+    /// The name of the custom service running, if any (see
+    /// `Config::service`). `is_authenticated` only concerns the
+    /// "ssh-connection" service, and is `false` in this case.
+    pub fn service(&self) -> Option<&str> {
+        if let Some(ref enc) = self.0.encrypted {
+            enc.service()
+        } else {
+            None
+        }
+    }
+
+    /// Send a message of the custom service running, starting with
+    /// its message number, which must not be one of the transport
+    /// layer (below 50). Returns `false`, and sends nothing, if no
+    /// custom service is running or the message number is invalid.
+    pub fn send_service_message(&mut self, message: &[u8]) -> bool {
+        let sent = if let Some(ref mut enc) = self.0.encrypted {
+            enc.write_service_message(message)
+        } else {
+            false
+        };
+        if sent {
+            self.flush()
+        }
+        sent
+    }
+
     /// Check whether a channel has been confirmed.
     pub fn channel_is_open(&self, channel: u32) -> bool {
         if let Some(ref enc) = self.0.encrypted {
@@ -568,6 +902,18 @@ impl Session {
         self.0.auth_method.is_some()
     }
 
+    /// Whether the server accepted our last authentication method,
+    /// but requires another one from `valid_auth_methods`
+    /// (multi-factor authentication).
+    pub fn is_partially_authenticated(&self) -> bool {
+        if let Some(ref enc) = self.0.encrypted {
+            if let Some(EncryptedState::WaitingAuthRequest(ref auth_request)) = enc.state {
+                return auth_request.partial_success;
+            }
+        }
+        false
+    }
+
     /// Returns the set of authentication methods that can continue, or None if this is not valid.
     pub fn valid_auth_methods(&self) -> Option<auth::MethodSet> {
         if let Some(ref enc) = self.0.encrypted {
@@ -935,9 +1281,10 @@ impl Session {
         self.flush();
     }
 
-    /// Request the forwarding of a remote port to the client. The server will then open forwarding channels (which cause the client to call `.channel_open_forwarded_tcpip()`).
+    /// Request the forwarding of a remote port to the client. The server will then open forwarding channels (which cause the client to call `.channel_open_forwarded_tcpip()`). If `port` is 0, the server chooses the port, and channels for any port on `address` are accepted.
     pub fn tcpip_forward(&mut self, want_reply: bool, address: &str, port: u32) {
         if let Some(ref mut enc) = self.0.encrypted {
+            enc.tcpip_forwards.push((address.to_string(), port));
             push_packet!(enc.write, {
                 enc.write.push(msg::GLOBAL_REQUEST);
                 enc.write.extend_ssh_string(b"tcpip-forward");
@@ -956,6 +1303,7 @@ impl Session {
     /// Cancel a previous forwarding request.
     pub fn cancel_tcpip_forward(&mut self, want_reply: bool, address: &str, port: u32) {
         if let Some(ref mut enc) = self.0.encrypted {
+            enc.tcpip_forwards.retain(|&(ref a, p)| a != address || p != port);
             push_packet!(enc.write, {
                 enc.write.push(msg::GLOBAL_REQUEST);
                 enc.write.extend_ssh_string(b"cancel-tcpip-forward");
@@ -971,3 +1319,219 @@ impl Session {
         self.flush();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use testing;
+    use super::Config;
+    use cipher::{self, CipherT};
+    use encoding::Reader;
+    use session::{Exchange, KexInit};
+    use sshbuffer::SSHBuffer;
+    use std::borrow::Cow;
+    use backend::{Backend, Selected};
+    use super::{Handler, KeyCheck};
+    use {auth, key, kex, msg, server, Error, Preferred};
+
+    /// Run `KexInit::client_write`, and return the packets it sent.
+    fn client_kexinit(config: &Config) -> Vec<Vec<u8>> {
+        let mut kexinit = KexInit {
+            exchange: Exchange::new(),
+            algo: None,
+            sent: false,
+            session_id: None,
+            guessed: None,
+        };
+        let mut written = SSHBuffer::new();
+        kexinit.client_write(config, &mut cipher::CLEAR_PAIR, &mut written);
+        assert_eq!(kexinit.guessed.is_some(), written.seqn == 2);
+        let mut stream = &written.buffer[..written.buffer.len()];
+        let mut read = SSHBuffer::new();
+        let mut packets = Vec::new();
+        while !stream.is_empty() {
+            packets.push(cipher::Clear.read(&mut stream, &mut read).unwrap().unwrap().to_vec());
+        }
+        packets
+    }
+
+    /// The first_kex_packet_follows field of a KEXINIT.
+    fn follows(kexinit: &[u8]) -> bool {
+        let mut r = kexinit.reader(17);
+        for _ in 0..10 {
+            r.read_string().unwrap();
+        }
+        r.read_byte().unwrap() != 0
+    }
+
+    #[test]
+    fn guessed_kex_packet_follows_kexinit() {
+        let mut config = Config::default();
+        config.guess_kex = true;
+        let packets = client_kexinit(&config);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][0], msg::KEXINIT);
+        assert!(follows(&packets[0]));
+        assert_eq!(packets[1][0], msg::KEX_ECDH_INIT);
+
+        config.guess_kex = false;
+        let packets = client_kexinit(&config);
+        assert_eq!(packets.len(), 1);
+        assert!(!follows(&packets[0]));
+    }
+
+    struct Failing;
+    impl kex::KexAlgorithm for Failing {
+        fn start(&self) -> Result<Box<kex::KexState>, Error> {
+            Err(Error::Kex)
+        }
+    }
+
+    #[test]
+    fn failed_guess_is_not_announced() {
+        let failing = kex::register("failing@example.com", Box::new(Failing));
+        let mut config = Config::default();
+        config.guess_kex = true;
+        config.preferred.kex = Cow::Owned(vec![failing, kex::CURVE25519]);
+        let packets = client_kexinit(&config);
+        assert_eq!(packets.len(), 1);
+        assert!(!follows(&packets[0]));
+    }
+
+    #[test]
+    fn guess_kex() {
+        let mut config = Config::default();
+        config.guess_kex = true;
+        let mut p = testing::pair(Default::default(), config, testing::client_key());
+        p.authenticate();
+        p.echo(b"right guess");
+
+        // The server prefers another method, and ignores the guess.
+        let mut server_config = ::server::Config::default();
+        server_config.preferred = Preferred {
+            kex: Cow::Borrowed(&[kex::ECDH_NISTP256, kex::CURVE25519]),
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        config.guess_kex = true;
+        let mut p = testing::pair(server_config, config, testing::client_key());
+        p.authenticate();
+        p.echo(b"wrong guess");
+    }
+
+    #[test]
+    fn strict_kex_resets_sequence_numbers() {
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        p.step().unwrap();
+        p.step().unwrap();
+        // The server's KEXINIT, ECDH_REPLY and NEWKEYS.
+        p.client_step().unwrap();
+        assert!(p.client_connection.session.0.strict_kex);
+        assert_eq!(p.client_connection.read_buffer.seqn, 0);
+        p.authenticate();
+        p.echo(b"strict");
+    }
+
+    #[test]
+    fn signature_algorithm_follows_server_sig_algs() {
+        let rsa = key::Algorithm::rsa(Selected::rsa_keypair(2048).unwrap());
+        let list = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(rsa.signature_algorithm(Some(&list(&["rsa-sha2-256", "rsa-sha2-512"]))),
+                   key::RSA_SHA2_512);
+        assert_eq!(rsa.signature_algorithm(Some(&list(&["ssh-rsa", "rsa-sha2-256"]))),
+                   key::RSA_SHA2_256);
+        assert_eq!(rsa.signature_algorithm(Some(&list(&["ssh-ed25519"]))),
+                   key::RSA_SHA2_512);
+        #[cfg(feature = "legacy")]
+        assert_eq!(rsa.signature_algorithm(None), key::RSA);
+        #[cfg(not(feature = "legacy"))]
+        assert_eq!(rsa.signature_algorithm(None), key::RSA_SHA2_512);
+
+        let ed25519 = testing::client_key();
+        assert_eq!(ed25519.signature_algorithm(Some(&list(&["rsa-sha2-256"]))),
+                   key::ED25519);
+    }
+
+    #[test]
+    fn keyboard_interactive_responses_must_match_the_prompts() {
+        // The default handler answers no prompts.
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        p.client_connection.set_auth_keyboard_interactive("");
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = p.step();
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Err(Error::WrongResponses) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    /// A client answering `auth_password` with `passwords`, in order.
+    struct Passwords {
+        server_pk: key::PublicKey,
+        passwords: Vec<&'static str>,
+    }
+
+    impl Handler for Passwords {
+        fn check_server_key(&mut self, server_pk: &key::PublicKey) -> Result<KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(KeyCheck::Accept)
+        }
+        fn auth_password(&mut self, user: &str) -> Result<Option<String>, Error> {
+            assert_eq!(user, "pe");
+            if self.passwords.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(self.passwords.remove(0).to_string()))
+            }
+        }
+    }
+
+    fn passwords_pair(methods: auth::MethodSet,
+                      passwords: Vec<&'static str>)
+                      -> testing::Pair<testing::Server, Passwords> {
+        let mut config = server::Config::default();
+        config.methods = methods;
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let client = Passwords {
+            server_pk: server_key.clone_public_key(),
+            passwords: passwords,
+        };
+        let server = testing::Server { client_pubkey: testing::client_key().clone_public_key() };
+        testing::Pair::new(server, config, server_key, client, Default::default())
+    }
+
+    #[test]
+    fn auth_chain() {
+        // Keyboard-interactive is skipped, the wrong key and the
+        // first password fail, and the second password succeeds.
+        let mut p = passwords_pair(auth::PUBLICKEY | auth::PASSWORD, vec!["x", "pw", "y"]);
+        p.client_connection.set_auth_chain(vec![
+            auth::Authenticator::Method(auth::Method::KeyboardInteractive {
+                submethods: String::new(),
+            }),
+            auth::Authenticator::Method(auth::Method::PublicKey { key: testing::client_key() }),
+            auth::Authenticator::Password,
+        ]);
+        p.authenticate();
+        assert_eq!(p.client.passwords, vec!["y"]);
+        match p.client_connection.session.auth_method() {
+            Some(&auth::Method::Password { ref password }) => assert_eq!(password, "pw"),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn auth_chain_exhausted() {
+        let mut p = passwords_pair(auth::PUBLICKEY | auth::PASSWORD, vec!["x", "y"]);
+        p.client_connection.set_auth_chain(vec![auth::Authenticator::Password]);
+        p.run_until(|p| {
+            p.client.passwords.is_empty() && !p.client_connection.session.has_auth_method()
+        });
+        assert!(!p.client_connection.session.is_authenticated());
+        assert!(!p.client_connection.session.is_partially_authenticated())
+    }
+}
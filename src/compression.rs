@@ -1 +1,247 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+use flate2;
+use std;
+use Error;
+use negociation;
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Name(&'static str);
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+pub const NONE: Name = Name("none");
+/// zlib compression, started right after the first key exchange
+/// ([RFC4253](https://tools.ietf.org/html/rfc4253#section-6.2)).
+pub const ZLIB: Name = Name("zlib");
+/// zlib compression, started only after the server sends
+/// `SSH_MSG_USERAUTH_SUCCESS`, so that unauthenticated clients
+/// cannot reach the decompressor.
+pub const ZLIB_DELAYED: Name = Name("zlib@openssh.com");
+
+/// All the compression algorithms implemented by this crate.
+pub const ALL: &'static [Name] = &[NONE, ZLIB, ZLIB_DELAYED];
+
+impl std::str::FromStr for Name {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Name, Error> {
+        negociation::parse_name(ALL, s)
+    }
+}
+
+// Maximal length of a decompressed payload.
+const MAX_PAYLOAD_LEN: usize = 1 << 18;
+
+/// The compression state of outgoing packets.
+#[derive(Debug)]
+pub enum Compress {
+    None,
+    /// zlib@openssh.com, before authentication.
+    Delayed,
+    Zlib(flate2::Compress),
+}
+
+/// The compression state of incoming packets.
+#[derive(Debug)]
+pub enum Decompress {
+    None,
+    /// zlib@openssh.com, before authentication.
+    Delayed,
+    Zlib(flate2::Decompress),
+}
+
+impl Compress {
+    pub fn new(name: Name) -> Compress {
+        match name {
+            ZLIB => Compress::Zlib(flate2::Compress::new(flate2::Compression::fast(), true)),
+            ZLIB_DELAYED => Compress::Delayed,
+            _ => Compress::None,
+        }
+    }
+
+    /// Start delayed compression, if negotiated.
+    pub fn start_delayed(&mut self) {
+        if let Compress::Delayed = *self {
+            *self = Compress::new(ZLIB)
+        }
+    }
+
+    /// Compress `input` into `output`, and return the payload to be
+    /// sent (`input` itself if compression is not enabled).
+    pub fn compress<'a>(&mut self, input: &'a [u8], output: &'a mut Vec<u8>) -> &'a [u8] {
+        match *self {
+            Compress::Zlib(ref mut z) => {
+                output.clear();
+                output.reserve(input.len() + 64);
+                let total_in = z.total_in();
+                loop {
+                    let consumed = (z.total_in() - total_in) as usize;
+                    // Each packet is flushed, so that the remote side
+                    // can decompress it entirely.
+                    z.compress_vec(&input[consumed..], output, flate2::FlushCompress::Partial)
+                        .unwrap();
+                    let consumed = (z.total_in() - total_in) as usize;
+                    if consumed >= input.len() && output.len() < output.capacity() {
+                        break;
+                    }
+                    let len = output.len();
+                    output.reserve(len);
+                }
+                output
+            }
+            _ => input,
+        }
+    }
+}
+
+impl Decompress {
+    pub fn new(name: Name) -> Decompress {
+        match name {
+            ZLIB => Decompress::Zlib(flate2::Decompress::new(true)),
+            ZLIB_DELAYED => Decompress::Delayed,
+            _ => Decompress::None,
+        }
+    }
+
+    /// Start delayed compression, if negotiated.
+    pub fn start_delayed(&mut self) {
+        if let Decompress::Delayed = *self {
+            *self = Decompress::new(ZLIB)
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        if let Decompress::Zlib(_) = *self {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Decompress `input` into `output`, and return the received
+    /// payload (`input` itself if compression is not enabled).
+    pub fn decompress<'a>(&mut self,
+                          input: &'a [u8],
+                          output: &'a mut Vec<u8>)
+                          -> Result<&'a [u8], Error> {
+        match *self {
+            Decompress::Zlib(ref mut z) => {
+                output.clear();
+                output.reserve(4 * input.len() + 64);
+                let total_in = z.total_in();
+                loop {
+                    let consumed = (z.total_in() - total_in) as usize;
+                    let len = output.len();
+                    try!(z.decompress_vec(&input[consumed..],
+                                          output,
+                                          flate2::FlushDecompress::Sync)
+                        .map_err(|_| Error::Compression));
+                    let new_consumed = (z.total_in() - total_in) as usize;
+                    if new_consumed >= input.len() && output.len() < output.capacity() {
+                        break;
+                    }
+                    if output.len() > MAX_PAYLOAD_LEN ||
+                       (new_consumed == consumed && output.len() == len) {
+                        return Err(Error::Compression);
+                    }
+                    if output.len() == output.capacity() {
+                        let len = output.len();
+                        output.reserve(len);
+                    }
+                }
+                Ok(output)
+            }
+            _ => Ok(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zlib_round_trip() {
+        let mut compress = Compress::new(ZLIB);
+        let mut decompress = Decompress::new(ZLIB);
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+        for i in 0..20 {
+            let packet: Vec<u8> = b"exec output ".iter().cycle().take(1 + 500 * i).cloned().collect();
+            let sent = compress.compress(&packet, &mut compressed).to_vec();
+            if i > 0 {
+                assert!(sent.len() < packet.len());
+            }
+            assert_eq!(decompress.decompress(&sent, &mut decompressed).unwrap(),
+                       &packet[..]);
+        }
+    }
+
+    #[test]
+    fn delayed_compression_starts_when_asked() {
+        let mut compress = Compress::new(ZLIB_DELAYED);
+        let mut decompress = Decompress::new(ZLIB_DELAYED);
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+        let packet = [1; 100];
+        assert_eq!(compress.compress(&packet, &mut compressed), &packet[..]);
+        assert!(!decompress.is_enabled());
+        compress.start_delayed();
+        decompress.start_delayed();
+        assert!(decompress.is_enabled());
+        let sent = compress.compress(&packet, &mut compressed).to_vec();
+        assert!(sent.len() < packet.len());
+        assert_eq!(decompress.decompress(&sent, &mut decompressed).unwrap(),
+                   &packet[..]);
+    }
+
+    #[test]
+    fn starting_delayed_compression_leaves_others_unchanged() {
+        let mut compress = Compress::new(NONE);
+        compress.start_delayed();
+        let mut compressed = Vec::new();
+        assert_eq!(compress.compress(b"abc", &mut compressed), b"abc");
+        let mut decompress = Decompress::new(NONE);
+        decompress.start_delayed();
+        assert!(!decompress.is_enabled());
+    }
+
+    #[test]
+    fn long_payloads_are_rejected() {
+        let mut compress = Compress::new(ZLIB);
+        let mut decompress = Decompress::new(ZLIB);
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+        let packet = vec![0; 2 * MAX_PAYLOAD_LEN];
+        let sent = compress.compress(&packet, &mut compressed).to_vec();
+        match decompress.decompress(&sent, &mut decompressed) {
+            Err(Error::Compression) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn invalid_streams_are_rejected() {
+        let mut decompress = Decompress::new(ZLIB);
+        let mut decompressed = Vec::new();
+        match decompress.decompress(&[0xff; 32], &mut decompressed) {
+            Err(Error::Compression) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+}
@@ -25,17 +25,38 @@ use super::cryptobuf::CryptoBuf;
 use session::Exchange;
 use key;
 use cipher;
+use mac;
+use compression;
+use negociation;
+use std::cell::RefCell;
+use std::sync::Arc;
+use registry::Registry;
+use backend::{self, Backend, Hash, Selected};
+#[cfg(feature = "openssl")]
+use openssl::derive::Deriver;
+#[cfg(feature = "openssl")]
+use openssl::pkey::{Id, PKey, Private};
 
 #[doc(hidden)]
 #[derive(Debug,Clone)]
 pub enum Digest {
     Sha256(sha256::Digest),
+    Sha384(Vec<u8>),
+    Sha512(Vec<u8>),
+    #[cfg(feature = "legacy")]
+    Sha1(Vec<u8>),
+    Custom(Vec<u8>),
 }
 impl std::ops::Deref for Digest {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
         match self {
             &Digest::Sha256(ref d) => d,
+            &Digest::Sha384(ref d) => d,
+            &Digest::Sha512(ref d) => d,
+            #[cfg(feature = "legacy")]
+            &Digest::Sha1(ref d) => d,
+            &Digest::Custom(ref d) => d,
         }
     }
 }
@@ -49,10 +70,72 @@ pub struct Curve25519 {
     shared_secret: Option<curve25519::GroupElement>,
 }
 
+#[doc(hidden)]
+pub struct EcdhNist {
+    curve: backend::Curve,
+    local_secret: Vec<u8>,
+    local_pubkey: Vec<u8>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+impl std::fmt::Debug for EcdhNist {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EcdhNist {{ curve: {:?}, local_secret: (hidden) }}", self.curve)
+    }
+}
+
+#[cfg(feature = "mlkem")]
+#[doc(hidden)]
+pub struct MlKem768X25519 {
+    /// Our ML-KEM decapsulation key, on the client side.
+    mlkem_secret: Option<CryptoBuf>,
+    x25519_secret: curve25519::Scalar,
+    local_pubkey: Vec<u8>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "mlkem")]
+impl std::fmt::Debug for MlKem768X25519 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MlKem768X25519 {{ local_secret: (hidden) }}")
+    }
+}
+
+#[cfg(feature = "openssl")]
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct Curve448 {
+    local_secret: PKey<Private>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "legacy")]
+#[doc(hidden)]
+pub struct Dh {
+    prime: &'static [u8],
+    local_secret: Vec<u8>,
+    shared_secret: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "legacy")]
+impl std::fmt::Debug for Dh {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Dh {{ prime: {:?}, local_secret: (hidden) }}", self.prime)
+    }
+}
+
 #[doc(hidden)]
 #[derive(Debug)]
 pub enum Algorithm {
     Curve25519(Curve25519), // "curve25519-sha256@libssh.org"
+    #[cfg(feature = "mlkem")]
+    MlKem768X25519(MlKem768X25519), // "mlkem768x25519-sha256"
+    EcdhNist(EcdhNist), // "ecdh-sha2-nistp256", "ecdh-sha2-nistp384", "ecdh-sha2-nistp521"
+    #[cfg(feature = "openssl")]
+    Curve448(Curve448), // "curve448-sha512"
+    #[cfg(feature = "legacy")]
+    Dh(Dh), // "diffie-hellman-group1-sha1", "diffie-hellman-group14-sha1"
+    Custom(Box<KexState>),
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -63,6 +146,363 @@ impl AsRef<str> for Name {
     }
 }
 pub const CURVE25519: Name = Name("curve25519-sha256@libssh.org");
+/// Post-quantum hybrid of ML-KEM-768 and X25519, with SHA-256
+/// ([draft-ietf-sshm-mlkem-hybrid-kex](https://datatracker.ietf.org/doc/draft-ietf-sshm-mlkem-hybrid-kex/))
+/// (requires the "mlkem" feature).
+#[cfg(feature = "mlkem")]
+pub const MLKEM768X25519: Name = Name("mlkem768x25519-sha256");
+/// ECDH on the NIST P-256 curve, with SHA-256 ([RFC5656](https://tools.ietf.org/html/rfc5656)).
+pub const ECDH_NISTP256: Name = Name("ecdh-sha2-nistp256");
+/// ECDH on the NIST P-384 curve, with SHA-384.
+pub const ECDH_NISTP384: Name = Name("ecdh-sha2-nistp384");
+/// ECDH on the NIST P-521 curve, with SHA-512.
+pub const ECDH_NISTP521: Name = Name("ecdh-sha2-nistp521");
+/// X448, with SHA-512 ([RFC8731](https://tools.ietf.org/html/rfc8731))
+/// (requires the "openssl" feature).
+#[cfg(feature = "openssl")]
+pub const CURVE448: Name = Name("curve448-sha512");
+/// Diffie-Hellman on the 1024-bit Oakley group 2, with SHA-1
+/// ([RFC4253](https://tools.ietf.org/html/rfc4253#section-8.1)).
+/// **Insecure**, only for compatibility with old equipment (requires
+/// the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const DH_GROUP1_SHA1: Name = Name("diffie-hellman-group1-sha1");
+/// Diffie-Hellman on the 2048-bit Oakley group 14, with SHA-1.
+/// **Insecure**, only for compatibility with old equipment (requires
+/// the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const DH_GROUP14_SHA1: Name = Name("diffie-hellman-group14-sha1");
+
+/// Pseudo-algorithm sent by clients supporting strict key exchange
+/// (OpenSSH's mitigation of CVE-2023-48795).
+pub const STRICT_KEX_CLIENT: Name = Name("kex-strict-c-v00@openssh.com");
+/// Pseudo-algorithm sent by servers supporting strict key exchange.
+pub const STRICT_KEX_SERVER: Name = Name("kex-strict-s-v00@openssh.com");
+
+/// Pseudo-algorithm sent by clients accepting `SSH_MSG_EXT_INFO`
+/// ([RFC8308](https://tools.ietf.org/html/rfc8308)).
+pub const EXT_INFO_C: Name = Name("ext-info-c");
+/// Pseudo-algorithm sent by servers accepting `SSH_MSG_EXT_INFO`.
+pub const EXT_INFO_S: Name = Name("ext-info-s");
+
+/// All the key exchange algorithms implemented by this crate.
+pub const ALL: &'static [Name] = &[#[cfg(feature = "mlkem")]
+                                   MLKEM768X25519,
+                                   CURVE25519,
+                                   #[cfg(feature = "openssl")]
+                                   CURVE448,
+                                   ECDH_NISTP256,
+                                   ECDH_NISTP384,
+                                   ECDH_NISTP521,
+                                   #[cfg(feature = "legacy")]
+                                   DH_GROUP14_SHA1,
+                                   #[cfg(feature = "legacy")]
+                                   DH_GROUP1_SHA1];
+
+// The Oakley groups 2 (RFC2409, section 6.2) and 14 (RFC3526,
+// section 3), with generator 2.
+#[cfg(feature = "legacy")]
+const GROUP1_PRIME: &'static [u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2,
+                                      0x21, 0x68, 0xc2, 0x34, 0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1,
+                                      0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74, 0x02, 0x0b, 0xbe, 0xa6,
+                                      0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+                                      0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d,
+                                      0xf2, 0x5f, 0x14, 0x37, 0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45,
+                                      0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6, 0xf4, 0x4c, 0x42, 0xe9,
+                                      0xa6, 0x37, 0xed, 0x6b, 0x0b, 0xff, 0x5c, 0xb6, 0xf4, 0x06, 0xb7, 0xed,
+                                      0xee, 0x38, 0x6b, 0xfb, 0x5a, 0x89, 0x9f, 0xa5, 0xae, 0x9f, 0x24, 0x11,
+                                      0x7c, 0x4b, 0x1f, 0xe6, 0x49, 0x28, 0x66, 0x51, 0xec, 0xe6, 0x53, 0x81,
+                                      0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+#[cfg(feature = "legacy")]
+const GROUP14_PRIME: &'static [u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2,
+                                       0x21, 0x68, 0xc2, 0x34, 0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1,
+                                       0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74, 0x02, 0x0b, 0xbe, 0xa6,
+                                       0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+                                       0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d,
+                                       0xf2, 0x5f, 0x14, 0x37, 0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45,
+                                       0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6, 0xf4, 0x4c, 0x42, 0xe9,
+                                       0xa6, 0x37, 0xed, 0x6b, 0x0b, 0xff, 0x5c, 0xb6, 0xf4, 0x06, 0xb7, 0xed,
+                                       0xee, 0x38, 0x6b, 0xfb, 0x5a, 0x89, 0x9f, 0xa5, 0xae, 0x9f, 0x24, 0x11,
+                                       0x7c, 0x4b, 0x1f, 0xe6, 0x49, 0x28, 0x66, 0x51, 0xec, 0xe4, 0x5b, 0x3d,
+                                       0xc2, 0x00, 0x7c, 0xb8, 0xa1, 0x63, 0xbf, 0x05, 0x98, 0xda, 0x48, 0x36,
+                                       0x1c, 0x55, 0xd3, 0x9a, 0x69, 0x16, 0x3f, 0xa8, 0xfd, 0x24, 0xcf, 0x5f,
+                                       0x83, 0x65, 0x5d, 0x23, 0xdc, 0xa3, 0xad, 0x96, 0x1c, 0x62, 0xf3, 0x56,
+                                       0x20, 0x85, 0x52, 0xbb, 0x9e, 0xd5, 0x29, 0x07, 0x70, 0x96, 0x96, 0x6d,
+                                       0x67, 0x0c, 0x35, 0x4e, 0x4a, 0xbc, 0x98, 0x04, 0xf1, 0x74, 0x6c, 0x08,
+                                       0xca, 0x18, 0x21, 0x7c, 0x32, 0x90, 0x5e, 0x46, 0x2e, 0x36, 0xce, 0x3b,
+                                       0xe3, 0x9e, 0x77, 0x2c, 0x18, 0x0e, 0x86, 0x03, 0x9b, 0x27, 0x83, 0xa2,
+                                       0xec, 0x07, 0xa2, 0x8f, 0xb5, 0xc5, 0x5d, 0xf0, 0x6f, 0x4c, 0x52, 0xc9,
+                                       0xde, 0x2b, 0xcb, 0xf6, 0x95, 0x58, 0x17, 0x18, 0x39, 0x95, 0x49, 0x7c,
+                                       0xea, 0x95, 0x6a, 0xe5, 0x15, 0xd2, 0x26, 0x18, 0x98, 0xfa, 0x05, 0x10,
+                                       0x15, 0x72, 0x8e, 0x5a, 0x8a, 0xac, 0xaa, 0x68, 0xff, 0xff, 0xff, 0xff,
+                                       0xff, 0xff, 0xff, 0xff];
+
+
+impl std::str::FromStr for Name {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Name, Error> {
+        negociation::parse_name(ALL, s).or_else(|e| {
+            if let Some((name, _)) = REGISTRY.get(s) {
+                Ok(Name(name))
+            } else {
+                Err(e)
+            }
+        })
+    }
+}
+
+/// A key exchange method implemented outside of this crate, see
+/// `register`. The messages are those of ECDH
+/// ([RFC5656](https://tools.ietf.org/html/rfc5656#section-4)): the
+/// client sends its ephemeral public key in `SSH_MSG_KEX_ECDH_INIT`,
+/// and the server replies with its own in `SSH_MSG_KEX_ECDH_REPLY`.
+pub trait KexAlgorithm: Send + Sync {
+    /// Generate an ephemeral key pair, for one key exchange.
+    fn start(&self) -> Result<Box<KexState>, Error>;
+}
+
+/// The state of a key exchange method implemented outside of this
+/// crate, on either side.
+pub trait KexState: Send + std::fmt::Debug {
+    /// Our ephemeral public key.
+    fn local_pubkey(&self) -> &[u8];
+    /// Compute the shared secret from the remote ephemeral public key.
+    fn compute_shared_secret(&mut self, remote_pubkey: &[u8]) -> Result<(), Error>;
+    /// The shared secret, if it has been computed. It is encoded as
+    /// an mpint in the exchange hash and the key derivation.
+    fn shared_secret(&self) -> Option<&[u8]>;
+    /// Hash `data` with the hash function of this method.
+    fn hash(&self, data: &[u8]) -> Vec<u8>;
+}
+
+static REGISTRY: Registry<KexAlgorithm> = Registry::new();
+
+/// Make `algorithm` available to all connections of this process,
+/// under `name` (which should be of the form `name@domain`), and
+/// return the corresponding `Name`, to be added to `Preferred::kex`.
+/// Methods implemented by this crate cannot be overridden.
+pub fn register(name: &'static str, algorithm: Box<KexAlgorithm>) -> Name {
+    REGISTRY.register(name, Arc::from(algorithm));
+    Name(name)
+}
+
+impl EcdhNist {
+    fn new(name: Name) -> Result<EcdhNist, Error> {
+        let curve = match name {
+            ECDH_NISTP256 => backend::Curve::P256,
+            ECDH_NISTP384 => backend::Curve::P384,
+            _ => backend::Curve::P521,
+        };
+        let (secret, public) = try!(Selected::ec_keypair(curve).ok_or(Error::Kex));
+        Ok(EcdhNist {
+            curve: curve,
+            local_secret: secret,
+            local_pubkey: public,
+            shared_secret: None,
+        })
+    }
+
+    /// Our public key, as an uncompressed point.
+    fn local_pubkey(&self) -> &[u8] {
+        &self.local_pubkey
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey: &[u8]) -> Result<(), Error> {
+        // Points that are not on the curve are rejected.
+        let shared = try!(Selected::ecdh(self.curve, &self.local_secret, remote_pubkey)
+            .ok_or(Error::Kex));
+        self.shared_secret = Some(shared);
+        Ok(())
+    }
+}
+
+/// A random X25519 secret key, and the corresponding public key.
+#[cfg(feature = "mlkem")]
+fn curve25519_keypair() -> (curve25519::Scalar, curve25519::GroupElement) {
+    let mut secret = [0; curve25519::SCALARBYTES];
+    randombytes::into(&mut secret);
+    // https://cr.yp.to/ecdh.html
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    let secret = curve25519::Scalar::copy_from_slice(&secret);
+    let mut public = curve25519::GroupElement::new_blank();
+    curve25519::scalarmult_base(&mut public, &secret);
+    (secret, public)
+}
+
+#[cfg(feature = "mlkem")]
+impl MlKem768X25519 {
+    /// The client's state, whose public key is the ML-KEM
+    /// encapsulation key followed by the X25519 public key.
+    fn client() -> Result<MlKem768X25519, Error> {
+        let mut local_pubkey = vec![0; backend::MLKEM768_PUBLICKEYBYTES];
+        let mut dk = CryptoBuf::new();
+        if !Selected::mlkem768_keypair(&mut local_pubkey,
+                                       dk.reserve(backend::MLKEM768_SECRETKEYBYTES)) {
+            return Err(Error::Kex);
+        }
+        let (x25519_secret, x25519_public) = curve25519_keypair();
+        local_pubkey.extend_from_slice(&x25519_public);
+        Ok(MlKem768X25519 {
+            mlkem_secret: Some(dk),
+            x25519_secret: x25519_secret,
+            local_pubkey: local_pubkey,
+            shared_secret: None,
+        })
+    }
+
+    /// The server's state, whose public key is the ML-KEM ciphertext
+    /// followed by the X25519 public key.
+    fn server(client_pubkey: &[u8]) -> Result<MlKem768X25519, Error> {
+        let (ek, remote_x25519) = if client_pubkey.len() ==
+                                     backend::MLKEM768_PUBLICKEYBYTES +
+                                     curve25519::GROUPELEMENTBYTES {
+            client_pubkey.split_at(backend::MLKEM768_PUBLICKEYBYTES)
+        } else {
+            return Err(Error::Kex);
+        };
+        let mut local_pubkey = vec![0; backend::MLKEM768_CIPHERTEXTBYTES];
+        let mut shared = CryptoBuf::new();
+        if !Selected::mlkem768_encapsulate(&mut local_pubkey, shared.reserve(32), ek) {
+            return Err(Error::Kex);
+        }
+        let (x25519_secret, x25519_public) = curve25519_keypair();
+        local_pubkey.extend_from_slice(&x25519_public);
+        let mut kex = MlKem768X25519 {
+            mlkem_secret: None,
+            x25519_secret: x25519_secret,
+            local_pubkey: local_pubkey,
+            shared_secret: None,
+        };
+        try!(kex.combine(&mut shared, remote_x25519));
+        Ok(kex)
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey: &[u8]) -> Result<(), Error> {
+        let (ct, remote_x25519) = if remote_pubkey.len() ==
+                                     backend::MLKEM768_CIPHERTEXTBYTES +
+                                     curve25519::GROUPELEMENTBYTES {
+            remote_pubkey.split_at(backend::MLKEM768_CIPHERTEXTBYTES)
+        } else {
+            return Err(Error::Kex);
+        };
+        let mut shared = CryptoBuf::new();
+        {
+            let dk = try!(self.mlkem_secret.as_ref().ok_or(Error::Kex));
+            if !Selected::mlkem768_decapsulate(shared.reserve(32), ct, dk) {
+                return Err(Error::Kex);
+            }
+        }
+        self.combine(&mut shared, remote_x25519)
+    }
+
+    /// The shared secret is the SHA-256 hash of the ML-KEM shared
+    /// secret, already in `shared`, followed by the X25519 one.
+    fn combine(&mut self, shared: &mut CryptoBuf, remote_x25519: &[u8]) -> Result<(), Error> {
+        let remote_x25519 = curve25519::GroupElement::copy_from_slice(remote_x25519);
+        let mut x25519_shared = curve25519::GroupElement::new_blank();
+        curve25519::scalarmult(&mut x25519_shared, &self.x25519_secret, &remote_x25519);
+        // An all-zero secret means that the remote key is of small
+        // order (RFC7748, section 6.1).
+        if x25519_shared.iter().all(|&x| x == 0) {
+            return Err(Error::Kex);
+        }
+        shared.extend(&x25519_shared);
+        let mut hash = sha256::Digest::new_blank();
+        sha256::hash(&mut hash, &shared);
+        self.shared_secret = Some(hash.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl Curve448 {
+    fn new() -> Result<Curve448, Error> {
+        Ok(Curve448 {
+            local_secret: try!(PKey::generate_x448().map_err(|_| Error::Kex)),
+            shared_secret: None,
+        })
+    }
+
+    fn local_pubkey(&self) -> Result<Vec<u8>, Error> {
+        self.local_secret.raw_public_key().map_err(|_| Error::Kex)
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey: &[u8]) -> Result<(), Error> {
+        if remote_pubkey.len() != 56 {
+            return Err(Error::Kex);
+        }
+        let remote = try!(PKey::public_key_from_raw_bytes(remote_pubkey, Id::X448)
+            .map_err(|_| Error::Kex));
+        let mut deriver = try!(Deriver::new(&self.local_secret).map_err(|_| Error::Kex));
+        try!(deriver.set_peer(&remote).map_err(|_| Error::Kex));
+        let shared = try!(deriver.derive_to_vec().map_err(|_| Error::Kex));
+        // An all-zero secret means that the remote key is of small
+        // order (RFC8731, section 3).
+        if shared.iter().all(|&x| x == 0) {
+            return Err(Error::Kex);
+        }
+        self.shared_secret = Some(shared);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "legacy")]
+impl Dh {
+    fn new(name: Name) -> Dh {
+        let prime = if name == DH_GROUP1_SHA1 {
+            GROUP1_PRIME
+        } else {
+            GROUP14_PRIME
+        };
+        // The secret is drawn from [2, 2^(n-1)), where n is the
+        // number of bits of the prime, which is a subset of [2, p-2].
+        let mut local_secret = vec![0; prime.len()];
+        while compare(&local_secret, &[2]) == std::cmp::Ordering::Less {
+            randombytes::into(&mut local_secret);
+            local_secret[0] &= 0x7f;
+        }
+        Dh {
+            prime: prime,
+            local_secret: local_secret,
+            shared_secret: None,
+        }
+    }
+
+    /// Our public value, encoded as the contents of an mpint.
+    fn local_pubkey(&self) -> Vec<u8> {
+        let mut bytes = Selected::modexp(&[2], &self.local_secret, self.prime);
+        if bytes[0] & 0x80 != 0 {
+            bytes.insert(0, 0)
+        }
+        bytes
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey: &[u8]) -> Result<(), Error> {
+        // The prime is odd.
+        let mut max = self.prime.to_vec();
+        *max.last_mut().unwrap() -= 1;
+        // The remote value must be in [2, p-2] (RFC4253, section 8).
+        if compare(remote_pubkey, &[1]) != std::cmp::Ordering::Greater ||
+           compare(remote_pubkey, &max) != std::cmp::Ordering::Less {
+            return Err(Error::Kex);
+        }
+        self.shared_secret = Some(Selected::modexp(remote_pubkey, &self.local_secret, self.prime));
+        Ok(())
+    }
+}
+
+/// Compare two big-endian unsigned integers.
+#[cfg(feature = "legacy")]
+fn compare(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    fn strip(x: &[u8]) -> &[u8] {
+        let zeros = x.iter().take_while(|&&x| x == 0).count();
+        &x[zeros..]
+    }
+    let (a, b) = (strip(a), strip(b));
+    a.len().cmp(&b.len()).then(a.cmp(b))
+}
 
 impl Algorithm {
     pub fn server_dh(name: Name,
@@ -110,7 +550,52 @@ impl Algorithm {
                     shared_secret: Some(shared_secret),
                 }))
             }
-            _ => unreachable!(),
+            #[cfg(feature = "mlkem")]
+            MLKEM768X25519 if payload[0] == msg::KEX_ECDH_INIT => {
+
+                let kex = try!(MlKem768X25519::server(&exchange.client_ephemeral));
+                exchange.server_ephemeral.clear();
+                exchange.server_ephemeral.extend(&kex.local_pubkey);
+                Ok(Algorithm::MlKem768X25519(kex))
+            }
+            ECDH_NISTP256 | ECDH_NISTP384 | ECDH_NISTP521 if payload[0] == msg::KEX_ECDH_INIT => {
+
+                let mut kex = try!(EcdhNist::new(name));
+                exchange.server_ephemeral.clear();
+                exchange.server_ephemeral.extend(kex.local_pubkey());
+                try!(kex.compute_shared_secret(&exchange.client_ephemeral));
+                Ok(Algorithm::EcdhNist(kex))
+            }
+            #[cfg(feature = "openssl")]
+            CURVE448 if payload[0] == msg::KEX_ECDH_INIT => {
+
+                let mut kex = try!(Curve448::new());
+                let server_pubkey = try!(kex.local_pubkey());
+                exchange.server_ephemeral.clear();
+                exchange.server_ephemeral.extend(&server_pubkey);
+                try!(kex.compute_shared_secret(&exchange.client_ephemeral));
+                Ok(Algorithm::Curve448(kex))
+            }
+            #[cfg(feature = "legacy")]
+            DH_GROUP1_SHA1 | DH_GROUP14_SHA1 if payload[0] == msg::KEXDH_INIT => {
+
+                let mut kex = Dh::new(name);
+                let server_pubkey = kex.local_pubkey();
+                exchange.server_ephemeral.clear();
+                exchange.server_ephemeral.extend(&server_pubkey);
+                try!(kex.compute_shared_secret(&exchange.client_ephemeral));
+                Ok(Algorithm::Dh(kex))
+            }
+            _ if payload[0] == msg::KEX_ECDH_INIT => {
+
+                let (_, algorithm) = try!(REGISTRY.get(name.0).ok_or(Error::Kex));
+                let mut kex = try!(algorithm.start());
+                exchange.server_ephemeral.clear();
+                exchange.server_ephemeral.extend(kex.local_pubkey());
+                try!(kex.compute_shared_secret(&exchange.client_ephemeral));
+                Ok(Algorithm::Custom(kex))
+            }
+            _ => Err(Error::Kex),
         }
     }
     pub fn client_dh(name: Name,
@@ -152,7 +637,74 @@ impl Algorithm {
                     shared_secret: None,
                 }))
             }
-            _ => unreachable!(),
+            #[cfg(feature = "mlkem")]
+            MLKEM768X25519 => {
+
+                let kex = try!(MlKem768X25519::client());
+
+                client_ephemeral.clear();
+                client_ephemeral.extend(&kex.local_pubkey);
+
+                buf.push(msg::KEX_ECDH_INIT);
+                buf.extend_ssh_string(&kex.local_pubkey);
+
+                Ok(Algorithm::MlKem768X25519(kex))
+            }
+            ECDH_NISTP256 | ECDH_NISTP384 | ECDH_NISTP521 => {
+
+                let kex = try!(EcdhNist::new(name));
+
+                client_ephemeral.clear();
+                client_ephemeral.extend(kex.local_pubkey());
+
+                buf.push(msg::KEX_ECDH_INIT);
+                buf.extend_ssh_string(kex.local_pubkey());
+
+                Ok(Algorithm::EcdhNist(kex))
+            }
+            #[cfg(feature = "openssl")]
+            CURVE448 => {
+
+                let kex = try!(Curve448::new());
+                let client_pubkey = try!(kex.local_pubkey());
+
+                client_ephemeral.clear();
+                client_ephemeral.extend(&client_pubkey);
+
+                buf.push(msg::KEX_ECDH_INIT);
+                buf.extend_ssh_string(&client_pubkey);
+
+                Ok(Algorithm::Curve448(kex))
+            }
+            #[cfg(feature = "legacy")]
+            DH_GROUP1_SHA1 | DH_GROUP14_SHA1 => {
+
+                let kex = Dh::new(name);
+                let client_pubkey = kex.local_pubkey();
+
+                client_ephemeral.clear();
+                client_ephemeral.extend(&client_pubkey);
+
+                // e is an mpint, whose encoding is that of a string
+                // with the contents computed by `local_pubkey`.
+                buf.push(msg::KEXDH_INIT);
+                buf.extend_ssh_string(&client_pubkey);
+
+                Ok(Algorithm::Dh(kex))
+            }
+            _ => {
+
+                let (_, algorithm) = try!(REGISTRY.get(name.0).ok_or(Error::Kex));
+                let kex = try!(algorithm.start());
+
+                client_ephemeral.clear();
+                client_ephemeral.extend(kex.local_pubkey());
+
+                buf.push(msg::KEX_ECDH_INIT);
+                buf.extend_ssh_string(kex.local_pubkey());
+
+                Ok(Algorithm::Custom(kex))
+            }
         }
     }
 
@@ -172,8 +724,75 @@ impl Algorithm {
                 kex.shared_secret = Some(shared_secret);
                 Ok(())
             }
+            #[cfg(feature = "mlkem")]
+            &mut Algorithm::MlKem768X25519(ref mut kex) => kex.compute_shared_secret(remote_pubkey),
+            &mut Algorithm::EcdhNist(ref mut kex) => kex.compute_shared_secret(remote_pubkey),
+            #[cfg(feature = "openssl")]
+            &mut Algorithm::Curve448(ref mut kex) => kex.compute_shared_secret(remote_pubkey),
+            #[cfg(feature = "legacy")]
+            &mut Algorithm::Dh(ref mut kex) => kex.compute_shared_secret(remote_pubkey),
+            &mut Algorithm::Custom(ref mut kex) => kex.compute_shared_secret(remote_pubkey),
+        }
+
+    }
+
+    fn shared_secret(&self) -> Option<&[u8]> {
+        match self {
+            &Algorithm::Curve25519(ref kex) => kex.shared_secret.as_ref().map(|s| &s[..]),
+            #[cfg(feature = "mlkem")]
+            &Algorithm::MlKem768X25519(ref kex) => kex.shared_secret.as_ref().map(|s| &s[..]),
+            &Algorithm::EcdhNist(ref kex) => kex.shared_secret.as_ref().map(|s| &s[..]),
+            #[cfg(feature = "openssl")]
+            &Algorithm::Curve448(ref kex) => kex.shared_secret.as_ref().map(|s| &s[..]),
+            #[cfg(feature = "legacy")]
+            &Algorithm::Dh(ref kex) => kex.shared_secret.as_ref().map(|s| &s[..]),
+            &Algorithm::Custom(ref kex) => kex.shared_secret(),
+        }
+    }
+
+    /// Append the shared secret to `buffer`, encoded as an mpint, or
+    /// as a string for the post-quantum hybrid methods.
+    fn push_shared_secret(&self, buffer: &mut CryptoBuf) {
+        if let Some(shared) = self.shared_secret() {
+            match *self {
+                #[cfg(feature = "mlkem")]
+                Algorithm::MlKem768X25519(_) => buffer.extend_ssh_string(shared),
+                _ => buffer.extend_ssh_mpint(shared),
+            }
         }
+    }
 
+    /// Hash `data` with the hash function of this key exchange method.
+    fn hash(&self, data: &[u8]) -> Digest {
+        match self {
+            &Algorithm::Curve25519(_) => {
+                let mut hash = sha256::Digest::new_blank();
+                sha256::hash(&mut hash, data);
+                Digest::Sha256(hash)
+            }
+            #[cfg(feature = "mlkem")]
+            &Algorithm::MlKem768X25519(_) => {
+                let mut hash = sha256::Digest::new_blank();
+                sha256::hash(&mut hash, data);
+                Digest::Sha256(hash)
+            }
+            &Algorithm::EcdhNist(ref kex) => {
+                match kex.curve {
+                    backend::Curve::P256 => {
+                        let mut hash = sha256::Digest::new_blank();
+                        sha256::hash(&mut hash, data);
+                        Digest::Sha256(hash)
+                    }
+                    backend::Curve::P384 => Digest::Sha384(Selected::hash(Hash::Sha384, data)),
+                    backend::Curve::P521 => Digest::Sha512(Selected::hash(Hash::Sha512, data)),
+                }
+            }
+            #[cfg(feature = "openssl")]
+            &Algorithm::Curve448(_) => Digest::Sha512(Selected::hash(Hash::Sha512, data)),
+            #[cfg(feature = "legacy")]
+            &Algorithm::Dh(_) => Digest::Sha1(Selected::hash(Hash::Sha1, data)),
+            &Algorithm::Custom(ref kex) => Digest::Custom(kex.hash(data)),
+        }
     }
 
     pub fn compute_exchange_hash<K: key::PubKey>(&self,
@@ -182,40 +801,29 @@ impl Algorithm {
                                                  buffer: &mut CryptoBuf)
                                                  -> Result<Digest, Error> {
         // Computing the exchange hash, see page 7 of RFC 5656.
-        match self {
-            &Algorithm::Curve25519(ref kex) => {
-
-                debug!("{:?} {:?}",
-                       std::str::from_utf8(&exchange.client_id),
-                       std::str::from_utf8(&exchange.server_id));
-                buffer.clear();
-                buffer.extend_ssh_string(&exchange.client_id);
-                buffer.extend_ssh_string(&exchange.server_id);
-                buffer.extend_ssh_string(&exchange.client_kex_init);
-                buffer.extend_ssh_string(&exchange.server_kex_init);
-
+        debug!("{:?} {:?}",
+               std::str::from_utf8(&exchange.client_id),
+               std::str::from_utf8(&exchange.server_id));
+        buffer.clear();
+        buffer.extend_ssh_string(&exchange.client_id);
+        buffer.extend_ssh_string(&exchange.server_id);
+        buffer.extend_ssh_string(&exchange.client_kex_init);
+        buffer.extend_ssh_string(&exchange.server_kex_init);
 
-                key.push_to(buffer);
-                debug!("client_ephemeral: {:?}",
-                       &exchange.client_ephemeral);
-                debug_assert_eq!(exchange.client_ephemeral.len(), 32);
-                buffer.extend_ssh_string(&exchange.client_ephemeral);
 
-                debug_assert_eq!(exchange.server_ephemeral.len(), 32);
-                buffer.extend_ssh_string(&exchange.server_ephemeral);
+        key.push_to(buffer);
+        debug!("client_ephemeral: {:?}",
+               &exchange.client_ephemeral);
+        buffer.extend_ssh_string(&exchange.client_ephemeral);
+        buffer.extend_ssh_string(&exchange.server_ephemeral);
 
-                if let Some(ref shared) = kex.shared_secret {
-                    buffer.extend_ssh_mpint(&shared);
-                }
-                debug!("buffer len = {:?}", buffer.len());
-                debug!("buffer: {:?}", &buffer);
-                // super::hexdump(buffer);
-                let mut hash = sha256::Digest::new_blank();
-                sha256::hash(&mut hash, &buffer);
-                debug!("hash: {:?}", hash);
-                Ok(Digest::Sha256(hash))
-            }
-        }
+        self.push_shared_secret(buffer);
+        debug!("buffer len = {:?}", buffer.len());
+        debug!("buffer: {:?}", &buffer);
+        // super::hexdump(buffer);
+        let hash = self.hash(&buffer);
+        debug!("hash: {:?}", hash);
+        Ok(hash)
     }
 
 
@@ -224,74 +832,295 @@ impl Algorithm {
                         exchange_hash: &Digest,
                         buffer: &mut CryptoBuf,
                         key: &mut CryptoBuf,
-                        cipher: cipher::Name,
+                        names: &negociation::Names,
                         is_server: bool)
                         -> Result<super::cipher::CipherPair, Error> {
-        match self {
-            &Algorithm::Curve25519(ref kex) => {
+        // https://tools.ietf.org/html/rfc4253#section-7.2
+        let mut compute_key = |c, key: &mut CryptoBuf, len| {
 
-                // https://tools.ietf.org/html/rfc4253#section-7.2
-                let mut compute_key = |c, key: &mut CryptoBuf, len| {
+            buffer.clear();
+            key.clear();
 
-                    buffer.clear();
-                    key.clear();
+            self.push_shared_secret(buffer);
 
-                    if let Some(ref shared) = kex.shared_secret {
-                        buffer.extend_ssh_mpint(&shared);
-                    }
+            buffer.extend(&exchange_hash);
+            buffer.push(c);
+            buffer.extend(&session_id);
+            key.extend(&self.hash(&buffer));
 
-                    buffer.extend(&exchange_hash);
-                    buffer.push(c);
-                    buffer.extend(&session_id);
-                    let mut hash = sha256::Digest::new_blank();
-                    sha256::hash(&mut hash, &buffer);
-                    key.extend(&hash);
-
-                    while key.len() < len {
-                        // extend.
-                        buffer.clear();
-                        if let Some(ref shared) = kex.shared_secret {
-                            buffer.extend_ssh_mpint(&shared);
-                        }
-                        buffer.extend(&exchange_hash);
-                        buffer.extend(key);
-                        let mut hash = sha256::Digest::new_blank();
-                        sha256::hash(&mut hash, &buffer);
-                        key.extend(&hash)
-                    }
-                };
+            while key.len() < len {
+                // extend.
+                buffer.clear();
+                self.push_shared_secret(buffer);
+                buffer.extend(&exchange_hash);
+                buffer.extend(key);
+                let hash = self.hash(&buffer);
+                key.extend(&hash)
+            }
+        };
 
-                match cipher {
-                    super::cipher::CHACHA20POLY1305 => {
-
-                        let client_to_server = {
-                            compute_key(b'C', key, super::cipher::key_size(cipher));
-                            super::cipher::Cipher::Chacha20Poly1305 (
-                                super::cipher::chacha20poly1305::Cipher::init(&key)
-                            )
-                        };
-                        let server_to_client = {
-                            compute_key(b'D', key, super::cipher::key_size(cipher));
-                            super::cipher::Cipher::Chacha20Poly1305 (
-                                super::cipher::chacha20poly1305::Cipher::init(&key)
-                            )
-                        };
-
-                        Ok(if is_server {
-                            super::cipher::CipherPair {
-                                local_to_remote: server_to_client,
-                                remote_to_local: client_to_server,
-                            }
-                        } else {
-                            super::cipher::CipherPair {
-                                local_to_remote: client_to_server,
-                                remote_to_local: server_to_client,
-                            }
-                        })
-                    }
-                    _ => unreachable!(),
-                }
+        let mut iv = CryptoBuf::new();
+        let mut mac_key = CryptoBuf::new();
+        // Compute the keys for one direction, where `c` is
+        // the letters used to derive the IV, the key and the
+        // MAC key.
+        let mut init_cipher = |c: (u8, u8, u8), cipher: cipher::Name, mac: mac::Name| {
+            let (iv_c, key_c, mac_c) = c;
+            let iv_size = super::cipher::iv_size(cipher);
+            let key_size = super::cipher::key_size(cipher);
+            let mac_key_size = if super::cipher::needs_mac(cipher) {
+                mac::key_size(mac)
+            } else {
+                0
+            };
+            if iv_size > 0 {
+                compute_key(iv_c, &mut iv, iv_size);
+            }
+            compute_key(key_c, key, key_size);
+            if mac_key_size > 0 {
+                compute_key(mac_c, &mut mac_key, mac_key_size);
+            }
+            super::cipher::Cipher::init(cipher,
+                                        &key[0..key_size],
+                                        &iv[0..iv_size],
+                                        mac,
+                                        &mac_key[0..mac_key_size])
+        };
+
+        let client_to_server =
+            try!(init_cipher((b'A', b'C', b'E'), names.client_cipher, names.client_mac));
+        let server_to_client =
+            try!(init_cipher((b'B', b'D', b'F'), names.server_cipher, names.server_mac));
+
+        Ok(if is_server {
+            super::cipher::CipherPair {
+                local_to_remote: server_to_client,
+                remote_to_local: client_to_server,
+                compress: RefCell::new(compression::Compress::new(names.server_compression)),
+                decompress: RefCell::new(compression::Decompress::new(names.client_compression)),
+            }
+        } else {
+            super::cipher::CipherPair {
+                local_to_remote: client_to_server,
+                remote_to_local: server_to_client,
+                compress: RefCell::new(compression::Compress::new(names.client_compression)),
+                decompress: RefCell::new(compression::Decompress::new(names.server_compression)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cipher::CipherT;
+    use sshbuffer::SSHBuffer;
+
+    fn names(client_cipher: cipher::Name,
+             server_cipher: cipher::Name,
+             client_mac: mac::Name,
+             server_mac: mac::Name)
+             -> negociation::Names {
+        negociation::Names {
+            kex: CURVE25519,
+            key: key::ED25519,
+            client_cipher: client_cipher,
+            server_cipher: server_cipher,
+            client_mac: client_mac,
+            server_mac: server_mac,
+            client_compression: compression::ZLIB,
+            server_compression: compression::NONE,
+            ignore_guessed: false,
+            guess_correct: false,
+            strict_kex: false,
+            ext_info: false,
+        }
+    }
+
+    /// A curve25519 exchange, run against itself.
+    fn curve25519() -> Algorithm {
+        let mut ephemeral = CryptoBuf::new();
+        let mut buf = CryptoBuf::new();
+        let mut kex = Algorithm::client_dh(CURVE25519, &mut ephemeral, &mut buf).unwrap();
+        let mut public = [0; 32];
+        public.clone_from_slice(&ephemeral[0..32]);
+        kex.compute_shared_secret(&public).unwrap();
+        kex
+    }
+
+    /// Send a packet with `sender` and read it with `receiver`.
+    fn send(sender: &cipher::Cipher, receiver: &cipher::Cipher) {
+        let mut written = SSHBuffer::new();
+        sender.write(b"per-direction", &mut written);
+        let mut stream = &written.buffer[0..written.buffer.len()];
+        let mut read = SSHBuffer::new();
+        assert_eq!(receiver.read(&mut stream, &mut read).unwrap(),
+                   Some(&b"per-direction"[..]));
+    }
+
+    #[test]
+    fn keys_are_computed_per_direction() {
+        let kex = curve25519();
+        let session_id = kex.hash(b"session id");
+        let names = names(cipher::AES128CTR,
+                          cipher::CHACHA20POLY1305,
+                          mac::HMAC_SHA512_ETM,
+                          mac::NONE);
+        let mut buffer = CryptoBuf::new();
+        let mut key = CryptoBuf::new();
+        let client = kex.compute_keys(&session_id, &session_id, &mut buffer, &mut key, &names, false)
+            .unwrap();
+        let server = kex.compute_keys(&session_id, &session_id, &mut buffer, &mut key, &names, true)
+            .unwrap();
+        match (&client.local_to_remote, &client.remote_to_local) {
+            (&cipher::Cipher::Block(_), &cipher::Cipher::Chacha20Poly1305(_)) => {}
+            x => panic!("{:?}", x),
+        }
+        match (&server.local_to_remote, &server.remote_to_local) {
+            (&cipher::Cipher::Chacha20Poly1305(_), &cipher::Cipher::Block(_)) => {}
+            x => panic!("{:?}", x),
+        }
+        assert!(!client.decompress.borrow().is_enabled());
+        assert!(server.decompress.borrow().is_enabled());
+        send(&client.local_to_remote, &server.remote_to_local);
+        send(&server.local_to_remote, &client.remote_to_local);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn dh_secrets_are_in_range() {
+        for &name in &[DH_GROUP1_SHA1, DH_GROUP14_SHA1] {
+            let mut max = Dh::new(name).prime.to_vec();
+            *max.last_mut().unwrap() -= 2;
+            for _ in 0..20 {
+                let dh = Dh::new(name);
+                assert!(compare(&dh.local_secret, &[2]) != std::cmp::Ordering::Less);
+                assert!(compare(&dh.local_secret, &max) != std::cmp::Ordering::Greater);
+            }
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn dh_exchange() {
+        for &name in &[DH_GROUP1_SHA1, DH_GROUP14_SHA1] {
+            let mut a = Dh::new(name);
+            let mut b = Dh::new(name);
+            let a_pub = a.local_pubkey();
+            let b_pub = b.local_pubkey();
+            a.compute_shared_secret(&b_pub).unwrap();
+            b.compute_shared_secret(&a_pub).unwrap();
+            assert!(a.shared_secret.is_some());
+            assert_eq!(a.shared_secret, b.shared_secret);
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn dh_rejects_degenerate_public_values() {
+        let mut dh = Dh::new(DH_GROUP14_SHA1);
+        let mut p_minus_one = dh.prime.to_vec();
+        *p_minus_one.last_mut().unwrap() -= 1;
+        for remote in &[vec![0], vec![1], vec![0, 1], p_minus_one, dh.prime.to_vec()] {
+            match dh.compute_shared_secret(remote) {
+                Err(Error::Kex) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[cfg(all(feature = "legacy", feature = "openssl"))]
+    #[test]
+    fn dh_primes_are_those_of_the_rfcs() {
+        use openssl::bn::BigNum;
+        assert_eq!(GROUP1_PRIME, &BigNum::get_rfc2409_prime_1024().unwrap().to_vec()[..]);
+        assert_eq!(GROUP14_PRIME, &BigNum::get_rfc3526_prime_2048().unwrap().to_vec()[..]);
+    }
+
+    #[test]
+    fn ecdh_exchange() {
+        for &name in &[ECDH_NISTP256, ECDH_NISTP384, ECDH_NISTP521] {
+            let mut a = EcdhNist::new(name).unwrap();
+            let mut b = EcdhNist::new(name).unwrap();
+            let a_pub = a.local_pubkey().to_vec();
+            let b_pub = b.local_pubkey().to_vec();
+            a.compute_shared_secret(&b_pub).unwrap();
+            b.compute_shared_secret(&a_pub).unwrap();
+            assert!(a.shared_secret.is_some());
+            assert_eq!(a.shared_secret, b.shared_secret);
+            // Points that are not on the curve.
+            let mut invalid = a_pub.clone();
+            *invalid.last_mut().unwrap() ^= 1;
+            match b.compute_shared_secret(&invalid) {
+                Err(Error::Kex) => {}
+                x => panic!("{:?}", x),
             }
         }
     }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn curve448_exchange() {
+        let mut a = Curve448::new().unwrap();
+        let mut b = Curve448::new().unwrap();
+        let a_pub = a.local_pubkey().unwrap();
+        let b_pub = b.local_pubkey().unwrap();
+        assert_eq!(a_pub.len(), 56);
+        a.compute_shared_secret(&b_pub).unwrap();
+        b.compute_shared_secret(&a_pub).unwrap();
+        assert!(a.shared_secret.is_some());
+        assert_eq!(a.shared_secret, b.shared_secret);
+        // Wrong lengths, and a point of small order.
+        for invalid in &[&a_pub[1..], &[0; 56][..]] {
+            match b.compute_shared_secret(invalid) {
+                Err(Error::Kex) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn mlkem768x25519_exchange() {
+        let mut client = MlKem768X25519::client().unwrap();
+        let server = MlKem768X25519::server(&client.local_pubkey).unwrap();
+        client.compute_shared_secret(&server.local_pubkey).unwrap();
+        assert!(client.shared_secret.is_some());
+        assert_eq!(client.shared_secret, server.shared_secret);
+        // The shared secret is encoded as a string, even when its
+        // first bit is set.
+        let mut kex = Algorithm::MlKem768X25519(client);
+        if let Algorithm::MlKem768X25519(ref mut kex) = kex {
+            kex.shared_secret.as_mut().unwrap()[0] = 0x80
+        }
+        let mut buffer = CryptoBuf::new();
+        kex.push_shared_secret(&mut buffer);
+        assert_eq!(&buffer[..5], &[0, 0, 0, 32, 0x80]);
+
+        let truncated = &server.local_pubkey[1..];
+        assert!(MlKem768X25519::server(truncated).is_err());
+        match kex.compute_shared_secret(truncated) {
+            Err(Error::Kex) => {}
+            x => panic!("{:?}", x),
+        }
+
+        // X25519 points of small order, on either side.
+        let mut client = MlKem768X25519::client().unwrap();
+        let mut small_order = client.local_pubkey.clone();
+        for x in &mut small_order[backend::MLKEM768_PUBLICKEYBYTES..] {
+            *x = 0
+        }
+        match MlKem768X25519::server(&small_order) {
+            Err(Error::Kex) => {}
+            x => panic!("{:?}", x),
+        }
+        let mut small_order = server.local_pubkey.clone();
+        for x in &mut small_order[backend::MLKEM768_CIPHERTEXTBYTES..] {
+            *x = 0
+        }
+        match client.compute_shared_secret(&small_order) {
+            Err(Error::Kex) => {}
+            x => panic!("{:?}", x),
+        }
+    }
 }
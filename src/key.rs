@@ -14,11 +14,19 @@
 //
 use sodium::{ed25519, sha256};
 use cryptobuf::CryptoBuf;
+use negociation;
 use negociation::Named;
 use Error;
 use encoding::Reader;
 use std;
+use std::sync::Arc;
+use registry::Registry;
 use rustc_serialize::base64::{ToBase64, STANDARD};
+#[cfg(feature = "openssl")]
+use openssl::pkey::{Id, PKey, Private};
+#[cfg(feature = "openssl")]
+use openssl::sign::{Signer, Verifier};
+use backend::{self, Backend, Hash, Selected};
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Name(&'static str);
@@ -28,13 +36,128 @@ impl AsRef<str> for Name {
     }
 }
 pub const ED25519: Name = Name("ssh-ed25519");
+/// Ed448 signatures ([RFC8709](https://tools.ietf.org/html/rfc8709))
+/// (requires the "openssl" feature).
+#[cfg(feature = "openssl")]
+pub const ED448: Name = Name("ssh-ed448");
+/// ECDSA on the NIST P-256 curve, with SHA-256
+/// ([RFC5656](https://tools.ietf.org/html/rfc5656)).
+pub const ECDSA_SHA2_NISTP256: Name = Name("ecdsa-sha2-nistp256");
+/// ECDSA on the NIST P-384 curve, with SHA-384.
+pub const ECDSA_SHA2_NISTP384: Name = Name("ecdsa-sha2-nistp384");
+/// ECDSA on the NIST P-521 curve, with SHA-512.
+pub const ECDSA_SHA2_NISTP521: Name = Name("ecdsa-sha2-nistp521");
+/// RSA with SHA-256 signatures, on "ssh-rsa" keys
+/// ([RFC8332](https://tools.ietf.org/html/rfc8332)).
+pub const RSA_SHA2_256: Name = Name("rsa-sha2-256");
+/// RSA with SHA-512 signatures, on "ssh-rsa" keys.
+pub const RSA_SHA2_512: Name = Name("rsa-sha2-512");
+/// RSA with SHA-1 signatures. **Insecure**, only for compatibility
+/// with old equipment (requires the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const RSA: Name = Name("ssh-rsa");
+/// DSA with SHA-1 signatures. **Insecure**, only for compatibility
+/// with old equipment (requires the "legacy" feature). Only
+/// signature verification is implemented.
+#[cfg(feature = "legacy")]
+pub const DSS: Name = Name("ssh-dss");
+
+/// The key type of RSA keys, whatever the signature algorithm.
+const SSH_RSA: &'static str = "ssh-rsa";
+
+/// All the public key algorithms implemented by this crate.
+pub const ALL: &'static [Name] = &[ED25519,
+                                   #[cfg(feature = "openssl")]
+                                   ED448,
+                                   ECDSA_SHA2_NISTP256,
+                                   ECDSA_SHA2_NISTP384,
+                                   ECDSA_SHA2_NISTP521,
+                                   RSA_SHA2_512,
+                                   RSA_SHA2_256,
+                                   #[cfg(feature = "legacy")]
+                                   RSA,
+                                   #[cfg(feature = "legacy")]
+                                   DSS];
+
+/// The public key algorithms accepted for user authentication by
+/// default, which are those of `ALL` except the insecure ones.
+pub const AUTH_DEFAULT: &'static [Name] = &[ED25519,
+                                            #[cfg(feature = "openssl")]
+                                            ED448,
+                                            ECDSA_SHA2_NISTP256,
+                                            ECDSA_SHA2_NISTP384,
+                                            ECDSA_SHA2_NISTP521,
+                                            RSA_SHA2_512,
+                                            RSA_SHA2_256];
+
+impl std::str::FromStr for Name {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Name, Error> {
+        negociation::parse_name(ALL, s).or_else(|e| {
+            if let Some((name, _)) = REGISTRY.get(s) {
+                Ok(Name(name))
+            } else {
+                Err(e)
+            }
+        })
+    }
+}
+
+/// A public key algorithm implemented outside of this crate, see
+/// `register`.
+pub trait PublicKeyAlgorithm: Send + Sync {
+    /// Check that `sig` is a signature of `buffer` by `public`, where
+    /// `public` is the encoding of the public key after its name.
+    fn verify_detached(&self, public: &[u8], buffer: &[u8], sig: &[u8]) -> bool;
+}
+
+/// A key pair of an algorithm implemented outside of this crate,
+/// used in `Algorithm::Custom`.
+pub trait KeyPair: Send + Sync {
+    /// The name of this algorithm, as returned by `register`.
+    fn name(&self) -> Name;
+    /// The encoding of the public key after its name, for instance
+    /// a list of strings.
+    fn public_key(&self) -> Vec<u8>;
+    /// Sign `data`.
+    fn sign(&self, data: &[u8]) -> Vec<u8>;
+}
+
+static REGISTRY: Registry<PublicKeyAlgorithm> = Registry::new();
+
+/// Make `algorithm` available to all connections of this process,
+/// under `name` (which should be of the form `name@domain`), and
+/// return the corresponding `Name`, to be added to `Preferred::key`.
+/// Algorithms implemented by this crate cannot be overridden.
+pub fn register(name: &'static str, algorithm: Box<PublicKeyAlgorithm>) -> Name {
+    REGISTRY.register(name, Arc::from(algorithm));
+    Name(name)
+}
 
 impl Name {
     /// Base name of the private key file for a key name.
     pub fn identity_file(&self) -> &'static str {
         match *self {
             ED25519 => "id_ed25519",
-            _ => unreachable!()
+            #[cfg(feature = "openssl")]
+            ED448 => "id_ed448",
+            ECDSA_SHA2_NISTP256 | ECDSA_SHA2_NISTP384 | ECDSA_SHA2_NISTP521 => "id_ecdsa",
+            RSA_SHA2_256 | RSA_SHA2_512 => "id_rsa",
+            #[cfg(feature = "legacy")]
+            RSA => "id_rsa",
+            #[cfg(feature = "legacy")]
+            DSS => "id_dsa",
+            _ => "id_custom",
+        }
+    }
+
+    /// Whether only signature verification is implemented for this
+    /// algorithm, which then cannot be used for host keys.
+    pub fn is_verify_only(&self) -> bool {
+        match *self {
+            #[cfg(feature = "legacy")]
+            DSS => true,
+            _ => false,
         }
     }
 }
@@ -48,13 +171,37 @@ pub trait Verify {
 pub enum PublicKey {
     #[doc(hidden)]
     Ed25519(ed25519::PublicKey),
+    /// The 57 bytes of the public key.
+    #[doc(hidden)]
+    #[cfg(feature = "openssl")]
+    Ed448(Vec<u8>),
+    /// The encoded curve identifier and point.
+    #[doc(hidden)]
+    Ecdsa(Name, Vec<u8>),
+    /// The encoded public exponent and modulus.
+    #[doc(hidden)]
+    Rsa(Vec<u8>),
+    /// The encoded p, q, g and y.
+    #[doc(hidden)]
+    #[cfg(feature = "legacy")]
+    Dss(Vec<u8>),
+    /// A key of an algorithm registered with `register`, encoded
+    /// after its name.
+    Custom(Name, Vec<u8>),
 }
 
 impl std::ops::Deref for PublicKey {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
         match *self {
-            PublicKey::Ed25519(ref k) => k
+            PublicKey::Ed25519(ref k) => k,
+            #[cfg(feature = "openssl")]
+            PublicKey::Ed448(ref k) => k,
+            PublicKey::Ecdsa(_, ref k) => k,
+            PublicKey::Rsa(ref k) => k,
+            #[cfg(feature = "legacy")]
+            PublicKey::Dss(ref k) => k,
+            PublicKey::Custom(_, ref k) => k,
         }
     }
 }
@@ -68,12 +215,87 @@ impl PublicKey {
                 try!(p.read_string());
                 Ok(PublicKey::Ed25519(ed25519::PublicKey::copy_from_slice(try!(p.read_string()))))
             }
-            _ => Err(Error::UnknownKey),
+            #[cfg(feature = "openssl")]
+            b"ssh-ed448" => {
+                let mut p = pubkey.reader(0);
+                try!(p.read_string());
+                let public = try!(p.read_string());
+                if public.len() != ED448_PUBLICKEYBYTES {
+                    return Err(Error::CouldNotReadKey);
+                }
+                Ok(PublicKey::Ed448(public.to_vec()))
+            }
+            b"ecdsa-sha2-nistp256" | b"ecdsa-sha2-nistp384" | b"ecdsa-sha2-nistp521" => {
+                let name = try!(std::str::from_utf8(algo)
+                    .map_err(|_| Error::UnknownKey)
+                    .and_then(|algo| negociation::parse_name(ALL, algo)));
+                let (curve, id) = try!(ecdsa_curve(name).ok_or(Error::UnknownKey));
+                let mut p = pubkey.reader(0);
+                try!(p.read_string());
+                let start = p.position;
+                let point = if try!(p.read_string()) == id.as_bytes() {
+                    try!(p.read_string())
+                } else {
+                    return Err(Error::CouldNotReadKey);
+                };
+                // Uncompressed points only.
+                if point.len() != 1 + 2 * curve.scalar_size() || point[0] != 4 {
+                    return Err(Error::CouldNotReadKey);
+                }
+                Ok(PublicKey::Ecdsa(name, pubkey[start..p.position].to_vec()))
+            }
+            b"ssh-rsa" | b"rsa-sha2-256" | b"rsa-sha2-512" => {
+                let mut p = pubkey.reader(0);
+                try!(p.read_string());
+                let start = p.position;
+                // e, n
+                for _ in 0..2 {
+                    try!(p.read_string());
+                }
+                Ok(PublicKey::Rsa(pubkey[start..p.position].to_vec()))
+            }
+            #[cfg(feature = "legacy")]
+            b"ssh-dss" => {
+                let mut p = pubkey.reader(0);
+                try!(p.read_string());
+                let start = p.position;
+                // p, q, g, y
+                for _ in 0..4 {
+                    try!(p.read_string());
+                }
+                Ok(PublicKey::Dss(pubkey[start..p.position].to_vec()))
+            }
+            _ => {
+                let name = try!(std::str::from_utf8(algo).map_err(|_| Error::UnknownKey));
+                let (name, _) = try!(REGISTRY.get(name).ok_or(Error::UnknownKey));
+                let mut p = pubkey.reader(0);
+                try!(p.read_string());
+                Ok(PublicKey::Custom(Name(name), pubkey[p.position..].to_vec()))
+            }
         }
     }
 }
 
 impl PublicKey {
+    /// Check that `sig` is a signature of `buffer` with algorithm
+    /// `algo`, which must be one of the algorithms of this key.
+    #[doc(hidden)]
+    pub fn verify_signature(&self, algo: &[u8], buffer: &[u8], sig: &[u8]) -> bool {
+        match *self {
+            PublicKey::Rsa(ref public) => {
+                let hash = match algo {
+                    b"rsa-sha2-256" => Hash::Sha256,
+                    b"rsa-sha2-512" => Hash::Sha512,
+                    #[cfg(feature = "legacy")]
+                    b"ssh-rsa" => Hash::Sha1,
+                    _ => return false,
+                };
+                verify_rsa(public, hash, buffer, sig).unwrap_or(false)
+            }
+            _ => algo == self.name().as_bytes() && self.verify_detached(buffer, sig),
+        }
+    }
+
     pub fn fingerprint(&self) -> String {
         match self {
             &PublicKey::Ed25519(ref public) => {
@@ -81,6 +303,13 @@ impl PublicKey {
                 sha256::hash(&mut digest, &public);
                 "SHA256: ".to_string() + &digest.to_base64(STANDARD)
             }
+            _ => {
+                let mut blob = CryptoBuf::new();
+                self.push_to(&mut blob);
+                let mut digest = sha256::Digest::new_blank();
+                sha256::hash(&mut digest, &blob[4..]);
+                "SHA256: ".to_string() + &digest.to_base64(STANDARD)
+            }
         }
     }
 }
@@ -91,10 +320,90 @@ impl Verify for PublicKey {
                 let sig = ed25519::Signature::copy_from_slice(sig);
                 ed25519::verify_detached(&sig, buffer, public)
             }
+            #[cfg(feature = "openssl")]
+            &PublicKey::Ed448(ref public) => verify_ed448(public, buffer, sig).unwrap_or(false),
+            &PublicKey::Ecdsa(name, ref public) => {
+                verify_ecdsa(name, public, buffer, sig).unwrap_or(false)
+            }
+            &PublicKey::Rsa(_) => self.verify_signature(SSH_RSA.as_bytes(), buffer, sig),
+            #[cfg(feature = "legacy")]
+            &PublicKey::Dss(ref public) => verify_dss(public, buffer, sig).unwrap_or(false),
+            &PublicKey::Custom(name, ref public) => {
+                if let Some((_, algorithm)) = REGISTRY.get(name.0) {
+                    algorithm.verify_detached(public, buffer, sig)
+                } else {
+                    false
+                }
+            }
         }
     }
 }
 
+/// Size of Ed448 public keys and secret keys.
+#[cfg(feature = "openssl")]
+const ED448_PUBLICKEYBYTES: usize = 57;
+/// Size of Ed448 signatures.
+#[cfg(feature = "openssl")]
+const ED448_SIGNATUREBYTES: usize = 114;
+
+/// Verify a pure Ed448 signature, with an empty context (RFC8709).
+#[cfg(feature = "openssl")]
+fn verify_ed448(public: &[u8], buffer: &[u8], sig: &[u8]) -> Result<bool, Error> {
+    if sig.len() != ED448_SIGNATUREBYTES {
+        return Ok(false);
+    }
+    let key = try!(PKey::public_key_from_raw_bytes(public, Id::ED448)
+        .map_err(|_| Error::CouldNotReadKey));
+    let mut verifier = try!(Verifier::new_without_digest(&key).map_err(|_| Error::CouldNotReadKey));
+    Ok(verifier.verify_oneshot(sig, buffer).unwrap_or(false))
+}
+
+/// The curve of ECDSA algorithm `name`, and its identifier in keys.
+fn ecdsa_curve(name: Name) -> Option<(backend::Curve, &'static str)> {
+    match name {
+        ECDSA_SHA2_NISTP256 => Some((backend::Curve::P256, "nistp256")),
+        ECDSA_SHA2_NISTP384 => Some((backend::Curve::P384, "nistp384")),
+        ECDSA_SHA2_NISTP521 => Some((backend::Curve::P521, "nistp521")),
+        _ => None,
+    }
+}
+
+/// Verify an ECDSA signature, where `sig` is the encoding of r and s
+/// as mpints (RFC5656, section 3.1.2).
+fn verify_ecdsa(name: Name, public: &[u8], buffer: &[u8], sig: &[u8]) -> Result<bool, Error> {
+    let (curve, _) = try!(ecdsa_curve(name).ok_or(Error::UnknownKey));
+    let mut p = public.reader(0);
+    try!(p.read_string());
+    let point = try!(p.read_string());
+    let mut s = sig.reader(0);
+    let r = try!(s.read_string());
+    let s = try!(s.read_string());
+    Ok(Selected::ecdsa_verify(curve, point, buffer, r, s))
+}
+
+/// Verify a PKCS#1 v1.5 signature (RFC4253, section 6.6, and RFC8332).
+fn verify_rsa(public: &[u8], hash: Hash, buffer: &[u8], sig: &[u8]) -> Result<bool, Error> {
+    let mut p = public.reader(0);
+    let e = try!(p.read_string());
+    let n = try!(p.read_string());
+    Ok(Selected::rsa_verify(hash, e, n, buffer, sig))
+}
+
+/// Verify a DSA signature with SHA-1, where `sig` is the
+/// concatenation of r and s, both 160-bit long (RFC4253, section 6.6).
+#[cfg(feature = "legacy")]
+fn verify_dss(public: &[u8], buffer: &[u8], sig: &[u8]) -> Result<bool, Error> {
+    if sig.len() != 40 {
+        return Ok(false);
+    }
+    let mut p = public.reader(0);
+    let p_ = try!(p.read_string());
+    let q = try!(p.read_string());
+    let g = try!(p.read_string());
+    let y = try!(p.read_string());
+    Ok(Selected::dsa_verify(p_, q, g, y, buffer, &sig[0..20], &sig[20..]))
+}
+
 #[derive(Clone)]
 pub enum Algorithm {
     #[doc(hidden)]
@@ -102,6 +411,28 @@ pub enum Algorithm {
         public: ed25519::PublicKey,
         secret: ed25519::SecretKey,
     },
+    #[doc(hidden)]
+    #[cfg(feature = "openssl")]
+    Ed448 {
+        public: Vec<u8>,
+        secret: PKey<Private>,
+    },
+    #[doc(hidden)]
+    Ecdsa {
+        name: Name,
+        /// The encoded curve identifier and point.
+        public: Vec<u8>,
+        /// The secret scalar.
+        secret: Vec<u8>,
+    },
+    #[doc(hidden)]
+    Rsa {
+        /// The encoded public exponent and modulus.
+        public: Vec<u8>,
+        secret: backend::RsaSecretKey,
+    },
+    /// A key pair of an algorithm implemented outside of this crate.
+    Custom(Arc<KeyPair>),
 }
 
 impl std::fmt::Debug for Algorithm {
@@ -110,6 +441,20 @@ impl std::fmt::Debug for Algorithm {
             Algorithm::Ed25519 { ref public, .. } => {
                 write!(f, "Ed25519 {{ public: {:?}, secret: (hidden) }}", public)
             }
+            #[cfg(feature = "openssl")]
+            Algorithm::Ed448 { ref public, .. } => {
+                write!(f, "Ed448 {{ public: {:?}, secret: (hidden) }}", public)
+            }
+            Algorithm::Ecdsa { name, ref public, .. } => {
+                write!(f,
+                       "Ecdsa {{ name: {:?}, public: {:?}, secret: (hidden) }}",
+                       name,
+                       public)
+            }
+            Algorithm::Rsa { ref public, .. } => {
+                write!(f, "Rsa {{ public: {:?}, secret: (hidden) }}", public)
+            }
+            Algorithm::Custom(ref pair) => write!(f, "Custom {{ name: {:?} }}", pair.name()),
         }
     }
 }
@@ -127,6 +472,19 @@ impl PubKey for PublicKey {
                 buffer.extend_ssh_string(ED25519.0.as_bytes());
                 buffer.extend_ssh_string(public);
             }
+            #[cfg(feature = "openssl")]
+            &PublicKey::Ed448(ref public) => {
+
+                buffer.push_u32_be((ED448.0.len() + ED448_PUBLICKEYBYTES + 8) as u32);
+                buffer.extend_ssh_string(ED448.0.as_bytes());
+                buffer.extend_ssh_string(public);
+            }
+            _ => {
+                let name = self.name();
+                buffer.push_u32_be((name.len() + self.len() + 4) as u32);
+                buffer.extend_ssh_string(name.as_bytes());
+                buffer.extend(&self[..]);
+            }
         }
     }
 }
@@ -140,6 +498,11 @@ impl PubKey for Algorithm {
                 buffer.extend_ssh_string(ED25519.0.as_bytes());
                 buffer.extend_ssh_string(public);
             }
+            #[cfg(feature = "openssl")]
+            &Algorithm::Ed448 { .. } => self.clone_public_key().push_to(buffer),
+            &Algorithm::Ecdsa { .. } | &Algorithm::Rsa { .. } | &Algorithm::Custom(_) => {
+                self.clone_public_key().push_to(buffer)
+            }
         }
     }
 }
@@ -149,6 +512,13 @@ impl Named for PublicKey {
     fn name(&self) -> &'static str {
         match self {
             &PublicKey::Ed25519(_) => ED25519.0,
+            #[cfg(feature = "openssl")]
+            &PublicKey::Ed448(_) => ED448.0,
+            &PublicKey::Ecdsa(name, _) => name.0,
+            &PublicKey::Rsa(_) => SSH_RSA,
+            #[cfg(feature = "legacy")]
+            &PublicKey::Dss(_) => DSS.0,
+            &PublicKey::Custom(name, _) => name.0,
         }
     }
 }
@@ -157,6 +527,11 @@ impl Named for Algorithm {
     fn name(&self) -> &'static str {
         match self {
             &Algorithm::Ed25519 { .. } => ED25519.0,
+            #[cfg(feature = "openssl")]
+            &Algorithm::Ed448 { .. } => ED448.0,
+            &Algorithm::Ecdsa { name, .. } => name.0,
+            &Algorithm::Rsa { .. } => SSH_RSA,
+            &Algorithm::Custom(ref pair) => pair.name().0,
         }
     }
 }
@@ -166,12 +541,33 @@ impl Algorithm {
     pub fn clone_public_key(&self) -> PublicKey {
         match self {
             &Algorithm::Ed25519 { ref public, .. } => PublicKey::Ed25519(public.clone()),
+            #[cfg(feature = "openssl")]
+            &Algorithm::Ed448 { ref public, .. } => PublicKey::Ed448(public.clone()),
+            &Algorithm::Ecdsa { name, ref public, .. } => PublicKey::Ecdsa(name, public.clone()),
+            &Algorithm::Rsa { ref public, .. } => PublicKey::Rsa(public.clone()),
+            &Algorithm::Custom(ref pair) => PublicKey::Custom(pair.name(), pair.public_key()),
         }
     }
 
-    /// Generate a key pair.
+    /// Generate a key pair. RSA keys are 3072-bit long.
     pub fn generate_keypair(t: Name) -> Option<Self> {
         match t {
+            ECDSA_SHA2_NISTP256 | ECDSA_SHA2_NISTP384 | ECDSA_SHA2_NISTP521 => {
+                let (curve, id) = ecdsa_curve(t).unwrap();
+                Selected::ec_keypair(curve).map(|(secret, point)| {
+                    let mut public = CryptoBuf::new();
+                    public.extend_ssh_string(id.as_bytes());
+                    public.extend_ssh_string(&point);
+                    Algorithm::Ecdsa {
+                        name: t,
+                        public: public.to_vec(),
+                        secret: secret,
+                    }
+                })
+            }
+            RSA_SHA2_256 | RSA_SHA2_512 => Selected::rsa_keypair(3072).map(Algorithm::rsa),
+            #[cfg(feature = "legacy")]
+            RSA => Selected::rsa_keypair(3072).map(Algorithm::rsa),
             ED25519 => {
                 if let Some((pk, sk)) = super::sodium::ed25519::generate_keypair() {
                     Some(Algorithm::Ed25519 {
@@ -182,12 +578,98 @@ impl Algorithm {
                     None
                 }
             }
+            #[cfg(feature = "openssl")]
+            ED448 => {
+                let secret = match PKey::generate_ed448() {
+                    Ok(secret) => secret,
+                    Err(_) => return None,
+                };
+                let public = match secret.raw_public_key() {
+                    Ok(public) => public,
+                    Err(_) => return None,
+                };
+                Some(Algorithm::Ed448 {
+                    public: public,
+                    secret: secret,
+                })
+            }
             _ => None,
         }
     }
 
+    /// Ed448 key pair from the raw secret key, which must match
+    /// the raw public key `public`.
+    #[doc(hidden)]
+    #[cfg(feature = "openssl")]
+    pub fn ed448_from_bytes(secret: &[u8], public: &[u8]) -> Result<Self, Error> {
+        let secret = try!(PKey::private_key_from_raw_bytes(secret, Id::ED448)
+            .map_err(|_| Error::CouldNotReadKey));
+        let computed = try!(secret.raw_public_key().map_err(|_| Error::CouldNotReadKey));
+        if &computed[..] != public {
+            return Err(Error::CouldNotReadKey);
+        }
+        Ok(Algorithm::Ed448 {
+            public: computed,
+            secret: secret,
+        })
+    }
+
+    /// RSA key pair from its secret key.
+    #[doc(hidden)]
+    pub fn rsa(secret: backend::RsaSecretKey) -> Self {
+        let mut public = CryptoBuf::new();
+        public.extend_ssh_mpint(&secret.e);
+        public.extend_ssh_mpint(&secret.n);
+        Algorithm::Rsa {
+            public: public.to_vec(),
+            secret: secret,
+        }
+    }
+
+    /// The signature algorithm to use with this key for user
+    /// authentication, given the algorithms accepted by the server
+    /// (from `server-sig-algs`), if known. For RSA keys, this is the
+    /// first of rsa-sha2-512 and rsa-sha2-256 (and ssh-rsa with the
+    /// "legacy" feature) accepted by the server. Servers that don't
+    /// send `server-sig-algs` are assumed to be old ones, accepting
+    /// only ssh-rsa with the "legacy" feature, and rsa-sha2-512
+    /// otherwise.
+    pub fn signature_algorithm(&self, server_sig_algs: Option<&[String]>) -> Name {
+        match *self {
+            Algorithm::Rsa { .. } => {
+                let algorithms = &[RSA_SHA2_512,
+                                   RSA_SHA2_256,
+                                   #[cfg(feature = "legacy")]
+                                   RSA];
+                match server_sig_algs {
+                    Some(accepted) => {
+                        algorithms.iter()
+                            .find(|a| accepted.iter().any(|b| b == a.0))
+                            .cloned()
+                            .unwrap_or(RSA_SHA2_512)
+                    }
+                    #[cfg(feature = "legacy")]
+                    None => RSA,
+                    #[cfg(not(feature = "legacy"))]
+                    None => RSA_SHA2_512,
+                }
+            }
+            _ => Name(self.name()),
+        }
+    }
+
+    /// Whether this key can make signatures with algorithm `algo`.
+    #[doc(hidden)]
+    pub fn can_sign(&self, algo: Name) -> bool {
+        match *self {
+            Algorithm::Rsa { .. } => rsa_hash(algo).is_some(),
+            _ => self.name() == algo.0,
+        }
+    }
+
+    /// Sign `hash` with algorithm `algo` (see `can_sign`).
     #[doc(hidden)]
-    pub fn add_signature(&self, buffer: &mut CryptoBuf, hash: &[u8]) {
+    pub fn add_signature(&self, algo: Name, buffer: &mut CryptoBuf, hash: &[u8]) {
         match self {
             &Algorithm::Ed25519 { ref secret, .. } => {
 
@@ -198,11 +680,20 @@ impl Algorithm {
                 buffer.extend_ssh_string(ED25519.0.as_bytes());
                 buffer.extend_ssh_string(&sign);
             }
+            #[cfg(feature = "openssl")]
+            &Algorithm::Ed448 { ref secret, .. } => push_ed448_signature(secret, buffer, hash),
+            &Algorithm::Ecdsa { name, ref secret, .. } => {
+                push_ecdsa_signature(name, secret, buffer, hash)
+            }
+            &Algorithm::Rsa { ref secret, .. } => push_rsa_signature(secret, algo, buffer, hash),
+            &Algorithm::Custom(ref pair) => push_custom_signature(&**pair, buffer, hash),
         }
     }
 
+    /// Sign the contents of `buffer` with algorithm `algo`, and append
+    /// the signature to it.
     #[doc(hidden)]
-    pub fn add_self_signature(&self, buffer: &mut CryptoBuf) {
+    pub fn add_self_signature(&self, algo: Name, buffer: &mut CryptoBuf) {
         match self {
             &Algorithm::Ed25519 { ref secret, .. } => {
 
@@ -213,6 +704,123 @@ impl Algorithm {
                 buffer.extend_ssh_string(ED25519.0.as_bytes());
                 buffer.extend_ssh_string(&sign);
             }
+            #[cfg(feature = "openssl")]
+            &Algorithm::Ed448 { ref secret, .. } => {
+                let data = buffer.to_vec();
+                push_ed448_signature(secret, buffer, &data)
+            }
+            &Algorithm::Ecdsa { name, ref secret, .. } => {
+                let data = buffer.to_vec();
+                push_ecdsa_signature(name, secret, buffer, &data)
+            }
+            &Algorithm::Rsa { ref secret, .. } => {
+                let data = buffer.to_vec();
+                push_rsa_signature(secret, algo, buffer, &data)
+            }
+            &Algorithm::Custom(ref pair) => {
+                let data = buffer.to_vec();
+                push_custom_signature(&**pair, buffer, &data)
+            }
         }
     }
 }
+
+#[cfg(feature = "openssl")]
+fn push_ed448_signature(secret: &PKey<Private>, buffer: &mut CryptoBuf, data: &[u8]) {
+    let sign = Signer::new_without_digest(secret)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+        .unwrap();
+    buffer.push_u32_be((ED448.0.len() + sign.len() + 8) as u32);
+    buffer.extend_ssh_string(ED448.0.as_bytes());
+    buffer.extend_ssh_string(&sign);
+}
+
+fn push_ecdsa_signature(name: Name, secret: &[u8], buffer: &mut CryptoBuf, data: &[u8]) {
+    let (curve, _) = ecdsa_curve(name).unwrap();
+    let (r, s) = Selected::ecdsa_sign(curve, secret, data).unwrap();
+    let mut sign = CryptoBuf::new();
+    sign.extend_ssh_mpint(&r);
+    sign.extend_ssh_mpint(&s);
+    buffer.push_u32_be((name.0.len() + sign.len() + 8) as u32);
+    buffer.extend_ssh_string(name.0.as_bytes());
+    buffer.extend_ssh_string(&sign);
+}
+
+/// The hash function of RSA signature algorithm `algo`.
+fn rsa_hash(algo: Name) -> Option<Hash> {
+    match algo {
+        RSA_SHA2_256 => Some(Hash::Sha256),
+        RSA_SHA2_512 => Some(Hash::Sha512),
+        #[cfg(feature = "legacy")]
+        RSA => Some(Hash::Sha1),
+        _ => None,
+    }
+}
+
+fn push_rsa_signature(secret: &backend::RsaSecretKey,
+                      algo: Name,
+                      buffer: &mut CryptoBuf,
+                      data: &[u8]) {
+    let hash = rsa_hash(algo).unwrap_or(Hash::Sha512);
+    let algo = if rsa_hash(algo).is_some() {
+        algo
+    } else {
+        RSA_SHA2_512
+    };
+    let sign = Selected::rsa_sign(hash, secret, data).unwrap();
+    buffer.push_u32_be((algo.0.len() + sign.len() + 8) as u32);
+    buffer.extend_ssh_string(algo.0.as_bytes());
+    buffer.extend_ssh_string(&sign);
+}
+
+fn push_custom_signature(pair: &KeyPair, buffer: &mut CryptoBuf, data: &[u8]) {
+    let name = pair.name().0;
+    let sign = pair.sign(data);
+    buffer.push_u32_be((name.len() + sign.len() + 8) as u32);
+    buffer.extend_ssh_string(name.as_bytes());
+    buffer.extend_ssh_string(&sign);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    /// Test "Blank" of RFC8032, section 7.4.
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn ed448_signatures_follow_rfc8032() {
+        let secret = hex("6c82a562cb808d10d632be89c8513ebf6c929f34ddfa8c9f63c9960ef6e348a3528c8a3fcc2f0\
+                          44e39a3fc5b94492f8f032e7549a20098f95b");
+        let public = hex("5fd7449b59b461fd2ce787ec616ad46a1da1342485a70e1f8a0ea75d80e96778edf124769b46c\
+                          7061bd6783df1e50f6cd1fa1abeafe8256180");
+        let sig = hex("533a37f6bbe457251f023c0d88f976ae2dfb504a843e34d2074fd823d41a591f2b233f034f6282\
+                       81f2fd7a22ddd47d7828c59bd0a21bfd3980ff0d2028d4b18a9df63e006c5d1c2d345b925d8dc0\
+                       0b4104852db99ac5c7cdda8530a113a0f4dbb61149f05a7363268c71d95808ff2e652600");
+        assert!(Algorithm::ed448_from_bytes(&secret, &public[1..]).is_err());
+        let pair = Algorithm::ed448_from_bytes(&secret, &public).unwrap();
+        let mut buffer = CryptoBuf::new();
+        pair.add_self_signature(ED448, &mut buffer);
+        let signed = buffer.to_vec();
+        assert_eq!(&signed[signed.len() - ED448_SIGNATUREBYTES..], &sig[..]);
+
+        let mut blob = CryptoBuf::new();
+        blob.extend_ssh_string(b"ssh-ed448");
+        blob.extend_ssh_string(&public);
+        let key = PublicKey::parse(b"ssh-ed448", &blob).unwrap();
+        assert_eq!(key, pair.clone_public_key());
+        assert!(key.verify_detached(b"", &sig));
+        assert!(!key.verify_detached(b"x", &sig));
+        let mut bad = sig.clone();
+        bad[0] ^= 1;
+        assert!(!key.verify_detached(b"", &bad));
+
+        let mut blob = CryptoBuf::new();
+        blob.extend_ssh_string(b"ssh-ed448");
+        blob.extend_ssh_string(&public[1..]);
+        assert!(PublicKey::parse(b"ssh-ed448", &blob).is_err());
+    }
+}
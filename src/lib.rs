@@ -22,7 +22,7 @@
 //!
 //! ```
 //! use std::sync::Arc;
-//! use thrussh::{key, server, client, CryptoBuf, Error};
+//! use thrussh::{auth, key, server, client, CryptoBuf, Error};
 //! let client_keypair = key::Algorithm::generate_keypair(key::ED25519).unwrap();
 //! let server_keypair = key::Algorithm::generate_keypair(key::ED25519).unwrap();
 //!
@@ -32,8 +32,12 @@
 //!     client_pubkey: key::PublicKey
 //! }
 //! impl server::Handler for S {
-//!     fn auth_publickey(&mut self, user:&str, publickey:&key::PublicKey) -> bool {
-//!         user == "pe" && publickey == &self.client_pubkey
+//!     fn auth_publickey(&mut self, user:&str, publickey:&key::PublicKey) -> auth::Auth {
+//!         if user == "pe" && publickey == &self.client_pubkey {
+//!             auth::Auth::Accept
+//!         } else {
+//!             auth::Auth::Reject
+//!         }
 //!     }
 //! }
 //!
@@ -44,13 +48,17 @@
 //!     channel_confirmed: Option<u32>
 //! }
 //! impl client::Handler for C {
-//!     fn check_server_key(&mut self, server_pk:&key::PublicKey) -> Result<bool, Error> {
+//!     fn check_server_key(&mut self, server_pk:&key::PublicKey) -> Result<client::KeyCheck, Error> {
 //!
 //!         // This is an important part of the protocol: check the
 //!         // server's public key against the known one, to help prevent
 //!         // man-in-the-middle attacks.
 //!
-//!         Ok(&self.server_pk == server_pk)
+//!         if &self.server_pk == server_pk {
+//!             Ok(client::KeyCheck::Accept)
+//!         } else {
+//!             Ok(client::KeyCheck::Reject)
+//!         }
 //!     }
 //!     fn channel_open_confirmation(&mut self, channel:u32, _:&mut client::Session) -> Result<(), Error> {
 //!         self.channel_confirmed = Some(channel);
@@ -128,10 +136,78 @@
 //! }
 //!
 //! ```
+//!
+//! # Legacy algorithms
+//!
+//! The `legacy` cargo feature adds support for the
+//! `diffie-hellman-group1-sha1` and `diffie-hellman-group14-sha1` key
+//! exchanges, the `aes128-cbc` and `3des-cbc` ciphers, the `hmac-sha1`
+//! MAC, `ssh-rsa` signatures (RSA with SHA-1), and the verification
+//! of `ssh-dss` signatures. RSA keys themselves are supported without
+//! this feature, with the `rsa-sha2-256` and `rsa-sha2-512` signature
+//! algorithms.
+//!
+//! **These algorithms are insecure**, and only meant to talk to old
+//! network equipment that supports nothing else. Even with this
+//! feature, they are never part of the default `Preferred`, and must
+//! be enabled explicitly, for instance with `LEGACY` or
+//! `Preferred::set_cipher("+aes128-cbc")`.
+//!
+//! # Cryptographic backends
+//!
+//! ChaCha20-Poly1305, SHA-256, Curve25519 and Ed25519 come from
+//! libsodium by default, and the other primitives from OpenSSL.
+//! Building with `default-features = false, features = ["pure-rust"]`
+//! uses pure-Rust implementations instead, and removes the dependency
+//! on both C libraries, at the cost of Ed448 and curve448. See the
+//! `backend` module. The `mlkem768x25519-sha256` key exchange
+//! requires the "mlkem" feature, and OpenSSL 3.5 or later, with
+//! both backends.
 
 
 extern crate libc;
+#[cfg(feature = "libsodium")]
 extern crate libsodium_sys;
+#[cfg(feature = "pure-rust")]
+extern crate chacha20;
+#[cfg(feature = "pure-rust")]
+extern crate poly1305;
+#[cfg(feature = "pure-rust")]
+extern crate sha2;
+#[cfg(feature = "pure-rust")]
+extern crate x25519_dalek;
+#[cfg(feature = "pure-rust")]
+extern crate ed25519_dalek;
+#[cfg(feature = "pure-rust")]
+extern crate getrandom;
+#[cfg(feature = "pure-rust")]
+extern crate sha1;
+#[cfg(feature = "pure-rust")]
+extern crate hmac;
+#[cfg(feature = "pure-rust")]
+extern crate aes;
+#[cfg(feature = "pure-rust")]
+extern crate ctr;
+#[cfg(feature = "pure-rust")]
+extern crate cbc;
+#[cfg(feature = "pure-rust")]
+extern crate des;
+#[cfg(feature = "pure-rust")]
+extern crate aes_gcm;
+#[cfg(feature = "pure-rust")]
+extern crate p256;
+#[cfg(feature = "pure-rust")]
+extern crate p384;
+#[cfg(feature = "pure-rust")]
+extern crate p521;
+#[cfg(feature = "pure-rust")]
+extern crate rsa;
+#[cfg(feature = "pure-rust")]
+extern crate dsa;
+#[cfg(feature = "openssl")]
+extern crate openssl;
+#[cfg(feature = "mlkem")]
+extern crate openssl_sys;
 extern crate rand;
 
 #[macro_use]
@@ -140,23 +216,29 @@ extern crate bitflags;
 #[macro_use]
 extern crate log;
 extern crate byteorder;
+extern crate flate2;
 
 extern crate rustc_serialize; // config: read base 64.
 
 
 use std::sync::{Once, ONCE_INIT};
 use std::io::{Read, BufRead, BufReader};
-use byteorder::{BigEndian, WriteBytesExt, ByteOrder};
+use byteorder::ByteOrder;
 use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
 use std::path::Path;
 use std::fs::File;
-use std::ops::Deref;
 
+#[cfg(not(any(feature = "libsodium", feature = "pure-rust")))]
+compile_error!("thrussh needs a cryptographic backend: enable feature \"libsodium\" or \"pure-rust\"");
+
+pub mod backend;
 mod sodium;
 mod cryptobuf;
 pub use cryptobuf::CryptoBuf;
 
 mod sshbuffer;
+pub use sshbuffer::SSHBuffer;
+mod registry;
 
 static SODIUM_INIT: Once = ONCE_INIT;
 
@@ -199,12 +281,20 @@ pub enum Error {
     NoHomeDir,
     KeyChanged,
     HUP,
+    StrictKex,
+    Compression,
+    UnknownAlgorithm(String),
+    NoAlgorithm,
+    WrongResponses,
 }
 
 use std::error::Error as StdError;
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.description())
+        match *self {
+            Error::UnknownAlgorithm(ref name) => write!(f, "Unknown algorithm: {:?}", name),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 impl std::error::Error for Error {
@@ -229,6 +319,11 @@ impl std::error::Error for Error {
             Error::NoHomeDir => "Home directory not found",
             Error::KeyChanged => "Server key changed",
             Error::HUP => "Connection closed by the remote side",
+            Error::StrictKex => "Unexpected packet during strict key exchange",
+            Error::Compression => "Invalid compressed packet",
+            Error::UnknownAlgorithm(_) => "Unknown algorithm",
+            Error::NoAlgorithm => "Empty list of algorithms",
+            Error::WrongResponses => "Wrong number of keyboard-interactive responses",
         }
     }
     fn cause(&self) -> Option<&std::error::Error> {
@@ -258,24 +353,28 @@ impl From<rustc_serialize::base64::FromBase64Error> for Error {
 
 mod negociation;
 use negociation::Named;
-pub use negociation::Preferred;
+pub use negociation::{Preferred, ExtInfo, MODERN, FIPS, LEGACY};
 mod pty;
 pub use pty::Pty;
 mod msg;
 /// Key generation and use.
 pub mod key;
+use key::PubKey;
 pub mod kex;
 
 pub mod cipher;
 
-// mod mac;
-// use mac::*;
-// mod compression;
+/// Message authentication codes, used with ciphers that don't
+/// authenticate packets themselves.
+pub mod mac;
+/// Compression of packet payloads.
+pub mod compression;
 
 mod encoding;
 use encoding::*;
 
-mod auth;
+/// Authentication methods.
+pub mod auth;
 
 /// The number of bytes read/written, and the number of seconds before a key re-exchange is requested.
 #[derive(Debug,Clone)]
@@ -299,6 +398,8 @@ impl Default for Limits {
 
 pub mod server;
 pub mod client;
+#[cfg(test)]
+mod testing;
 
 
 /// A reason for disconnection.
@@ -397,6 +498,25 @@ impl ChannelOpenFailure {
     }
 }
 
+/// A custom service, run directly over the transport layer instead
+/// of the standard "ssh-connection" service and its channels. See
+/// `server::Config::services` and `client::Config::service`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// The name of the service, which should be of the form
+    /// "name@domain"
+    /// ([RFC4251](https://tools.ietf.org/html/rfc4251#section-6)).
+    pub name: String,
+    /// Whether the client must authenticate first. If `true`, the
+    /// service is requested by the authentication requests
+    /// ([RFC4252](https://tools.ietf.org/html/rfc4252#section-5)),
+    /// instead of "ssh-connection". Else, it is requested right after
+    /// the key exchange
+    /// ([RFC4253](https://tools.ietf.org/html/rfc4253#section-10)),
+    /// instead of "ssh-userauth".
+    pub authenticated: bool,
+}
+
 /// The parameters of a channel.
 #[derive(Debug)]
 #[doc(hidden)]
@@ -413,8 +533,10 @@ pub struct Channel {
 }
 
 const KEYTYPE_ED25519: &'static [u8] = b"ssh-ed25519";
+#[cfg(feature = "openssl")]
+const KEYTYPE_ED448: &'static [u8] = b"ssh-ed448";
 
-/// Load a public key from a file. Only ed25519 keys are currently supported.
+/// Load a public key from a file.
 pub fn load_public_key<P: AsRef<Path>>(p: P) -> Result<key::PublicKey, Error> {
 
     let mut pubkey = String::new();
@@ -443,28 +565,21 @@ pub fn parse_public_key_base64(key: &str) -> Result<key::PublicKey, Error> {
 
 pub fn parse_public_key(p: &[u8]) -> Result<key::PublicKey, Error> {
     let mut pos = p.reader(0);
-    if try!(pos.read_string()) == b"ssh-ed25519" {
-        if let Ok(pubkey) = pos.read_string() {
-            return Ok(key::PublicKey::Ed25519(sodium::ed25519::PublicKey::copy_from_slice(pubkey)));
-        }
-    }
-    Err(Error::CouldNotReadKey)
+    let algo = try!(pos.read_string());
+    key::PublicKey::parse(algo, p).map_err(|_| Error::CouldNotReadKey)
 }
 
 pub fn write_public_key_base64<W:std::io::Write>(mut w:W, publickey:&key::PublicKey) -> Result<(), Error> {
     try!(w.write_all(publickey.name().as_bytes()));
     try!(w.write_all(b" "));
-    let mut s = Vec::new();
-    let name = publickey.name().as_bytes();
-    s.write_u32::<BigEndian>(name.len() as u32);
-    s.extend(name);
-    try!(s.write_u32::<BigEndian>(publickey.len() as u32));
-    s.extend(publickey.deref());
-    try!(w.write_all(s.to_base64(STANDARD).as_bytes()));
+    let mut s = CryptoBuf::new();
+    publickey.push_to(&mut s);
+    try!(w.write_all(s[4..].to_base64(STANDARD).as_bytes()));
     Ok(())
 }
 
-/// Load a secret key from a file. Only ed25519 keys are currently supported.
+/// Load a secret key from a file. Only unencrypted ed25519 and ed448
+/// keys are currently supported (ed448 requires the "openssl" feature).
 pub fn load_secret_key<P: AsRef<Path>>(p: P) -> Result<key::Algorithm, Error> {
 
     let file = try!(File::open(p.as_ref()));
@@ -533,9 +648,23 @@ pub fn load_secret_key<P: AsRef<Path>>(p: P) -> Result<key::Algorithm, Error> {
                         public: public,
                         secret: secret,
                     });
-                } else {
-                    info!("unsupported key type {:?}", std::str::from_utf8(key_type));
                 }
+                #[cfg(feature = "openssl")]
+                {
+                    if key_type == KEYTYPE_ED448 {
+                        // As for ed25519, the secret key is followed by
+                        // the public key.
+                        let pubkey = try!(position.read_string());
+                        let seckey = try!(position.read_string());
+                        let comment = try!(position.read_string());
+                        debug!("comment = {:?}", comment);
+                        if seckey.len() != 2 * pubkey.len() {
+                            return Err(Error::CouldNotReadKey);
+                        }
+                        return key::Algorithm::ed448_from_bytes(&seckey[..pubkey.len()], pubkey);
+                    }
+                }
+                info!("unsupported key type {:?}", std::str::from_utf8(key_type));
             }
             Err(Error::CouldNotReadKey)
         } else {
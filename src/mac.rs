@@ -1 +1,129 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+use byteorder::{ByteOrder, BigEndian};
+use backend::{Backend, Hash, Selected};
+use std;
+use sodium;
+use negociation;
+use Error;
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Name(&'static str);
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+/// No MAC, used with authenticated ciphers such as chacha20-poly1305.
+pub const NONE: Name = Name("none");
+pub const HMAC_SHA256: Name = Name("hmac-sha2-256");
+pub const HMAC_SHA512: Name = Name("hmac-sha2-512");
+/// Encrypt-then-MAC variant of `hmac-sha2-256`: the MAC is computed
+/// on the encrypted packet, and the packet length is not encrypted.
+pub const HMAC_SHA256_ETM: Name = Name("hmac-sha2-256-etm@openssh.com");
+/// Encrypt-then-MAC variant of `hmac-sha2-512`.
+pub const HMAC_SHA512_ETM: Name = Name("hmac-sha2-512-etm@openssh.com");
+/// HMAC-SHA1. **Insecure**, only for compatibility with old equipment
+/// (requires the "legacy" feature).
+#[cfg(feature = "legacy")]
+pub const HMAC_SHA1: Name = Name("hmac-sha1");
+
+/// All the MACs implemented by this crate.
+#[cfg(not(feature = "legacy"))]
+pub const ALL: &'static [Name] = &[HMAC_SHA256, HMAC_SHA512, HMAC_SHA256_ETM, HMAC_SHA512_ETM];
+/// All the MACs implemented by this crate.
+#[cfg(feature = "legacy")]
+pub const ALL: &'static [Name] = &[HMAC_SHA256,
+                                   HMAC_SHA512,
+                                   HMAC_SHA256_ETM,
+                                   HMAC_SHA512_ETM,
+                                   HMAC_SHA1];
+
+impl std::str::FromStr for Name {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Name, Error> {
+        negociation::parse_name(ALL, s)
+    }
+}
+
+/// Length of the key derived for MAC `m`.
+pub fn key_size(m: Name) -> usize {
+    match m {
+        HMAC_SHA256 | HMAC_SHA256_ETM => 32,
+        HMAC_SHA512 | HMAC_SHA512_ETM => 64,
+        #[cfg(feature = "legacy")]
+        HMAC_SHA1 => 20,
+        _ => 0,
+    }
+}
+
+pub struct Mac {
+    hash: Hash,
+    key: Vec<u8>,
+    size: usize,
+    etm: bool,
+}
+
+impl std::fmt::Debug for Mac {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Mac {{ size: {:?}, etm: {:?} }}", self.size, self.etm)
+    }
+}
+
+impl Mac {
+    pub fn init(m: Name, key: &[u8]) -> Mac {
+        let (hash, etm) = match m {
+            HMAC_SHA256 => (Hash::Sha256, false),
+            HMAC_SHA256_ETM => (Hash::Sha256, true),
+            HMAC_SHA512 => (Hash::Sha512, false),
+            HMAC_SHA512_ETM => (Hash::Sha512, true),
+            #[cfg(feature = "legacy")]
+            HMAC_SHA1 => (Hash::Sha1, false),
+            _ => unreachable!(),
+        };
+        Mac {
+            hash: hash,
+            key: key.to_vec(),
+            size: key_size(m),
+            etm: etm,
+        }
+    }
+
+    /// Length of the MAC appended to each packet.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether this MAC is computed on the encrypted packet.
+    pub fn is_etm(&self) -> bool {
+        self.etm
+    }
+
+    /// Compute the MAC of `packet` (starting with the packet length),
+    /// with sequence number `seqn`, and append it to `out`.
+    pub fn compute(&self, seqn: usize, packet: &[u8], out: &mut Vec<u8>) {
+        let mut seqn_ = [0; 4];
+        BigEndian::write_u32(&mut seqn_, seqn as u32);
+        out.extend(Selected::hmac(self.hash, &self.key, &[&seqn_, packet]))
+    }
+
+    /// Check in constant time that `mac` is the MAC of `packet`.
+    pub fn verify(&self, seqn: usize, packet: &[u8], mac: &[u8]) -> bool {
+        let mut expected = Vec::with_capacity(self.size);
+        self.compute(seqn, packet, &mut expected);
+        sodium::memcmp(&expected, mac)
+    }
+}
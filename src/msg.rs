@@ -23,14 +23,25 @@ pub const DEBUG: u8 = 4;
 
 pub const SERVICE_REQUEST: u8 = 5;
 pub const SERVICE_ACCEPT: u8 = 6;
+// https://tools.ietf.org/html/rfc8308#section-2.3
+pub const EXT_INFO: u8 = 7;
 pub const KEXINIT: u8 = 20;
 pub const NEWKEYS: u8 = 21;
 
 
+// https://tools.ietf.org/html/rfc4250#section-4.1.2, key exchange method specific.
+pub const KEX_FIRST: u8 = 30;
+pub const KEX_LAST: u8 = 49;
+
 // http://tools.ietf.org/html/rfc5656#section-7.1
 pub const KEX_ECDH_INIT: u8 = 30;
 pub const KEX_ECDH_REPLY: u8 = 31;
 
+// https://tools.ietf.org/html/rfc4253#section-8, the reply is the
+// same as KEX_ECDH_REPLY.
+#[cfg(feature = "legacy")]
+pub const KEXDH_INIT: u8 = 30;
+
 
 // https://tools.ietf.org/html/rfc4250#section-4.1.2
 pub const USERAUTH_REQUEST: u8 = 50;
@@ -38,6 +49,11 @@ pub const USERAUTH_FAILURE: u8 = 51;
 pub const USERAUTH_SUCCESS: u8 = 52;
 pub const USERAUTH_BANNER: u8 = 53;
 pub const USERAUTH_PK_OK: u8 = 60;
+pub const USERAUTH_PASSWD_CHANGEREQ: u8 = 60;
+
+// https://tools.ietf.org/html/rfc4256#section-5
+pub const USERAUTH_INFO_REQUEST: u8 = 60;
+pub const USERAUTH_INFO_RESPONSE: u8 = 61;
 
 // https://tools.ietf.org/html/rfc4254#section-9
 pub const GLOBAL_REQUEST: u8 = 80;
@@ -19,38 +19,367 @@ use super::key;
 use super::kex;
 use super::cipher;
 use super::msg;
-// use super::mac; // unimplemented
-// use super::compression; // unimplemented
+use super::mac;
+use super::compression;
 use cryptobuf::CryptoBuf;
 use super::encoding::Reader;
+use std;
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub struct Names {
     pub kex: kex::Name,
     pub key: key::Name,
-    pub cipher: cipher::Name,
-    pub mac: &'static str,
+    /// Cipher of packets sent by the client.
+    pub client_cipher: cipher::Name,
+    /// Cipher of packets sent by the server.
+    pub server_cipher: cipher::Name,
+    /// MAC of packets sent by the client (`mac::NONE` if
+    /// `client_cipher` is authenticated).
+    pub client_mac: mac::Name,
+    /// MAC of packets sent by the server.
+    pub server_mac: mac::Name,
+    /// Compression of packets sent by the client.
+    pub client_compression: compression::Name,
+    /// Compression of packets sent by the server.
+    pub server_compression: compression::Name,
     pub ignore_guessed: bool,
+    /// Whether both sides have the same first choice of kex and host
+    /// key algorithms, i.e. whether a guessed kex packet is valid.
+    pub guess_correct: bool,
+    /// Whether the remote side advertised strict key exchange.
+    pub strict_kex: bool,
+    /// Whether the remote side accepts `SSH_MSG_EXT_INFO`.
+    pub ext_info: bool,
 }
 
-/// Lists of preferred algorithms. This is normally hard-coded into implementations.
-#[derive(Debug)]
+/// Lists of preferred algorithms. These can be changed at runtime
+/// from OpenSSH-style lists of algorithm names:
+///
+/// ```
+/// use thrussh::{Preferred, cipher};
+/// let mut preferred = Preferred::default();
+/// preferred.set_cipher("^aes256-gcm@openssh.com").unwrap();
+/// preferred.set_cipher("-chacha20-poly1305@openssh.com").unwrap();
+/// assert_eq!(preferred.cipher[0], cipher::AES256GCM);
+/// assert!(!preferred.cipher.contains(&cipher::CHACHA20POLY1305));
+/// assert!(preferred.set_cipher("+blowfish-cbc").is_err());
+/// ```
+#[derive(Debug, Clone)]
 pub struct Preferred {
-    pub kex: &'static [kex::Name],
-    pub key: &'static [key::Name],
-    pub cipher: &'static [cipher::Name],
-    pub mac: &'static [&'static str],
-    pub compression: &'static [&'static str],
+    pub kex: Cow<'static, [kex::Name]>,
+    pub key: Cow<'static, [key::Name]>,
+    pub cipher: Cow<'static, [cipher::Name]>,
+    pub mac: Cow<'static, [mac::Name]>,
+    pub compression: Cow<'static, [compression::Name]>,
 }
 
 pub const DEFAULT: Preferred = Preferred {
-    kex: &[kex::CURVE25519],
-    key: &[key::ED25519],
-    cipher: &[cipher::CHACHA20POLY1305],
-    mac: &["hmac-sha2-256"],
-    compression: &["none"],
+    kex: Cow::Borrowed(&[kex::CURVE25519]),
+    key: Cow::Borrowed(&[key::ED25519]),
+    cipher: Cow::Borrowed(&[cipher::CHACHA20POLY1305]),
+    mac: Cow::Borrowed(&[mac::HMAC_SHA256]),
+    compression: Cow::Borrowed(&[compression::NONE]),
+};
+
+/// Only modern algorithms: the mlkem768x25519 post-quantum hybrid
+/// (with the "mlkem" feature), curve25519 and curve448 key
+/// exchanges, ed25519 and ed448 keys, and authenticated ciphers.
+pub const MODERN: Preferred = Preferred {
+    kex: Cow::Borrowed(&[#[cfg(feature = "mlkem")]
+                         kex::MLKEM768X25519,
+                         kex::CURVE25519,
+                         #[cfg(feature = "openssl")]
+                         kex::CURVE448]),
+    key: Cow::Borrowed(&[key::ED25519,
+                         #[cfg(feature = "openssl")]
+                         key::ED448]),
+    cipher: Cow::Borrowed(&[cipher::CHACHA20POLY1305, cipher::AES256GCM, cipher::AES128GCM]),
+    mac: Cow::Borrowed(&[mac::HMAC_SHA256_ETM, mac::HMAC_SHA512_ETM]),
+    compression: Cow::Borrowed(&[compression::NONE, compression::ZLIB_DELAYED]),
+};
+
+/// Algorithms approved by FIPS 140-3: ECDH on the NIST curves, ECDSA
+/// and RSA host keys, AES-GCM and AES-CTR, and HMAC-SHA2.
+///
+/// Note that using this profile does not make the underlying
+/// implementations FIPS-validated.
+pub const FIPS: Preferred = Preferred {
+    kex: Cow::Borrowed(&[kex::ECDH_NISTP256, kex::ECDH_NISTP384, kex::ECDH_NISTP521]),
+    key: Cow::Borrowed(&[key::ECDSA_SHA2_NISTP256,
+                         key::ECDSA_SHA2_NISTP384,
+                         key::ECDSA_SHA2_NISTP521,
+                         key::RSA_SHA2_512,
+                         key::RSA_SHA2_256]),
+    cipher: Cow::Borrowed(&[cipher::AES256GCM,
+                            cipher::AES128GCM,
+                            cipher::AES256CTR,
+                            cipher::AES192CTR,
+                            cipher::AES128CTR]),
+    mac: Cow::Borrowed(&[mac::HMAC_SHA256_ETM,
+                         mac::HMAC_SHA512_ETM,
+                         mac::HMAC_SHA256,
+                         mac::HMAC_SHA512]),
+    compression: Cow::Borrowed(&[compression::NONE, compression::ZLIB_DELAYED]),
+};
+
+/// Every secure algorithm implemented by this crate, followed by the
+/// insecure ones of the "legacy" feature, for compatibility with old
+/// clients and servers.
+pub const LEGACY: Preferred = Preferred {
+    kex: Cow::Borrowed(&[#[cfg(feature = "mlkem")]
+                         kex::MLKEM768X25519,
+                         kex::CURVE25519,
+                         #[cfg(feature = "openssl")]
+                         kex::CURVE448,
+                         kex::ECDH_NISTP256,
+                         kex::ECDH_NISTP384,
+                         kex::ECDH_NISTP521,
+                         #[cfg(feature = "legacy")]
+                         kex::DH_GROUP14_SHA1,
+                         #[cfg(feature = "legacy")]
+                         kex::DH_GROUP1_SHA1]),
+    key: Cow::Borrowed(&[key::ED25519,
+                         #[cfg(feature = "openssl")]
+                         key::ED448,
+                         key::ECDSA_SHA2_NISTP256,
+                         key::ECDSA_SHA2_NISTP384,
+                         key::ECDSA_SHA2_NISTP521,
+                         key::RSA_SHA2_512,
+                         key::RSA_SHA2_256,
+                         #[cfg(feature = "legacy")]
+                         key::RSA,
+                         #[cfg(feature = "legacy")]
+                         key::DSS]),
+    cipher: Cow::Borrowed(&[cipher::CHACHA20POLY1305,
+                            cipher::AES256GCM,
+                            cipher::AES128GCM,
+                            cipher::AES256CTR,
+                            cipher::AES192CTR,
+                            cipher::AES128CTR,
+                            #[cfg(feature = "legacy")]
+                            cipher::AES128CBC,
+                            #[cfg(feature = "legacy")]
+                            cipher::TRIPLE_DES_CBC]),
+    mac: Cow::Borrowed(&[mac::HMAC_SHA256_ETM,
+                         mac::HMAC_SHA512_ETM,
+                         mac::HMAC_SHA256,
+                         mac::HMAC_SHA512,
+                         #[cfg(feature = "legacy")]
+                         mac::HMAC_SHA1]),
+    compression: Cow::Borrowed(&[compression::NONE,
+                                 compression::ZLIB_DELAYED,
+                                 compression::ZLIB]),
 };
 
+/// A known weakness: kind of algorithm, name, and reason.
+type Weakness = (&'static str, &'static str, &'static str);
+
+/// Insecure algorithms, reported by every audit.
+const INSECURE: &'static [Weakness] = &[
+    ("kex", "diffie-hellman-group1-sha1", "1024-bit group, SHA-1"),
+    ("kex", "diffie-hellman-group14-sha1", "SHA-1 exchange hash"),
+    ("key", "ssh-rsa", "SHA-1 signatures"),
+    ("key", "ssh-dss", "1024-bit DSA, SHA-1 signatures"),
+    ("cipher", "3des-cbc", "64-bit blocks (Sweet32)"),
+    ("cipher", "aes128-cbc", "CBC mode, plaintext recovery attacks"),
+    ("mac", "hmac-sha1", "SHA-1"),
+];
+
+/// Reasons why `MODERN` leaves out the other algorithms.
+const MODERN_WEAKNESSES: &'static [Weakness] = &[
+    ("kex", "ecdh-sha2-nistp256", "NIST curve"),
+    ("kex", "ecdh-sha2-nistp384", "NIST curve"),
+    ("kex", "ecdh-sha2-nistp521", "NIST curve"),
+    ("key", "ecdsa-sha2-nistp256", "NIST curve, fragile nonces"),
+    ("key", "ecdsa-sha2-nistp384", "NIST curve, fragile nonces"),
+    ("key", "ecdsa-sha2-nistp521", "NIST curve, fragile nonces"),
+    ("key", "rsa-sha2-256", "RSA, PKCS#1 v1.5 padding"),
+    ("key", "rsa-sha2-512", "RSA, PKCS#1 v1.5 padding"),
+    ("cipher", "aes128-ctr", "unauthenticated cipher"),
+    ("cipher", "aes192-ctr", "unauthenticated cipher"),
+    ("cipher", "aes256-ctr", "unauthenticated cipher"),
+    ("mac", "hmac-sha2-256", "encrypt-and-MAC"),
+    ("mac", "hmac-sha2-512", "encrypt-and-MAC"),
+    ("compression", "zlib", "compression before authentication"),
+];
+
+/// Reasons why `FIPS` leaves out the other algorithms.
+const FIPS_WEAKNESSES: &'static [Weakness] = &[
+    ("kex", "mlkem768x25519-sha256", "X25519 is not FIPS-approved"),
+    ("kex", "curve25519-sha256@libssh.org", "not FIPS-approved"),
+    ("kex", "curve448-sha512", "not FIPS-approved"),
+    ("key", "ssh-ed25519", "EdDSA, not in FIPS-validated SSH modules"),
+    ("key", "ssh-ed448", "EdDSA, not in FIPS-validated SSH modules"),
+    ("cipher", "chacha20-poly1305@openssh.com", "not FIPS-approved"),
+    ("compression", "zlib", "compression before authentication"),
+];
+
+impl Preferred {
+    /// List the algorithms of `self` that are not in the `MODERN`
+    /// profile, as strings of the form `"cipher aes128-ctr: reason"`.
+    /// The result is empty if `self` only contains modern algorithms.
+    ///
+    /// ```
+    /// use thrussh::Preferred;
+    /// assert!(thrussh::MODERN.audit_modern().is_empty());
+    /// let mut preferred = Preferred::default();
+    /// preferred.set_cipher("aes128-ctr").unwrap();
+    /// assert!(preferred.audit_modern()
+    ///                  .contains(&"cipher aes128-ctr: unauthenticated cipher".to_string()));
+    /// ```
+    pub fn audit_modern(&self) -> Vec<String> {
+        self.audit(Some(&MODERN), MODERN_WEAKNESSES)
+    }
+
+    /// List the algorithms of `self` that are not in the `FIPS`
+    /// profile, see `audit_modern`.
+    pub fn audit_fips(&self) -> Vec<String> {
+        self.audit(Some(&FIPS), FIPS_WEAKNESSES)
+    }
+
+    /// List the insecure algorithms of `self`, such as those of the
+    /// "legacy" feature, see `audit_modern`.
+    pub fn audit_legacy(&self) -> Vec<String> {
+        self.audit(None, &[])
+    }
+
+    fn audit(&self, profile: Option<&Preferred>, table: &[Weakness]) -> Vec<String> {
+        let mut weak = Vec::new();
+        audit_list("kex",
+                   &self.kex,
+                   profile.map(|p| &p.kex[..]),
+                   table,
+                   &mut weak);
+        audit_list("key",
+                   &self.key,
+                   profile.map(|p| &p.key[..]),
+                   table,
+                   &mut weak);
+        audit_list("cipher",
+                   &self.cipher,
+                   profile.map(|p| &p.cipher[..]),
+                   table,
+                   &mut weak);
+        audit_list("mac",
+                   &self.mac,
+                   profile.map(|p| &p.mac[..]),
+                   table,
+                   &mut weak);
+        audit_list("compression",
+                   &self.compression,
+                   profile.map(|p| &p.compression[..]),
+                   table,
+                   &mut weak);
+        weak
+    }
+
+    /// Change the key exchange algorithms. `list` is a
+    /// comma-separated list of names. If it starts with `+`, these
+    /// names are appended to the current list; with `-`, they are
+    /// removed from it; with `^`, they are moved to its front.
+    /// Otherwise, the current list is replaced.
+    pub fn set_kex(&mut self, list: &str) -> Result<(), Error> {
+        self.kex = Cow::Owned(try!(modify_list(&self.kex, list)));
+        Ok(())
+    }
+
+    /// Change the host key algorithms, see `set_kex` for the syntax of `list`.
+    pub fn set_key(&mut self, list: &str) -> Result<(), Error> {
+        self.key = Cow::Owned(try!(modify_list(&self.key, list)));
+        Ok(())
+    }
+
+    /// Change the ciphers, see `set_kex` for the syntax of `list`.
+    pub fn set_cipher(&mut self, list: &str) -> Result<(), Error> {
+        self.cipher = Cow::Owned(try!(modify_list(&self.cipher, list)));
+        Ok(())
+    }
+
+    /// Change the MACs, see `set_kex` for the syntax of `list`.
+    pub fn set_mac(&mut self, list: &str) -> Result<(), Error> {
+        self.mac = Cow::Owned(try!(modify_list(&self.mac, list)));
+        Ok(())
+    }
+
+    /// Change the compression algorithms, see `set_kex` for the syntax of `list`.
+    pub fn set_compression(&mut self, list: &str) -> Result<(), Error> {
+        self.compression = Cow::Owned(try!(modify_list(&self.compression, list)));
+        Ok(())
+    }
+}
+
+/// Report the algorithms of `list` that are insecure, or not
+/// `allowed`. Algorithms absent from the tables, such as registered
+/// ones, have no known reason.
+fn audit_list<N: AsRef<str> + PartialEq>(kind: &str,
+                                         list: &[N],
+                                         allowed: Option<&[N]>,
+                                         table: &[Weakness],
+                                         weak: &mut Vec<String>) {
+    for name in list {
+        let name_ = name.as_ref();
+        let reason = INSECURE.iter()
+            .chain(table.iter())
+            .find(|&&(k, n, _)| k == kind && n == name_)
+            .map(|&(_, _, reason)| reason);
+        if let Some(reason) = reason {
+            weak.push(format!("{} {}: {}", kind, name_, reason))
+        } else if allowed.map(|a| !a.contains(name)).unwrap_or(false) {
+            weak.push(format!("{} {}: not in the profile", kind, name_))
+        }
+    }
+}
+
+/// Find `name` among the algorithms implemented by this crate.
+pub fn parse_name<N: AsRef<str> + Copy>(all: &[N], name: &str) -> Result<N, Error> {
+    if let Some(n) = all.iter().find(|n| n.as_ref() == name) {
+        Ok(*n)
+    } else {
+        Err(Error::UnknownAlgorithm(name.to_string()))
+    }
+}
+
+/// Apply an OpenSSH-style list of algorithms to `current`.
+fn modify_list<N>(current: &[N], list: &str) -> Result<Vec<N>, Error>
+    where N: std::str::FromStr<Err = Error> + PartialEq + Copy
+{
+    let (modifier, list) = match list.chars().next() {
+        Some(c) if c == '+' || c == '-' || c == '^' => (Some(c), &list[1..]),
+        _ => (None, list),
+    };
+    let mut names = Vec::new();
+    for name in list.split(',') {
+        let name = name.trim();
+        if !name.is_empty() {
+            names.push(try!(name.parse()))
+        }
+    }
+    let result: Vec<N> = match modifier {
+        Some('+') => {
+            let mut result = current.to_vec();
+            for name in names {
+                if !result.contains(&name) {
+                    result.push(name)
+                }
+            }
+            result
+        }
+        Some('-') => current.iter().filter(|n| !names.contains(n)).cloned().collect(),
+        Some('^') => {
+            let mut result = names.clone();
+            result.extend(current.iter().filter(|n| !names.contains(n)));
+            result
+        }
+        _ => names,
+    };
+    if result.is_empty() {
+        Err(Error::NoAlgorithm)
+    } else {
+        Ok(result)
+    }
+}
+
 impl Default for Preferred {
     fn default() -> Preferred {
         DEFAULT
@@ -70,53 +399,218 @@ impl Named for () {
 pub trait Select {
     fn select<S: AsRef<str> + Copy>(a: &[S], b: &[u8]) -> Option<(bool, S)>;
 
+    /// Pseudo-algorithms appended to our list of kex algorithms, to
+    /// advertise protocol extensions.
+    fn kex_extensions() -> &'static [kex::Name];
+
+    /// Pseudo-algorithm used by the remote side to advertise strict
+    /// key exchange.
+    fn remote_strict_kex() -> kex::Name;
+
+    /// Pseudo-algorithm used by the remote side to advertise
+    /// extension negotiation.
+    fn remote_ext_info() -> kex::Name;
+
     fn read_kex(buffer: &[u8], pref: &Preferred) -> Result<Names, Error> {
         let mut r = buffer.reader(17);
-        let (kex_both_first, kex_algorithm) = if let Some(x) =
-                                                     Self::select(pref.kex, try!(r.read_string())) {
+        let kex_list = try!(r.read_string());
+        let (kex_both_first, kex_algorithm) = if let Some(x) = Self::select(&pref.kex, kex_list) {
             x
         } else {
             return Err(Error::KexInit);
         };
+        let strict_kex = contains(kex_list, Self::remote_strict_kex());
+        let ext_info = contains(kex_list, Self::remote_ext_info());
 
         let (key_both_first, key_algorithm) = if let Some(x) =
-                                                     Self::select(pref.key, try!(r.read_string())) {
+                                                     Self::select(&pref.key, try!(r.read_string())) {
             x
         } else {
             return Err(Error::KexInit);
         };
 
-        let cipher = Self::select(pref.cipher, try!(r.read_string()));
-
-        try!(r.read_string()); // SERVER_TO_CLIENT
-        let mac = Self::select(pref.mac, try!(r.read_string()));
-
-        try!(r.read_string()); // SERVER_TO_CLIENT
-        try!(r.read_string()); //
-        try!(r.read_string()); //
-        try!(r.read_string()); //
+        let client_cipher = Self::select(&pref.cipher, try!(r.read_string()));
+        let server_cipher = Self::select(&pref.cipher, try!(r.read_string()));
+        let client_mac = Self::select_mac(pref, client_cipher, try!(r.read_string()));
+        let server_mac = Self::select_mac(pref, server_cipher, try!(r.read_string()));
+        let client_compression = Self::select(&pref.compression, try!(r.read_string()));
+        let server_compression = Self::select(&pref.compression, try!(r.read_string()));
+        try!(r.read_string()); // languages client to server
+        try!(r.read_string()); // languages server to client
 
         let follows = try!(r.read_byte()) != 0;
-        match (cipher, mac, follows) {
-            (Some((_, cip)), Some((_, mac)), fol) => {
+        let guess_correct = kex_both_first && key_both_first;
+        match (client_cipher,
+               server_cipher,
+               client_mac,
+               server_mac,
+               client_compression,
+               server_compression) {
+            (Some((_, client_cipher)),
+             Some((_, server_cipher)),
+             Some((_, client_mac)),
+             Some((_, server_mac)),
+             Some((_, client_compression)),
+             Some((_, server_compression))) => {
                 Ok(Names {
                     kex: kex_algorithm,
                     key: key_algorithm,
-                    cipher: cip,
-                    mac: mac,
+                    client_cipher: client_cipher,
+                    server_cipher: server_cipher,
+                    client_mac: client_mac,
+                    server_mac: server_mac,
+                    client_compression: client_compression,
+                    server_compression: server_compression,
                     // Ignore the next packet if (1) it follows and (2) it's not the correct guess.
-                    ignore_guessed: fol && !(kex_both_first && key_both_first),
+                    ignore_guessed: follows && !guess_correct,
+                    guess_correct: guess_correct,
+                    strict_kex: strict_kex,
+                    ext_info: ext_info,
                 })
             }
             _ => Err(Error::KexInit),
         }
     }
+
+    /// Select the MAC used with `cipher`, in one direction.
+    /// Authenticated ciphers don't use the negotiated MAC.
+    fn select_mac(pref: &Preferred,
+                  cipher: Option<(bool, cipher::Name)>,
+                  mac_list: &[u8])
+                  -> Option<(bool, mac::Name)> {
+        match cipher {
+            Some((_, cipher)) if !cipher::needs_mac(cipher) => Some((false, mac::NONE)),
+            _ => Self::select(&pref.mac, mac_list),
+        }
+    }
+
+    /// Write a KEXINIT packet. The extension pseudo-algorithms are
+    /// only advertised during the first key exchange. `follows`
+    /// indicates that a guessed kex packet will be sent right after
+    /// this one.
+    fn write_kex(prefs: &Preferred, buf: &mut CryptoBuf, first_kex: bool, follows: bool) {
+        // buf.clear();
+        buf.push(msg::KEXINIT);
+
+        let mut cookie = [0; 16];
+        randombytes::into(&mut cookie);
+
+        buf.extend(&cookie); // cookie
+        let extensions: &[kex::Name] = if first_kex {
+            Self::kex_extensions()
+        } else {
+            &[]
+        };
+        buf.extend_list(prefs.kex.iter().chain(extensions.iter())); // kex algo
+
+        buf.extend_list(prefs.key.iter());
+
+        buf.extend_list(prefs.cipher.iter()); // cipher client to server
+        buf.extend_list(prefs.cipher.iter()); // cipher server to client
+
+        buf.extend_list(prefs.mac.iter()); // mac client to server
+        buf.extend_list(prefs.mac.iter()); // mac server to client
+        buf.extend_list(prefs.compression.iter()); // compress client to server
+        buf.extend_list(prefs.compression.iter()); // compress server to client
+
+        buf.write_empty_list(); // languages client to server
+        buf.write_empty_list(); // languagesserver to client
+
+        buf.push(if follows {
+            1
+        } else {
+            0
+        }); // first_kex_packet_follows
+        buf.extend(&[0, 0, 0, 0]); // reserved
+    }
+}
+
+/// Extensions received in an `SSH_MSG_EXT_INFO` message, see
+/// [RFC8308](https://tools.ietf.org/html/rfc8308). Unknown
+/// extensions are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct ExtInfo {
+    /// Public key algorithms accepted by the server for user authentication.
+    pub server_sig_algs: Option<Vec<String>>,
+    /// Value of "no-flow-control" (`"p"` if preferred, `"s"` if supported).
+    pub no_flow_control: Option<String>,
+    /// Compression algorithms from "delay-compression", client to
+    /// server and server to client.
+    pub delay_compression: Option<(Vec<String>, Vec<String>)>,
+    /// Version of "publickey-hostbound@openssh.com".
+    pub publickey_hostbound: Option<String>,
+}
+
+fn name_list(list: &[u8]) -> Result<Vec<String>, Error> {
+    let mut v = Vec::new();
+    for name in list.split(|&x| x == b',') {
+        if !name.is_empty() {
+            v.push(try!(std::str::from_utf8(name)).to_string())
+        }
+    }
+    Ok(v)
+}
+
+impl ExtInfo {
+    /// Update these extensions with the contents of an `SSH_MSG_EXT_INFO` packet.
+    pub fn read(&mut self, buf: &[u8]) -> Result<(), Error> {
+        let mut r = buf.reader(1);
+        let n = try!(r.read_u32());
+        for _ in 0..n {
+            let name = try!(r.read_string());
+            let value = try!(r.read_string());
+            debug!("ext info: {:?} {:?}", std::str::from_utf8(name), std::str::from_utf8(value));
+            match name {
+                b"server-sig-algs" => self.server_sig_algs = Some(try!(name_list(value))),
+                b"no-flow-control" => {
+                    self.no_flow_control = Some(try!(std::str::from_utf8(value)).to_string())
+                }
+                b"delay-compression" => {
+                    let mut v = value.reader(0);
+                    let c2s = try!(name_list(try!(v.read_string())));
+                    let s2c = try!(name_list(try!(v.read_string())));
+                    self.delay_compression = Some((c2s, s2c))
+                }
+                b"publickey-hostbound@openssh.com" => {
+                    self.publickey_hostbound = Some(try!(std::str::from_utf8(value)).to_string())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the `SSH_MSG_EXT_INFO` sent by servers, advertising the
+/// public key algorithms accepted for authentication.
+pub fn write_server_ext_info(auth_key_algorithms: &[key::Name], buf: &mut CryptoBuf) {
+    buf.push(msg::EXT_INFO);
+    buf.push_u32_be(1);
+    buf.extend_ssh_string(b"server-sig-algs");
+    buf.extend_list(auth_key_algorithms.iter());
+}
+
+/// Whether the comma-separated name-list `list` contains `name`.
+fn contains<S: AsRef<str>>(list: &[u8], name: S) -> bool {
+    list.split(|&x| x == b',').any(|x| x == name.as_ref().as_bytes())
 }
 
 pub struct Server;
 pub struct Client;
 
 impl Select for Server {
+    fn kex_extensions() -> &'static [kex::Name] {
+        &[kex::EXT_INFO_S, kex::STRICT_KEX_SERVER]
+    }
+
+    fn remote_strict_kex() -> kex::Name {
+        kex::STRICT_KEX_CLIENT
+    }
+
+    fn remote_ext_info() -> kex::Name {
+        kex::EXT_INFO_C
+    }
+
     fn select<S: AsRef<str> + Copy>(server_list: &[S], client_list: &[u8]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for c in client_list.split(|&x| x == b',') {
@@ -132,6 +626,18 @@ impl Select for Server {
 }
 
 impl Select for Client {
+    fn kex_extensions() -> &'static [kex::Name] {
+        &[kex::EXT_INFO_C, kex::STRICT_KEX_CLIENT]
+    }
+
+    fn remote_strict_kex() -> kex::Name {
+        kex::STRICT_KEX_SERVER
+    }
+
+    fn remote_ext_info() -> kex::Name {
+        kex::EXT_INFO_S
+    }
+
     fn select<S: AsRef<str> + Copy>(client_list: &[S], server_list: &[u8]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for &c in client_list {
@@ -147,29 +653,185 @@ impl Select for Client {
 }
 
 
-pub fn write_kex(prefs: &Preferred, buf: &mut CryptoBuf) {
-    // buf.clear();
-    buf.push(msg::KEXINIT);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cryptobuf::CryptoBuf;
+
+    /// A KEXINIT packet with the given name-lists, in the order of
+    /// RFC4253, section 7.1.
+    fn kexinit(lists: &[&str]) -> CryptoBuf {
+        let mut buf = CryptoBuf::new();
+        buf.push(msg::KEXINIT);
+        buf.extend(&[0; 16]);
+        for list in lists {
+            buf.extend_ssh_string(list.as_bytes());
+        }
+        buf.extend_ssh_string(b"");
+        buf.extend_ssh_string(b"");
+        buf.push(0);
+        buf.extend(&[0, 0, 0, 0]);
+        buf
+    }
+
+    #[test]
+    fn algorithms_are_negotiated_per_direction() {
+        let buf = kexinit(&["curve25519-sha256@libssh.org",
+                            "ssh-ed25519",
+                            "aes128-ctr",
+                            "chacha20-poly1305@openssh.com",
+                            "hmac-sha2-512,hmac-sha2-256",
+                            "hmac-sha2-256",
+                            "zlib@openssh.com,none",
+                            "none"]);
+        let names = Server::read_kex(&buf, &LEGACY).unwrap();
+        assert_eq!(names.client_cipher, cipher::AES128CTR);
+        assert_eq!(names.server_cipher, cipher::CHACHA20POLY1305);
+        assert_eq!(names.client_mac, mac::HMAC_SHA512);
+        // The server's cipher is authenticated.
+        assert_eq!(names.server_mac, mac::NONE);
+        assert_eq!(names.client_compression, compression::ZLIB_DELAYED);
+        assert_eq!(names.server_compression, compression::NONE);
+
+        let names = Client::read_kex(&buf, &LEGACY).unwrap();
+        assert_eq!(names.client_cipher, cipher::AES128CTR);
+        assert_eq!(names.server_cipher, cipher::CHACHA20POLY1305);
+        // The client's preference wins.
+        assert_eq!(names.client_mac, mac::HMAC_SHA256);
+        assert_eq!(names.client_compression, compression::NONE);
+    }
+
+    #[test]
+    fn each_direction_must_agree() {
+        let buf = kexinit(&["curve25519-sha256@libssh.org",
+                            "ssh-ed25519",
+                            "aes128-ctr",
+                            "blowfish-cbc",
+                            "hmac-sha2-256",
+                            "hmac-sha2-256",
+                            "none",
+                            "none"]);
+        match Server::read_kex(&buf, &Preferred::default()) {
+            Err(Error::KexInit) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn modifiers_are_applied_to_the_current_list() {
+        let current = [cipher::AES256CTR, cipher::AES192CTR, cipher::AES128CTR];
+        assert_eq!(modify_list(&current, "+aes128-gcm@openssh.com,aes256-ctr").unwrap(),
+                   vec![cipher::AES256CTR,
+                        cipher::AES192CTR,
+                        cipher::AES128CTR,
+                        cipher::AES128GCM]);
+        assert_eq!(modify_list(&current, "-aes192-ctr, aes128-ctr").unwrap(),
+                   vec![cipher::AES256CTR]);
+        assert_eq!(modify_list(&current, "^aes128-ctr,aes128-gcm@openssh.com").unwrap(),
+                   vec![cipher::AES128CTR,
+                        cipher::AES128GCM,
+                        cipher::AES256CTR,
+                        cipher::AES192CTR]);
+        assert_eq!(modify_list(&current, "aes128-gcm@openssh.com,").unwrap(),
+                   vec![cipher::AES128GCM]);
+    }
 
-    let mut cookie = [0; 16];
-    randombytes::into(&mut cookie);
+    #[test]
+    fn unknown_names_are_rejected() {
+        let current = [cipher::AES256CTR];
+        for list in &["+blowfish-cbc", "-aes256-ctr,nope", "aes256-ctr,AES128-CTR"] {
+            match modify_list(&current, list) {
+                Err(Error::UnknownAlgorithm(_)) => {}
+                x => panic!("{:?} {:?}", list, x),
+            }
+        }
+        match modify_list(&current, "+blowfish-cbc") {
+            Err(Error::UnknownAlgorithm(ref name)) => assert_eq!(name, "blowfish-cbc"),
+            x => panic!("{:?}", x),
+        }
+    }
 
-    buf.extend(&cookie); // cookie
-    buf.extend_list(prefs.kex.iter()); // kex algo
+    #[test]
+    fn empty_lists_are_rejected() {
+        let mut preferred = Preferred::default();
+        for list in &["", "-aes256-gcm@openssh.com,aes128-gcm@openssh.com,aes256-ctr,\
+                            aes192-ctr,aes128-ctr,chacha20-poly1305@openssh.com"] {
+            match preferred.set_cipher(list) {
+                Err(Error::NoAlgorithm) => {}
+                x => panic!("{:?} {:?}", list, x),
+            }
+        }
+        // The preferences are unchanged on errors.
+        assert_eq!(&preferred.cipher[..], &DEFAULT.cipher[..]);
+    }
 
-    buf.extend_list(prefs.key.iter());
+    #[test]
+    fn every_list_can_be_modified() {
+        let mut preferred = LEGACY;
+        preferred.set_kex("^ecdh-sha2-nistp256").unwrap();
+        preferred.set_key("ssh-ed25519").unwrap();
+        preferred.set_mac("-hmac-sha2-256,hmac-sha2-512").unwrap();
+        preferred.set_compression("+zlib").unwrap();
+        assert_eq!(preferred.kex[0], kex::ECDH_NISTP256);
+        assert_eq!(&preferred.key[..], &[key::ED25519]);
+        assert!(!preferred.mac.contains(&mac::HMAC_SHA256));
+        assert!(preferred.mac.contains(&mac::HMAC_SHA256_ETM));
+        assert!(preferred.compression.contains(&compression::ZLIB));
+        assert!(preferred.set_kex("+diffie-hellman-group42-sha1").is_err());
+        assert!(preferred.set_mac("umac-64@openssh.com").is_err());
+    }
 
-    buf.extend_list(prefs.cipher.iter()); // cipher client to server
-    buf.extend_list(prefs.cipher.iter()); // cipher server to client
+    #[test]
+    fn profiles_pass_their_own_audits() {
+        assert!(MODERN.audit_modern().is_empty());
+        assert!(FIPS.audit_fips().is_empty());
+        assert!(DEFAULT.audit_legacy().is_empty());
+        assert!(MODERN.audit_fips().contains(&"key ssh-ed25519: EdDSA, not in FIPS-validated \
+                                                SSH modules"
+            .to_string()));
+        assert!(FIPS.audit_modern().contains(&"kex ecdh-sha2-nistp256: NIST curve".to_string()));
+    }
 
-    buf.extend_list(prefs.mac.iter()); // mac client to server
-    buf.extend_list(prefs.mac.iter()); // mac server to client
-    buf.extend_list(prefs.compression.iter()); // compress client to server
-    buf.extend_list(prefs.compression.iter()); // compress server to client
+    #[test]
+    fn every_algorithm_outside_modern_has_a_reason() {
+        let all = Preferred {
+            kex: Cow::Borrowed(kex::ALL),
+            key: Cow::Borrowed(key::ALL),
+            cipher: Cow::Borrowed(cipher::ALL),
+            mac: Cow::Borrowed(mac::ALL),
+            compression: Cow::Borrowed(compression::ALL),
+        };
+        for weak in all.audit_modern().iter().chain(all.audit_fips().iter()) {
+            assert!(!weak.ends_with("not in the profile"), "{}", weak)
+        }
+    }
 
-    buf.write_empty_list(); // languages client to server
-    buf.write_empty_list(); // languagesserver to client
+    #[test]
+    fn unlisted_algorithms_are_reported() {
+        let mut preferred = MODERN;
+        preferred.set_kex("+ecdh-sha2-nistp256").unwrap();
+        preferred.set_key("+rsa-sha2-256").unwrap();
+        assert_eq!(preferred.audit_modern(),
+                   vec!["kex ecdh-sha2-nistp256: NIST curve".to_string(),
+                        "key rsa-sha2-256: RSA, PKCS#1 v1.5 padding".to_string()]);
+        assert!(preferred.audit_legacy().is_empty());
+    }
 
-    buf.push(0); // doesn't follow
-    buf.extend(&[0, 0, 0, 0]); // reserved
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn legacy_audit_reports_insecure_algorithms() {
+        let weak = LEGACY.audit_legacy();
+        assert_eq!(weak,
+                   vec!["kex diffie-hellman-group14-sha1: SHA-1 exchange hash".to_string(),
+                        "kex diffie-hellman-group1-sha1: 1024-bit group, SHA-1".to_string(),
+                        "key ssh-rsa: SHA-1 signatures".to_string(),
+                        "key ssh-dss: 1024-bit DSA, SHA-1 signatures".to_string(),
+                        "cipher aes128-cbc: CBC mode, plaintext recovery attacks".to_string(),
+                        "cipher 3des-cbc: 64-bit blocks (Sweet32)".to_string(),
+                        "mac hmac-sha1: SHA-1".to_string()]);
+        // The insecure algorithms come after the modern ones.
+        assert_eq!(&LEGACY.kex[..MODERN.kex.len()], &MODERN.kex[..]);
+        assert_eq!(&LEGACY.key[..MODERN.key.len()], &MODERN.key[..]);
+        assert!(LEGACY.audit_modern().len() > weak.len());
+    }
 }
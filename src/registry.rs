@@ -0,0 +1,174 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Process-wide tables of algorithms implemented outside of this
+// crate, indexed by their names.
+
+use std::sync::{Arc, RwLock};
+
+pub struct Registry<T: ?Sized> {
+    entries: RwLock<Vec<(&'static str, Arc<T>)>>,
+}
+
+impl<T: ?Sized> Registry<T> {
+    pub const fn new() -> Self {
+        Registry { entries: RwLock::new(Vec::new()) }
+    }
+
+    /// Add `algorithm` under `name`, replacing any previous algorithm
+    /// registered with that name.
+    pub fn register(&self, name: &'static str, algorithm: Arc<T>) {
+        let mut entries = self.entries.write().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|e| e.0 == name) {
+            entry.1 = algorithm;
+            return;
+        }
+        entries.push((name, algorithm))
+    }
+
+    pub fn get(&self, name: &str) -> Option<(&'static str, Arc<T>)> {
+        let entries = self.entries.read().unwrap();
+        entries.iter().find(|e| e.0 == name).map(|e| (e.0, e.1.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use cipher::{self, block, CipherAlgorithm, CipherT};
+    use kex::{self, KexAlgorithm, KexState};
+    use key::{self, KeyPair, PublicKeyAlgorithm};
+    use sodium::{curve25519, ed25519, randombytes, sha256};
+    use {client, mac, server, testing, Error, Preferred};
+
+    /// AES-128 in counter mode, under another name.
+    struct Ctr;
+    impl CipherAlgorithm for Ctr {
+        fn key_size(&self) -> usize {
+            16
+        }
+        fn iv_size(&self) -> usize {
+            16
+        }
+        fn needs_mac(&self) -> bool {
+            true
+        }
+        fn init(&self, key: &[u8], iv: &[u8], mac: Option<mac::Mac>) -> Box<CipherT + Send> {
+            Box::new(block::Cipher::init_ctr(key, iv, mac.unwrap()))
+        }
+    }
+
+    /// curve25519-sha256, under another name.
+    struct X25519;
+    #[derive(Debug)]
+    struct X25519State {
+        secret: curve25519::Scalar,
+        public: curve25519::GroupElement,
+        shared: Option<curve25519::GroupElement>,
+    }
+    impl KexAlgorithm for X25519 {
+        fn start(&self) -> Result<Box<KexState>, Error> {
+            let mut secret = [0; curve25519::SCALARBYTES];
+            randombytes::into(&mut secret);
+            let secret = curve25519::Scalar::copy_from_slice(&secret);
+            let mut public = curve25519::GroupElement::new_blank();
+            curve25519::scalarmult_base(&mut public, &secret);
+            Ok(Box::new(X25519State {
+                secret: secret,
+                public: public,
+                shared: None,
+            }))
+        }
+    }
+    impl KexState for X25519State {
+        fn local_pubkey(&self) -> &[u8] {
+            &self.public
+        }
+        fn compute_shared_secret(&mut self, remote: &[u8]) -> Result<(), Error> {
+            if remote.len() != curve25519::GROUPELEMENTBYTES {
+                return Err(Error::Kex);
+            }
+            let remote = curve25519::GroupElement::copy_from_slice(remote);
+            let mut shared = curve25519::GroupElement::new_blank();
+            curve25519::scalarmult(&mut shared, &self.secret, &remote);
+            self.shared = Some(shared);
+            Ok(())
+        }
+        fn shared_secret(&self) -> Option<&[u8]> {
+            self.shared.as_ref().map(|s| &s[..])
+        }
+        fn hash(&self, data: &[u8]) -> Vec<u8> {
+            let mut digest = sha256::Digest::new_blank();
+            sha256::hash(&mut digest, data);
+            digest.to_vec()
+        }
+    }
+
+    /// Ed25519, under another name.
+    struct Ed;
+    impl PublicKeyAlgorithm for Ed {
+        fn verify_detached(&self, public: &[u8], buffer: &[u8], sig: &[u8]) -> bool {
+            public.len() == ed25519::PUBLICKEYBYTES && sig.len() == ed25519::SIGNATUREBYTES &&
+            ed25519::verify_detached(&ed25519::Signature::copy_from_slice(sig),
+                                     buffer,
+                                     &ed25519::PublicKey::copy_from_slice(public))
+        }
+    }
+    struct EdPair(ed25519::PublicKey, ed25519::SecretKey, key::Name);
+    impl KeyPair for EdPair {
+        fn name(&self) -> key::Name {
+            self.2
+        }
+        fn public_key(&self) -> Vec<u8> {
+            self.0.to_vec()
+        }
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            let mut sig = ed25519::Signature::new_blank();
+            ed25519::sign_detached(&mut sig, data, &self.1);
+            sig.to_vec()
+        }
+    }
+
+    #[test]
+    fn registered_algorithms() {
+        let preferred = Preferred {
+            kex: Cow::Owned(vec![kex::register("x25519@example.com", Box::new(X25519))]),
+            key: Cow::Owned(vec![key::register("ed@example.com", Box::new(Ed))]),
+            cipher: Cow::Owned(vec![cipher::register("ctr@example.com", Box::new(Ctr))]),
+            ..Default::default()
+        };
+        assert_eq!("ctr@example.com".parse::<cipher::Name>().unwrap(), preferred.cipher[0]);
+        assert!("ctr@example.org".parse::<cipher::Name>().is_err());
+
+        let (public, secret) = ed25519::generate_keypair().unwrap();
+        let host_key = key::Algorithm::Custom(Arc::new(EdPair(public, secret, preferred.key[0])));
+        let client_key = testing::client_key();
+        let mut server_config = server::Config::default();
+        server_config.preferred = preferred.clone();
+        let mut client_config = client::Config::default();
+        client_config.preferred = preferred;
+        let server = testing::Server { client_pubkey: client_key.clone_public_key() };
+        let client = testing::Client {
+            server_pk: host_key.clone_public_key(),
+            confirmed: Vec::new(),
+            received: Vec::new(),
+        };
+        let mut p = testing::Pair::new(server, server_config, host_key, client, client_config);
+        p.client_connection.set_auth_public_key(client_key);
+        p.authenticate();
+        p.echo(b"registered");
+    }
+}
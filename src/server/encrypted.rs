@@ -19,9 +19,9 @@ use msg;
 use encoding::Reader;
 use auth::*;
 use std;
+use std::collections::VecDeque;
 use byteorder::{ByteOrder, BigEndian};
 use rand::{thread_rng, Rng};
-use key::Verify;
 use negociation;
 use negociation::Select;
 use auth;
@@ -43,7 +43,7 @@ impl Session {
                 if let Some(exchange) = std::mem::replace(&mut enc.exchange, None) {
                     let kexinit = KexInit::received_rekey(
                         exchange,
-                        try!(negociation::Server::read_kex(buf, &self.0.config.as_ref().preferred)),
+                        try!(negociation::Server::read_kex(buf, &self.0.config.kex_preferred())),
                         &enc.session_id
                     );
                     self.0.kex = Some(try!(kexinit.server_parse(self.0.config.as_ref(),
@@ -54,9 +54,15 @@ impl Session {
                 return Ok(());
             }
         }
+        if buf[0] == msg::EXT_INFO {
+            if let Some(ref mut enc) = self.0.encrypted {
+                return enc.read_ext_info(buf);
+            }
+        }
         // If we've successfully read a packet.
         // debug!("state = {:?}, buf = {:?}", self.0.state, buf);
         let mut is_authenticated = false;
+        let mut service = None;
         if let Some(ref mut enc) = self.0.encrypted {
             let state = std::mem::replace(&mut enc.state, None);
             match state {
@@ -67,12 +73,28 @@ impl Session {
                     debug!("request: {:?}", std::str::from_utf8(request));
                     if request == b"ssh-userauth" {
 
-                        let auth_request =
-                            server_accept_service(self.0.config.as_ref().auth_banner,
-                                                  self.0.config.as_ref().methods,
-                                                  &mut enc.write);
+                        let banner = server.auth_banner(None);
+                        let banner = banner.as_ref()
+                            .map(|banner| banner.as_ref())
+                            .or(self.0.config.as_ref().auth_banner);
+                        let auth_request = server_accept_service(banner,
+                                                                 self.0.config.as_ref().methods,
+                                                                 &mut enc.write);
                         enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
 
+                    } else if let Some(s) = self.0.config.services.iter().find(|s| {
+                        !s.authenticated && s.name.as_bytes() == request
+                    }) {
+
+                        push_packet!(enc.write, {
+                            enc.write.push(msg::SERVICE_ACCEPT);
+                            enc.write.extend_ssh_string(s.name.as_bytes());
+                        });
+                        enc.state = Some(EncryptedState::Service {
+                            name: s.name.clone(),
+                            authenticated: false,
+                        });
+
                     } else {
 
                         enc.state = Some(EncryptedState::WaitingServiceRequest)
@@ -86,6 +108,12 @@ impl Session {
                                                           buffer,
                                                           &mut self.0.auth_user,
                                                           auth_request));
+                    } else if buf[0] == msg::USERAUTH_INFO_RESPONSE {
+                        try!(enc.server_read_info_response(self.0.config.as_ref(),
+                                                           server,
+                                                           buf,
+                                                           &mut self.0.auth_user,
+                                                           auth_request));
                     } else {
                         // Wrong request
                         enc.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
@@ -95,15 +123,32 @@ impl Session {
                     is_authenticated = true;
                     enc.state = Some(EncryptedState::Authenticated)
                 }
+                Some(EncryptedState::Service { name, authenticated }) => {
+                    service = Some(name.clone());
+                    enc.state = Some(EncryptedState::Service {
+                        name: name,
+                        authenticated: authenticated,
+                    })
+                }
                 state => {
                     enc.state = state;
                 }
             }
         }
-        if is_authenticated {
+        if self.too_many_auth_failures() {
+            self.auth_limit_exceeded(server, auth::Limit::MaxAuthAttempts);
+            Ok(())
+        } else if is_authenticated {
             self.server_read_authenticated(server, buf)
+        } else if let Some(service) = service {
+            if buf[0] >= msg::USERAUTH_REQUEST {
+                server.service_message(&service, buf, self)
+            } else {
+                info!("Unhandled packet: {:?}", buf);
+                Ok(())
+            }
         } else {
-            Ok(())
+            self.check_service_started(server, false)
         }
     }
 
@@ -428,122 +473,522 @@ impl Encrypted {
                std::str::from_utf8(method));
 
         let t0 = std::time::Instant::now();
+        // Requests received while a decision is pending are answered
+        // after it, in order (RFC4252, section 5).
+        if let Some(CurrentRequest::Pending { .. }) = auth_request.current {
+            if auth_request.queued.len() >= config.max_auth_attempts {
+                return Err(Error::Inconsistent);
+            }
+            auth_request.queued.push_back(buf.to_vec());
+            self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+            return Ok(());
+        }
+        // A new request cancels any keyboard-interactive exchange
+        // in progress (RFC4256, section 3.4).
+        auth_request.current = None;
         if service_name == b"ssh-connection" {
+            auth_request.service = None
+        } else if let Some(s) = config.services.iter().find(|s| {
+            s.authenticated && s.name.as_bytes() == service_name
+        }) {
+            auth_request.service = Some(s.name.clone())
+        } else {
+            // Unknown service
+            return Err(Error::Inconsistent);
+        }
+        // After a partial success, the next methods must be for the
+        // same user.
+        if let Some(ref partial_user) = auth_request.partial_user {
+            if partial_user != user {
+                return Err(Error::Inconsistent);
+            }
+        }
+        // Each new user may get a different banner, sent before the
+        // answer to this request.
+        if auth_request.banner_user.as_ref().map(|u| u.as_str()) != Some(user) {
+            if let Some(banner) = server.auth_banner(Some(user)) {
+                push_auth_banner(&mut self.write, &banner)
+            }
+            auth_request.banner_user = Some(user.to_string())
+        }
+        // Only the methods that can continue are allowed. Only
+        // passwords, signatures and keyboard-interactive responses
+        // count as failures.
+        match MethodSet::from_bytes(method) {
+            Some(m) if auth_request.methods.contains(m) => {}
+            _ => {
+                auth_user.clear();
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, false, auth_request);
+                return Ok(());
+            }
+        }
+
+        if method == b"none" {
 
-            if method == b"password" {
+            auth_user.clear();
+            auth_user.push_str(user);
 
+            let result = server.auth_none(user);
+            self.server_auth_result(config, t0, auth_user, result, RequestKind::None, auth_request);
+
+        } else if method == b"password" {
+
+            auth_user.clear();
+            auth_user.push_str(user);
+
+            let is_change = try!(r.read_byte()) != 0;
+            let password = try!(r.read_string());
+            let password = try!(std::str::from_utf8(password));
+
+            let reply = if is_change {
+                // https://tools.ietf.org/html/rfc4252#section-8
+                let new_password = try!(std::str::from_utf8(try!(r.read_string())));
+                server.auth_password_change(user, password, new_password)
+            } else {
+                server.auth_password(user, password)
+            };
+            self.server_reply_password(config, t0, auth_user, reply, auth_request);
+
+        } else if method == b"publickey" {
+
+            let is_real = try!(r.read_byte());
+            let pubkey_algo = try!(r.read_string());
+            let pubkey_key = try!(r.read_string());
+            if !is_accepted_algorithm(config, pubkey_algo) {
+                debug!("public key algorithm not accepted");
                 auth_user.clear();
-                auth_user.push_str(user);
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, is_real != 0, auth_request);
+                return Ok(());
+            }
+            let pubkey = try!(key::PublicKey::parse(pubkey_algo, pubkey_key));
+            debug!("is_real = {:?}", is_real);
 
-                try!(r.read_byte());
-                let password = try!(r.read_string());
-                let password = try!(std::str::from_utf8(password));
+            if is_real != 0 {
 
-                if server.auth_password(user, password) {
-                    server_auth_request_success(&mut self.write);
-                    self.state = Some(EncryptedState::Authenticated);
-                } else {
+                let pos0 = r.position;
 
+                let signature = try!(r.read_string());
+                let mut s = signature.reader(0);
+                let sig_algo = try!(s.read_string());
+                let sig = try!(s.read_string());
+
+                buffer.clear();
+                buffer.extend_ssh_string(&self.session_id);
+                buffer.extend(&buf[0..pos0]);
+                // Verify signature.
+                if sig_algo == pubkey_algo && pubkey.verify_signature(pubkey_algo, &buffer, sig) {
+                    debug!("signature verified");
+                    auth_user.clear();
+                    auth_user.push_str(user);
+                    // The handler is asked again, since this key
+                    // might not be the one of the probe.
+                    let result = server.auth_publickey(user, &pubkey);
+                    self.server_auth_result(config,
+                                            t0,
+                                            auth_user,
+                                            result,
+                                            RequestKind::PublicKey,
+                                            auth_request);
+                } else {
+                    debug!("wrong signature");
                     auth_user.clear();
-                    auth_request.methods = auth_request.methods - auth::PASSWORD;
                     auth_request.partial_success = false;
-                    self.reject_auth_request(config, t0, auth_request);
+                    self.reject_auth_request(config, t0, true, auth_request);
                 }
 
-            } else if method == b"publickey" {
+            } else {
 
-                let is_real = try!(r.read_byte());
-                let pubkey_algo = try!(r.read_string());
-                let pubkey_key = try!(r.read_string());
-                let pubkey = try!(key::PublicKey::parse(pubkey_algo, pubkey_key));
-                debug!("is_real = {:?}", is_real);
+                let result = server.auth_publickey(user, &pubkey);
+                auth_user.clear();
+                auth_user.push_str(user);
+                auth_request.public_key.clear();
+                auth_request.public_key.extend(pubkey_key);
+                auth_request.public_key_algorithm.clear();
+                auth_request.public_key_algorithm.extend(pubkey_algo);
+                self.server_reply_probe(config, t0, auth_user, result, auth_request);
+            }
+        } else if method == b"hostbased" {
 
-                if is_real != 0 {
+            // https://tools.ietf.org/html/rfc4252#section-9
+            let pubkey_algo = try!(r.read_string());
+            let pubkey_key = try!(r.read_string());
+            let host_name = try!(std::str::from_utf8(try!(r.read_string())));
+            let client_user = try!(std::str::from_utf8(try!(r.read_string())));
+            if !is_accepted_algorithm(config, pubkey_algo) {
+                debug!("public key algorithm not accepted");
+                auth_user.clear();
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, true, auth_request);
+                return Ok(());
+            }
+            let pubkey = try!(key::PublicKey::parse(pubkey_algo, pubkey_key));
+            let pos0 = r.position;
 
-                    let pos0 = r.position;
+            let signature = try!(r.read_string());
+            let mut s = signature.reader(0);
+            let sig_algo = try!(s.read_string());
+            let sig = try!(s.read_string());
 
-                    let t0 = std::time::Instant::now();
+            buffer.clear();
+            buffer.extend_ssh_string(&self.session_id);
+            buffer.extend(&buf[0..pos0]);
+            if sig_algo == pubkey_algo && pubkey.verify_signature(pubkey_algo, &buffer, sig) {
+                debug!("signature verified");
+                auth_user.clear();
+                auth_user.push_str(user);
+                let result = server.auth_hostbased(user, host_name, client_user, &pubkey);
+                self.server_auth_result(config,
+                                        t0,
+                                        auth_user,
+                                        result,
+                                        RequestKind::Hostbased,
+                                        auth_request);
+            } else {
+                debug!("wrong signature");
+                auth_user.clear();
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, true, auth_request);
+            }
 
-                    if (auth_request.sent_pk_ok && user == auth_user) || (auth_user.len() == 0 && server.auth_publickey(user, &pubkey)) {
+        } else if method == b"keyboard-interactive" {
 
-                        let signature = try!(r.read_string());
-                        let mut s = signature.reader(0);
-                        // let algo_ =
-                        try!(s.read_string());
-                        let sig = try!(s.read_string());
+            auth_user.clear();
+            auth_user.push_str(user);
 
-                        buffer.clear();
-                        buffer.extend_ssh_string(&self.session_id);
-                        buffer.extend(&buf[0..pos0]);
-                        // Verify signature.
-                        if pubkey.verify_detached(&buffer, sig) {
-                            debug!("signature verified");
-                            server_auth_request_success(&mut self.write);
-                            self.state = Some(EncryptedState::Authenticated);
-                        } else {
-                            debug!("wrong signature");
-                            auth_user.clear();
-                            self.reject_auth_request(config, t0, auth_request);
-                        }
-                    } else {
-                        debug!("rejected");
-                        auth_user.clear();
-                        self.reject_auth_request(config, t0, auth_request)
-                    }
+            try!(r.read_string()); // language tag, deprecated.
+            let submethods = try!(std::str::from_utf8(try!(r.read_string())));
+            let reply = server.auth_keyboard_interactive(user, submethods, None);
+            self.server_reply_keyboard_interactive(config,
+                                                   t0,
+                                                   auth_user,
+                                                   submethods,
+                                                   reply,
+                                                   RequestKind::KeyboardInteractive,
+                                                   auth_request);
+
+        } else {
+            // Unknown methods are rejected by the check above.
+            auth_request.partial_success = false;
+            self.reject_auth_request(config, t0, false, auth_request);
+        }
+        Ok(())
+    }
 
+    /// Answer an authentication request of kind `kind` according to
+    /// the handler's decision.
+    fn server_auth_result(&mut self,
+                          config: &Config,
+                          t0: std::time::Instant,
+                          auth_user: &mut String,
+                          result: Auth,
+                          kind: RequestKind,
+                          mut auth_request: AuthRequest) {
+        match result {
+            Auth::Accept => {
+                server_auth_request_success(&mut self.write);
+                self.state = Some(if let Some(name) = auth_request.service {
+                    EncryptedState::Service {
+                        name: name,
+                        authenticated: true,
+                    }
                 } else {
+                    EncryptedState::Authenticated
+                });
+            }
+            Auth::Partial { methods } => {
+                // https://tools.ietf.org/html/rfc4252#section-5.1
+                auth_request.methods = methods;
+                auth_request.partial_success = true;
+                auth_request.partial_user = Some(auth_user.clone());
+                auth_request.sent_pk_ok = false;
+                server_auth_request_failure(&mut self.write, &auth_request);
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+            }
+            Auth::Reject => {
+                auth_user.clear();
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, kind.is_attempt(), auth_request);
+            }
+            Auth::Pending => {
+                auth_request.current = Some(CurrentRequest::Pending {
+                    t0: t0,
+                    request: kind,
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+            }
+        }
+    }
 
-                    let t0 = std::time::Instant::now();
-                    if server.auth_publickey(user, &pubkey) {
+    /// Answer a public key probe, with `SSH_MSG_USERAUTH_PK_OK` unless
+    /// the key is rejected.
+    fn server_reply_probe(&mut self,
+                          config: &Config,
+                          t0: std::time::Instant,
+                          auth_user: &mut String,
+                          result: Auth,
+                          mut auth_request: AuthRequest) {
+        match result {
+            Auth::Reject => {
+                auth_user.clear();
+                auth_request.partial_success = false;
+                self.reject_auth_request(config, t0, false, auth_request);
+            }
+            Auth::Pending => {
+                auth_request.current = Some(CurrentRequest::Pending {
+                    t0: t0,
+                    request: RequestKind::PublicKeyProbe,
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+            }
+            _ => {
+                server_send_pk_ok(&mut self.write, &mut auth_request);
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request))
+            }
+        }
+    }
 
-                        auth_user.clear();
-                        auth_user.push_str(user);
-                        auth_request.public_key.extend(pubkey_key);
-                        auth_request.public_key_algorithm.extend(pubkey_algo);
-                        server_send_pk_ok(&mut self.write, &mut auth_request);
-                        self.state = Some(EncryptedState::WaitingAuthRequest(auth_request))
-                    } else {
-                        auth_request.methods -= auth::PUBLICKEY;
-                        auth_request.partial_success = false;
-                        auth_user.clear();
-                        self.reject_auth_request(config, t0, auth_request);
-                    }
+    /// Complete a request for which the handler returned `Pending`,
+    /// if it is of one of the `kinds`.
+    pub fn server_complete_auth(&mut self,
+                                config: &Config,
+                                auth_user: &mut String,
+                                kinds: &[RequestKind],
+                                result: auth::Password)
+                                -> Result<(), Error> {
+        let mut auth_request = match self.state.take() {
+            Some(EncryptedState::WaitingAuthRequest(auth_request)) => auth_request,
+            state => {
+                self.state = state;
+                return Err(Error::Inconsistent);
+            }
+        };
+        let (t0, request) = match auth_request.current.take() {
+            Some(CurrentRequest::Pending { t0, request }) if kinds.contains(&request) => {
+                (t0, request)
+            }
+            current => {
+                auth_request.current = current;
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return Err(Error::Inconsistent);
+            }
+        };
+        if request == RequestKind::Password {
+            self.server_reply_password(config, t0, auth_user, result, auth_request);
+            return Ok(());
+        }
+        let result = match result {
+            auth::Password::Accept => Auth::Accept,
+            auth::Password::Reject => Auth::Reject,
+            auth::Password::Partial { methods } => Auth::Partial { methods: methods },
+            auth::Password::Pending => Auth::Pending,
+            auth::Password::ChangeRequest { .. } => {
+                // Only password requests can be answered with this.
+                auth_request.current = Some(CurrentRequest::Pending {
+                    t0: t0,
+                    request: request,
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return Err(Error::Inconsistent);
+            }
+        };
+        if request == RequestKind::PublicKeyProbe {
+            self.server_reply_probe(config, t0, auth_user, result, auth_request)
+        } else {
+            self.server_auth_result(config, t0, auth_user, result, request, auth_request)
+        }
+        Ok(())
+    }
+
+    /// Read the requests queued while a decision was pending, until
+    /// one of them is pending too.
+    pub fn server_read_queued_requests<S: Handler>(&mut self,
+                                                   config: &Config,
+                                                   server: &mut S,
+                                                   auth_user: &mut String)
+                                                   -> Result<(), Error> {
+        let mut buffer = CryptoBuf::new();
+        loop {
+            let mut auth_request = match self.state.take() {
+                Some(EncryptedState::WaitingAuthRequest(auth_request)) => auth_request,
+                state => {
+                    // After a success, the queued requests are ignored.
+                    self.state = state;
+                    return Ok(());
                 }
+            };
+            let is_pending = match auth_request.current {
+                Some(CurrentRequest::Pending { .. }) => true,
+                _ => false,
+            };
+            let next = if is_pending || auth_request.failures >= config.max_auth_attempts {
+                None
             } else {
-                // Other methods of the base specification are insecure or optional.
-                self.reject_auth_request(config, t0, auth_request);
+                auth_request.queued.pop_front()
+            };
+            if let Some(buf) = next {
+                try!(self.server_read_auth_request(config,
+                                                   server,
+                                                   &buf,
+                                                   &mut buffer,
+                                                   auth_user,
+                                                   auth_request))
+            } else {
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return Ok(());
             }
-            Ok(())
-        } else {
-            // Unknown service
-            Err(Error::Inconsistent)
         }
     }
 
-    fn reject_auth_request(&mut self, config:&Config, t0:std::time::Instant, mut auth_request: AuthRequest) {
+    /// Read the answers to the last prompts sent during a
+    /// keyboard-interactive request.
+    pub fn server_read_info_response<S: Handler>(&mut self,
+                                                 config: &Config,
+                                                 server: &mut S,
+                                                 buf: &[u8],
+                                                 auth_user: &mut String,
+                                                 mut auth_request: AuthRequest)
+                                                 -> Result<(), Error> {
+        let t0 = std::time::Instant::now();
+        let (submethods, n_prompts) = match auth_request.current.take() {
+            Some(CurrentRequest::KeyboardInteractive { submethods, n_prompts }) => {
+                (submethods, n_prompts)
+            }
+            _ => return Err(Error::Inconsistent),
+        };
+        // https://tools.ietf.org/html/rfc4256#section-3.4
+        let mut r = buf.reader(1);
+        let n_responses = try!(r.read_u32()) as usize;
+        if n_responses != n_prompts {
+            auth_user.clear();
+            auth_request.partial_success = false;
+            self.reject_auth_request(config, t0, true, auth_request);
+            return Ok(());
+        }
+        let mut responses = Vec::with_capacity(n_responses);
+        for _ in 0..n_responses {
+            responses.push(try!(std::str::from_utf8(try!(r.read_string()))))
+        }
+        let user = auth_user.clone();
+        let reply = server.auth_keyboard_interactive(&user, &submethods, Some(&responses));
+        self.server_reply_keyboard_interactive(config,
+                                               t0,
+                                               auth_user,
+                                               &submethods,
+                                               reply,
+                                               RequestKind::KeyboardInteractiveResponse,
+                                               auth_request);
+        Ok(())
+    }
+
+    fn server_reply_password(&mut self,
+                             config: &Config,
+                             t0: std::time::Instant,
+                             auth_user: &mut String,
+                             reply: auth::Password,
+                             mut auth_request: AuthRequest) {
+        let result = match reply {
+            auth::Password::Accept => Auth::Accept,
+            auth::Password::Reject => Auth::Reject,
+            auth::Password::Partial { methods } => Auth::Partial { methods: methods },
+            auth::Password::Pending => {
+                auth_request.current = Some(CurrentRequest::Pending {
+                    t0: t0,
+                    request: RequestKind::Password,
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return;
+            }
+            auth::Password::ChangeRequest { prompt } => {
+                push_packet!(self.write, {
+                    self.write.push(msg::USERAUTH_PASSWD_CHANGEREQ);
+                    self.write.extend_ssh_string(prompt.as_bytes());
+                    self.write.extend_ssh_string(b""); // language tag, deprecated.
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return;
+            }
+        };
+        self.server_auth_result(config,
+                                t0,
+                                auth_user,
+                                result,
+                                RequestKind::Password,
+                                auth_request)
+    }
+
+    fn server_reply_keyboard_interactive(&mut self,
+                                         config: &Config,
+                                         t0: std::time::Instant,
+                                         auth_user: &mut String,
+                                         submethods: &str,
+                                         reply: KeyboardInteractive,
+                                         kind: RequestKind,
+                                         mut auth_request: AuthRequest) {
+        let result = match reply {
+            KeyboardInteractive::Accept => Auth::Accept,
+            KeyboardInteractive::Reject => Auth::Reject,
+            KeyboardInteractive::Partial { methods } => Auth::Partial { methods: methods },
+            KeyboardInteractive::Prompts { name, instructions, prompts } => {
+                push_packet!(self.write, {
+                    self.write.push(msg::USERAUTH_INFO_REQUEST);
+                    self.write.extend_ssh_string(name.as_bytes());
+                    self.write.extend_ssh_string(instructions.as_bytes());
+                    self.write.extend_ssh_string(b""); // language tag, deprecated.
+                    self.write.push_u32_be(prompts.len() as u32);
+                    for prompt in prompts.iter() {
+                        self.write.extend_ssh_string(prompt.prompt.as_bytes());
+                        self.write.push(if prompt.echo { 1 } else { 0 });
+                    }
+                });
+                auth_request.current = Some(CurrentRequest::KeyboardInteractive {
+                    submethods: submethods.to_string(),
+                    n_prompts: prompts.len(),
+                });
+                self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
+                return;
+            }
+        };
+        self.server_auth_result(config, t0, auth_user, result, kind, auth_request)
+    }
+
+    /// Reject the current request, counting it as a failure if
+    /// `is_attempt` is true.
+    fn reject_auth_request(&mut self,
+                           config: &Config,
+                           t0: std::time::Instant,
+                           is_attempt: bool,
+                           mut auth_request: AuthRequest) {
 
         debug!("rejecting {:?}", auth_request);
-        push_packet!(self.write, {
-            self.write.push(msg::USERAUTH_FAILURE);
-            self.write.extend_list(auth_request.methods);
-            self.write.push(if auth_request.partial_success {
-                1
-            } else {
-                0
-            });
-        });
+        if is_attempt {
+            auth_request.failures += 1;
+        }
+        // After the last failure, the client is disconnected instead.
+        if auth_request.failures < config.max_auth_attempts {
+            server_auth_request_failure(&mut self.write, &auth_request);
+        }
         auth_request.sent_pk_ok = false;
         debug!("packet pushed");
         self.state = Some(EncryptedState::WaitingAuthRequest(auth_request));
-        let t1 = std::time::Instant::now();
-        let dur = t1.duration_since(t0);
-        if dur < config.auth_rejection_time {
-            std::thread::sleep(config.auth_rejection_time - dur);
-        }
+        // The rejection is held back by `Connection::write`, along
+        // with any earlier one still pending.
+        let deadline = t0 + config.auth_rejection_time;
+        self.write_deadline = Some(match self.write_deadline {
+            Some(d) if d > deadline => d,
+            _ => deadline,
+        });
     }
 }
 
 
+/// Whether `algo` is one of the public key algorithms accepted for
+/// authentication.
+fn is_accepted_algorithm(config: &Config, algo: &[u8]) -> bool {
+    config.auth_key_algorithms.iter().any(|a| a.as_ref().as_bytes() == algo)
+}
+
 fn server_accept_service(banner: Option<&str>,
                          methods: auth::MethodSet,
                          buffer: &mut CryptoBuf)
@@ -554,12 +999,8 @@ fn server_accept_service(banner: Option<&str>,
         buffer.extend_ssh_string(b"ssh-userauth");
     });
 
-    if let Some(ref banner) = banner {
-        push_packet!(buffer, {
-            buffer.push(msg::USERAUTH_BANNER);
-            buffer.extend_ssh_string(banner.as_bytes());
-            buffer.extend_ssh_string(b"");
-        })
+    if let Some(banner) = banner {
+        push_auth_banner(buffer, banner)
     }
 
     AuthRequest {
@@ -569,10 +1010,25 @@ fn server_accept_service(banner: Option<&str>,
         public_key_algorithm: CryptoBuf::new(),
         sent_pk_ok: false,
         public_key_is_ok: false,
+        partial_user: None,
+        current: None,
+        failures: 0,
+        banner_user: None,
+        service: None,
+        queued: VecDeque::new(),
     }
 }
 
 
+pub fn push_auth_banner(buffer: &mut CryptoBuf, banner: &str) {
+    // https://tools.ietf.org/html/rfc4252#section-5.4
+    push_packet!(buffer, {
+        buffer.push(msg::USERAUTH_BANNER);
+        buffer.extend_ssh_string(banner.as_bytes());
+        buffer.extend_ssh_string(b"");
+    })
+}
+
 fn server_auth_request_success(buffer: &mut CryptoBuf) {
 
     push_packet!(buffer, {
@@ -580,6 +1036,20 @@ fn server_auth_request_success(buffer: &mut CryptoBuf) {
     })
 }
 
+fn server_auth_request_failure(buffer: &mut CryptoBuf, auth_request: &AuthRequest) {
+    push_packet!(buffer, {
+        buffer.push(msg::USERAUTH_FAILURE);
+        // "none" must not be listed
+        // (https://tools.ietf.org/html/rfc4252#section-5.2).
+        buffer.extend_list(auth_request.methods - auth::NONE);
+        buffer.push(if auth_request.partial_success {
+            1
+        } else {
+            0
+        });
+    })
+}
+
 fn server_send_pk_ok(buffer: &mut CryptoBuf, auth_request: &mut AuthRequest) {
     push_packet!(buffer, {
         buffer.push(msg::USERAUTH_PK_OK);
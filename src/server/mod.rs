@@ -15,6 +15,8 @@
 use std::io::{Write, BufRead};
 use std;
 use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use byteorder::ByteOrder;
 use rand;
 use rand::Rng;
@@ -44,7 +46,16 @@ pub struct Config {
     /// The authentication banner, usually a warning message shown to the client.
     pub auth_banner: Option<&'static str>,
     /// Authentication rejections must happen in constant time for security reasons.
+    /// They are held back by `Connection::write`, see `Connection::write_deadline`.
     pub auth_rejection_time: std::time::Duration,
+    /// Maximal number of failed authentication attempts before
+    /// disconnecting the client. Only rejected passwords, signed
+    /// public key and hostbased requests, and keyboard-interactive
+    /// responses are counted.
+    pub max_auth_attempts: usize,
+    /// Maximal time between the creation of a connection and its
+    /// authentication, see `Connection::login_deadline`.
+    pub login_grace_time: std::time::Duration,
     /// The server's keys. The first key pair in the client's preference order will be chosen.
     pub keys: Vec<key::Algorithm>,
     /// The bytes and time limits before key re-exchange.
@@ -55,6 +66,13 @@ pub struct Config {
     pub maximum_packet_size: u32,
     /// Lists of preferred algorithms.
     pub preferred: Preferred,
+    /// Public key algorithms accepted for "publickey" and "hostbased"
+    /// authentication, advertised to clients in `server-sig-algs`
+    /// ([RFC8308](https://tools.ietf.org/html/rfc8308)).
+    pub auth_key_algorithms: Cow<'static, [key::Name]>,
+    /// Custom services that clients may request, in addition to the
+    /// standard "ssh-connection" service.
+    pub services: Vec<Service>,
 }
 
 impl Default for Config {
@@ -66,19 +84,42 @@ impl Default for Config {
             methods: auth::MethodSet::all(),
             auth_banner: None,
             auth_rejection_time: std::time::Duration::from_secs(1),
+            max_auth_attempts: 6,
+            login_grace_time: std::time::Duration::from_secs(120),
             keys: Vec::new(),
             window_size: 100,
             maximum_packet_size: 100,
             limits: Limits::default(),
             preferred: Default::default(),
+            auth_key_algorithms: Cow::Borrowed(key::AUTH_DEFAULT),
+            services: Vec::new(),
         }
     }
 }
 
+impl Config {
+    /// The preferred algorithms, where the host key algorithms are
+    /// restricted to those of `self.keys` that can sign, so that
+    /// clients never choose an algorithm for which we have no key.
+    fn kex_preferred(&self) -> Preferred {
+        let mut preferred = self.preferred.clone();
+        preferred.key = Cow::Owned(self.preferred
+            .key
+            .iter()
+            .filter(|name| {
+                !name.is_verify_only() && self.keys.iter().any(|key| key.can_sign(**name))
+            })
+            .cloned()
+            .collect());
+        preferred
+    }
+}
+
 #[derive(Debug)]
 pub struct Connection {
     read_buffer: SSHBuffer,
     session: Session,
+    login_deadline: std::time::Instant,
 }
 
 
@@ -99,31 +140,102 @@ impl std::ops::DerefMut for Connection {
 }
 
 pub trait Handler {
+    /// The banner to send before the first request of `user`, or of
+    /// the client if `user` is `None`, where `None` sends
+    /// `config.auth_banner`.
+    #[allow(unused_variables)]
+    fn auth_banner(&mut self, user: Option<&str>) -> Option<Cow<'static, str>> {
+        None
+    }
 
     /// Check authentication using the "none" method. Thrussh makes
     /// sure rejection happens in time `config.auth_rejection_time`,
     /// except if this method takes more than that.
     #[allow(unused_variables)]
-    fn auth_none(&mut self, user: &str) -> bool {
-        false
+    fn auth_none(&mut self, user: &str) -> auth::Auth {
+        auth::Auth::Reject
     }
 
     /// Check authentication using the "password" method. Thrussh
     /// makes sure rejection happens in time
     /// `config.auth_rejection_time`, except if this method takes more
-    /// than that.
+    /// than that. `ChangeRequest` asks the client for a new password.
     #[allow(unused_variables)]
-    fn auth_password(&mut self, user: &str, password: &str) -> bool {
-        false
+    fn auth_password(&mut self, user: &str, password: &str) -> auth::Password {
+        auth::Password::Reject
     }
 
-    /// Check authentication using the "publickey" method. Thrussh
-    /// makes sure rejection happens in time
+    /// Change the password of `user`, after a `ChangeRequest` or on
+    /// the client's initiative. `old_password` must be checked, and
+    /// returning `ChangeRequest` again refuses `new_password`.
+    #[allow(unused_variables)]
+    fn auth_password_change(&mut self,
+                            user: &str,
+                            old_password: &str,
+                            new_password: &str)
+                            -> auth::Password {
+        auth::Password::Reject
+    }
+
+    /// Check authentication using the "publickey" method, both when
+    /// the client asks whether a key is acceptable and when it signs
+    /// with that key. Thrussh makes sure rejection happens in time
     /// `config.auth_rejection_time`, except if this method takes more
     /// than that.
     #[allow(unused_variables)]
-    fn auth_publickey(&mut self, user: &str, public_key: &key::PublicKey) -> bool {
-        false
+    fn auth_publickey(&mut self, user: &str, public_key: &key::PublicKey) -> auth::Auth {
+        auth::Auth::Reject
+    }
+
+    /// Check authentication using the "hostbased" method: whether
+    /// `public_key` is the key of `host_name` (with a trailing dot
+    /// from OpenSSH), and whether its user `client_user` may log in
+    /// as `user`.
+    #[allow(unused_variables)]
+    fn auth_hostbased(&mut self,
+                      user: &str,
+                      host_name: &str,
+                      client_user: &str,
+                      public_key: &key::PublicKey)
+                      -> auth::Auth {
+        auth::Auth::Reject
+    }
+
+    /// Called when a client is disconnected for reaching one of the
+    /// authentication limits of the configuration, for instance to
+    /// block its address.
+    #[allow(unused_variables)]
+    fn auth_limit_exceeded(&mut self, limit: auth::Limit) {}
+
+    /// Called when a custom service starts.
+    #[allow(unused_variables)]
+    fn service_started(&mut self, service: &str, session: &mut Session) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called on each message of a custom service, including its
+    /// message number.
+    #[allow(unused_variables)]
+    fn service_message(&mut self,
+                       service: &str,
+                       message: &[u8],
+                       session: &mut Session)
+                       -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Check authentication using the "keyboard-interactive" method,
+    /// first with `response` equal to `None`, then with the answers
+    /// to the previous prompts. Thrussh makes sure rejection happens
+    /// in time `config.auth_rejection_time`, except if this method
+    /// takes more than that.
+    #[allow(unused_variables)]
+    fn auth_keyboard_interactive(&mut self,
+                                 user: &str,
+                                 submethods: &str,
+                                 response: Option<&[&str]>)
+                                 -> auth::KeyboardInteractive {
+        auth::KeyboardInteractive::Reject
     }
 
 
@@ -314,7 +426,7 @@ impl KexInit {
             let algo = if self.algo.is_none() {
                 // read algorithms from packet.
                 self.exchange.client_kex_init.extend(buf);
-                try!(super::negociation::Server::read_kex(buf, &config.preferred))
+                try!(super::negociation::Server::read_kex(buf, &config.kex_preferred()))
             } else {
                 return Err(Error::Kex);
             };
@@ -322,7 +434,7 @@ impl KexInit {
                 self.server_write(config, cipher, write_buffer)
             }
             let mut key = 0;
-            while key < config.keys.len() && config.keys[key].name() != algo.key.as_ref() {
+            while key < config.keys.len() && !config.keys[key].can_sign(algo.key) {
                 key += 1
             }
             let next_kex = if key < config.keys.len() {
@@ -347,7 +459,10 @@ impl KexInit {
                                         cipher: &mut C,
                                         write_buffer: &mut SSHBuffer) {
         self.exchange.server_kex_init.clear();
-        negociation::write_kex(&config.preferred, &mut self.exchange.server_kex_init);
+        negociation::Server::write_kex(&config.kex_preferred(),
+                                       &mut self.exchange.server_kex_init,
+                                       self.session_id.is_none(),
+                                       false);
         self.sent = true;
         cipher.write(&self.exchange.server_kex_init, write_buffer)
     }
@@ -396,7 +511,7 @@ impl KexDh {
             // Server ephemeral
             buffer.extend_ssh_string(&kexdhdone.exchange.server_ephemeral);
             // Hash signature
-            config.keys[kexdhdone.key].add_signature(buffer, &hash);
+            config.keys[kexdhdone.key].add_signature(kexdhdone.names.key, buffer, &hash);
             cipher.write(&buffer, write_buffer);
 
             cipher.write(&[msg::NEWKEYS], write_buffer);
@@ -416,18 +531,22 @@ impl Connection {
         let mut write_buffer = SSHBuffer::new();
         write_buffer.send_ssh_id(config.as_ref().server_id.as_bytes());
 
+        let login_deadline = std::time::Instant::now() + config.login_grace_time;
         let session = Connection {
             read_buffer: SSHBuffer::new(),
+            login_deadline: login_deadline,
             session: Session(CommonSession {
                 write_buffer: write_buffer,
                 kex: None,
                 auth_user: String::new(),
                 auth_method: None, // Client only.
+                auth_chain: VecDeque::new(), // Client only.
                 cipher: cipher::CLEAR_PAIR,
                 encrypted: None,
                 config: config,
                 wants_reply: false,
                 disconnected: false,
+                strict_kex: false,
             }),
         };
         session
@@ -435,15 +554,30 @@ impl Connection {
 
     /// Process all packets available in the buffer, and returns
     /// whether at least one complete packet was read. `buffer` and `buffer2` are work spaces mostly used to compute keys. They are cleared before using, hence nothing is expected from them.
+    ///
+    /// Once the session is disconnected, for instance after too many
+    /// authentication failures, this returns `Error::Disconnect`. The
+    /// disconnection message might still be held back by `write`
+    /// until `write_deadline`.
     pub fn read<R: BufRead, S: Handler>(&mut self,
                                         server: &mut S,
                                         stream: &mut R,
                                         buffer: &mut CryptoBuf,
                                         buffer2: &mut CryptoBuf)
                                         -> Result<bool, Error> {
+        if self.session.0.disconnected {
+            return Err(Error::Disconnect);
+        }
+        if let Some(deadline) = self.login_deadline() {
+            if std::time::Instant::now() >= deadline {
+                self.session.auth_limit_exceeded(server, auth::Limit::LoginGraceTime);
+                return Ok(false);
+            }
+        }
         let mut at_least_one_was_read = false;
         loop {
             match self.read_one_packet(server, stream, buffer, buffer2) {
+                Ok(true) if self.session.0.disconnected => return Ok(true),
                 Ok(true) => at_least_one_was_read = true,
                 Ok(false) => return Ok(at_least_one_was_read),
                 Err(Error::IO(e)) => {
@@ -487,6 +621,7 @@ impl Connection {
                 algo: None,
                 sent: false,
                 session_id: None,
+                guessed: None,
             };
             kexinit.server_write(self.session.0.config.as_ref(),
                                  &mut self.session.0.cipher,
@@ -506,6 +641,7 @@ impl Connection {
                 // transport
                 return Err(Error::Disconnect);
             }
+            try!(self.session.0.check_strict_kex(buf[0]));
             if buf[0] <= 4 {
                 return Ok(true);
             }
@@ -522,6 +658,10 @@ impl Connection {
                                                             &mut self.session.0.write_buffer);
                         match next_kex {
                             Ok(next_kex) => {
+                                if let Kex::KexDh(ref kexdh) = next_kex {
+                                    try!(self.session.0.set_strict_kex(&kexdh.names,
+                                                                       self.read_buffer.seqn));
+                                }
                                 self.session.0.kex = Some(next_kex);
                                 return Ok(true);
                             }
@@ -542,6 +682,12 @@ impl Connection {
                                                &mut self.session.0.write_buffer);
                     match next_kex {
                         Ok(next_kex) => {
+                            if let Kex::NewKeys(_) = next_kex {
+                                // We've just sent NEWKEYS.
+                                if self.session.0.strict_kex {
+                                    self.session.0.write_buffer.seqn = 0
+                                }
+                            }
                             self.session.0.kex = Some(next_kex);
                             return Ok(true);
                         }
@@ -553,8 +699,19 @@ impl Connection {
                     if buf[0] != msg::NEWKEYS {
                         return Err(Error::NewKeys);
                     }
+                    if self.session.0.strict_kex {
+                        self.read_buffer.seqn = 0
+                    }
+                    let send_ext_info = self.session.0.encrypted.is_none() && newkeys.names.ext_info;
                     // Ok, NEWKEYS received, now encrypted.
                     self.session.0.encrypted(EncryptedState::WaitingServiceRequest, newkeys);
+                    if send_ext_info {
+                        // This must be the first packet after our NEWKEYS.
+                        buffer.clear();
+                        negociation::write_server_ext_info(&self.session.0.config.auth_key_algorithms,
+                                                           buffer);
+                        self.session.0.cipher.write(&buffer, &mut self.session.0.write_buffer);
+                    }
                     return Ok(true);
                 }
                 Some(kex) => {
@@ -571,9 +728,46 @@ impl Connection {
     }
 
     /// Write all computed packets to the stream. Returns whether all packets have been sent.
+    ///
+    /// After an authentication rejection, nothing is written before
+    /// `write_deadline`, and this returns `false`.
     pub fn write<W: Write>(&mut self, stream: &mut W) -> Result<bool, Error> {
+        if let Some(ref mut enc) = self.session.0.encrypted {
+            if let Some(deadline) = enc.write_deadline {
+                if std::time::Instant::now() < deadline {
+                    return Ok(false);
+                }
+                enc.write_deadline = None
+            }
+        }
         self.session.0.write_buffer.write_all(stream)
     }
+
+    /// The time before which `write` holds packets back, so that
+    /// authentication rejections take at least
+    /// `config.auth_rejection_time`. Event loops must poll this after
+    /// each `read`, and call `write` again at that time, or the
+    /// rejection is never sent.
+    pub fn write_deadline(&self) -> Option<std::time::Instant> {
+        if let Some(ref enc) = self.session.0.encrypted {
+            enc.write_deadline
+        } else {
+            None
+        }
+    }
+
+    /// The time at which `read` disconnects the client if it is not
+    /// authenticated yet (see `config.login_grace_time`), or `None`
+    /// after authentication. Event loops should call `read` at that
+    /// time, even if nothing was received.
+    pub fn login_deadline(&self) -> Option<std::time::Instant> {
+        if self.session.0.disconnected || self.session.is_authenticated() ||
+           self.session.service().is_some() {
+            None
+        } else {
+            Some(self.login_deadline)
+        }
+    }
 }
 
 impl Session {
@@ -598,6 +792,171 @@ impl Session {
         &self.0.config
     }
 
+    fn is_authenticated(&self) -> bool {
+        if let Some(ref enc) = self.0.encrypted {
+            if let Some(EncryptedState::Authenticated) = enc.state {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn too_many_auth_failures(&self) -> bool {
+        if let Some(ref enc) = self.0.encrypted {
+            if let Some(EncryptedState::WaitingAuthRequest(ref auth_request)) = enc.state {
+                return auth_request.failures >= self.0.config.max_auth_attempts;
+            }
+        }
+        false
+    }
+
+    fn auth_limit_exceeded<S: Handler>(&mut self, server: &mut S, limit: auth::Limit) {
+        let description = match limit {
+            auth::Limit::MaxAuthAttempts => "Too many authentication failures",
+            auth::Limit::LoginGraceTime => "Authentication timeout",
+        };
+        self.0.disconnect(Disconnect::NoMoreAuthMethodsAvailable, description, "en");
+        server.auth_limit_exceeded(limit)
+    }
+
+    /// Call `Handler::service_started` if a custom service has just
+    /// started.
+    fn check_service_started<S: Handler>(&mut self,
+                                         server: &mut S,
+                                         was_running: bool)
+                                         -> Result<(), Error> {
+        if !was_running {
+            if let Some(service) = self.service().map(|s| s.to_string()) {
+                return server.service_started(&service, self);
+            }
+        }
+        Ok(())
+    }
+
+    /// Complete the authentication request for which the handler
+    /// returned `auth::Auth::Pending`, with the final decision. This
+    /// returns `Error::Inconsistent` if no request is pending.
+    pub fn complete_auth<S: Handler>(&mut self,
+                                     server: &mut S,
+                                     result: auth::Auth)
+                                     -> Result<(), Error> {
+        self.complete(server,
+                      &[auth::RequestKind::None,
+                        auth::RequestKind::Password,
+                        auth::RequestKind::PublicKeyProbe,
+                        auth::RequestKind::PublicKey,
+                        auth::RequestKind::Hostbased],
+                      result.into())
+    }
+
+    /// Complete a pending "password" request, which may also be asked
+    /// to change its password. This returns `Error::Inconsistent` if
+    /// no "password" request is pending.
+    pub fn complete_password_auth<S: Handler>(&mut self,
+                                              server: &mut S,
+                                              result: auth::Password)
+                                              -> Result<(), Error> {
+        self.complete(server, &[auth::RequestKind::Password], result)
+    }
+
+    /// Complete a pending "publickey" request, see
+    /// `complete_password_auth`.
+    pub fn complete_publickey_auth<S: Handler>(&mut self,
+                                               server: &mut S,
+                                               result: auth::Auth)
+                                               -> Result<(), Error> {
+        self.complete(server,
+                      &[auth::RequestKind::PublicKeyProbe, auth::RequestKind::PublicKey],
+                      result.into())
+    }
+
+    /// Complete a pending "hostbased" request, see
+    /// `complete_password_auth`.
+    pub fn complete_hostbased_auth<S: Handler>(&mut self,
+                                               server: &mut S,
+                                               result: auth::Auth)
+                                               -> Result<(), Error> {
+        self.complete(server, &[auth::RequestKind::Hostbased], result.into())
+    }
+
+    fn complete<S: Handler>(&mut self,
+                            server: &mut S,
+                            kinds: &[auth::RequestKind],
+                            result: auth::Password)
+                            -> Result<(), Error> {
+        if let Some(ref mut enc) = self.0.encrypted {
+            try!(enc.server_complete_auth(self.0.config.as_ref(),
+                                          &mut self.0.auth_user,
+                                          kinds,
+                                          result));
+            try!(enc.server_read_queued_requests(self.0.config.as_ref(),
+                                                 server,
+                                                 &mut self.0.auth_user));
+        } else {
+            return Err(Error::Inconsistent);
+        }
+        if self.too_many_auth_failures() {
+            self.auth_limit_exceeded(server, auth::Limit::MaxAuthAttempts);
+        }
+        try!(self.check_service_started(server, false));
+        self.flush();
+        Ok(())
+    }
+
+    /// Send an authentication banner to the client, for instance
+    /// while a decision is pending. Banners can only be sent during
+    /// authentication, hence this returns `false` and does nothing
+    /// before or after.
+    pub fn send_auth_banner(&mut self, banner: &str) -> bool {
+        if let Some(ref mut enc) = self.0.encrypted {
+            if let Some(EncryptedState::WaitingAuthRequest(_)) = enc.state {
+                encrypted::push_auth_banner(&mut enc.write, banner)
+            } else {
+                return false;
+            }
+        } else {
+            return false;
+        }
+        self.flush();
+        true
+    }
+
+    /// The name of the custom service running, if any (see
+    /// `Config::services`). Channels cannot be opened in this case.
+    pub fn service(&self) -> Option<&str> {
+        if let Some(ref enc) = self.0.encrypted {
+            enc.service()
+        } else {
+            None
+        }
+    }
+
+    /// Send a message of the custom service running, starting with
+    /// its message number, which must not be one of the transport
+    /// layer (below 50). Returns `false`, and sends nothing, if no
+    /// custom service is running or the message number is invalid.
+    pub fn send_service_message(&mut self, message: &[u8]) -> bool {
+        let sent = if let Some(ref mut enc) = self.0.encrypted {
+            enc.write_service_message(message)
+        } else {
+            false
+        };
+        if sent {
+            self.flush()
+        }
+        sent
+    }
+
+    /// Extensions sent by the client in `SSH_MSG_EXT_INFO`, if any
+    /// (see [RFC8308](https://tools.ietf.org/html/rfc8308)).
+    pub fn ext_info(&self) -> Option<&ExtInfo> {
+        if let Some(ref enc) = self.0.encrypted {
+            enc.ext_info.as_ref()
+        } else {
+            None
+        }
+    }
+
     /// Sends a disconnect message.
     pub fn disconnect(&mut self, reason: Disconnect, description: &str, language_tag: &str) {
         self.0.disconnect(reason, description, language_tag);
@@ -805,3 +1164,969 @@ impl Session {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use testing;
+    use backend::{Backend, Selected};
+    use cipher::{self, CipherT};
+    use compression::{self, Compress};
+    use sshbuffer::SSHBuffer;
+    use {auth, client, kex, key, msg, Error, Preferred, Service};
+    use super::{Config, Handler, Session};
+    use std;
+    use std::borrow::Cow;
+    use session::EncryptedState;
+    use cryptobuf::CryptoBuf;
+
+    #[test]
+    fn strict_kex_resets_sequence_numbers() {
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        // Versions and KEXINITs, then the client's ECDH_INIT, the
+        // server's ECDH_REPLY and NEWKEYS.
+        p.step().unwrap();
+        p.step().unwrap();
+        assert!(p.server_connection.session.0.strict_kex);
+        assert_eq!(p.server_connection.session.0.write_buffer.seqn, 0);
+        // The client's NEWKEYS and SERVICE_REQUEST.
+        p.step().unwrap();
+        assert_eq!(p.server_connection.read_buffer.seqn, 1);
+        p.authenticate();
+        p.echo(b"strict");
+    }
+
+    #[test]
+    fn strict_kex_rejects_packets_before_kexinit() {
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        // Versions, then the client's KEXINIT, preceded by an IGNORE.
+        p.step().unwrap();
+        p.client_step().unwrap();
+        let mut ignore = SSHBuffer::new();
+        cipher::Clear.write(&[msg::IGNORE, 0, 0, 0, 0], &mut ignore);
+        let kexinit = std::mem::replace(&mut p.to_server, ignore.buffer.to_vec());
+        p.to_server.extend(kexinit);
+        match p.server_step() {
+            Err(Error::StrictKex) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    fn compression_pair(name: compression::Name) -> testing::Pair<testing::Server, testing::Client> {
+        let mut config = client::Config::default();
+        config.preferred = Preferred {
+            compression: Cow::Owned(vec![name]),
+            ..Default::default()
+        };
+        let mut sconfig = Config::default();
+        sconfig.preferred = ::LEGACY;
+        testing::pair(sconfig, config, testing::client_key())
+    }
+
+    fn is_zlib(c: &Compress) -> bool {
+        if let Compress::Zlib(_) = *c { true } else { false }
+    }
+
+    #[test]
+    fn zlib_starts_after_kex() {
+        let mut p = compression_pair(compression::ZLIB);
+        p.run_until(|p| p.server_connection.session.0.encrypted.is_some());
+        assert!(is_zlib(&p.server_connection.session.0.cipher.compress.borrow()));
+        assert!(p.server_connection.session.0.cipher.decompress.borrow().is_enabled());
+        p.authenticate();
+        p.echo(&[b'z'; 90]);
+    }
+
+    #[test]
+    fn delayed_zlib_starts_after_authentication() {
+        let mut p = compression_pair(compression::ZLIB_DELAYED);
+        p.run_until(|p| p.server_connection.session.0.encrypted.is_some());
+        assert!(!is_zlib(&p.server_connection.session.0.cipher.compress.borrow()));
+        assert!(!p.server_connection.session.0.cipher.decompress.borrow().is_enabled());
+        p.authenticate();
+        assert!(is_zlib(&p.server_connection.session.0.cipher.compress.borrow()));
+        assert!(p.server_connection.session.0.cipher.decompress.borrow().is_enabled());
+        p.echo(&[b'z'; 90]);
+        p.echo(b"delayed");
+    }
+
+    /// A key pair claiming to be of algorithm `name`.
+    #[cfg(feature = "legacy")]
+    struct Named(key::Name);
+
+    #[cfg(feature = "legacy")]
+    impl key::KeyPair for Named {
+        fn name(&self) -> key::Name {
+            self.0
+        }
+        fn public_key(&self) -> Vec<u8> {
+            Vec::new()
+        }
+        fn sign(&self, _: &[u8]) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn host_key_algorithms_need_a_key() {
+        let mut config = Config::default();
+        config.preferred = ::MODERN;
+        config.keys.push(key::Algorithm::generate_keypair(key::ED448).unwrap());
+        assert_eq!(&config.kex_preferred().key[..], &[key::ED448]);
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn ed448_keys_and_curve448_kex() {
+        let mut sconf = Config::default();
+        sconf.preferred = ::MODERN;
+        sconf.preferred.set_kex("curve448-sha512").unwrap();
+        let mut cconf = client::Config::default();
+        cconf.preferred = ::MODERN;
+        let client_key = key::Algorithm::generate_keypair(key::ED448).unwrap();
+        let server_key = key::Algorithm::generate_keypair(key::ED448).unwrap();
+        let server = testing::Server { client_pubkey: client_key.clone_public_key() };
+        let client = testing::Client {
+            server_pk: server_key.clone_public_key(),
+            confirmed: Vec::new(),
+            received: Vec::new(),
+        };
+        let mut p = testing::Pair::new(server, sconf, server_key, client, cconf);
+        p.client_connection.set_auth_public_key(client_key);
+        p.authenticate();
+        p.echo(b"ed448");
+        match p.server_connection.session.0.encrypted.as_ref().map(|e| &e.kex) {
+            Some(&kex::Algorithm::Curve448(_)) => {}
+            _ => panic!("wrong kex"),
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn verify_only_algorithms_are_not_host_keys() {
+        let mut config = Config::default();
+        config.preferred = ::LEGACY;
+        config.keys.push(key::Algorithm::generate_keypair(key::ED25519).unwrap());
+        config.keys.push(key::Algorithm::Custom(std::sync::Arc::new(Named(key::DSS))));
+        assert_eq!(&config.kex_preferred().key[..], &[key::ED25519]);
+    }
+
+    /// An RSA key pair, smaller (and faster to generate) than those
+    /// of `generate_keypair`.
+    fn rsa_key() -> key::Algorithm {
+        key::Algorithm::rsa(Selected::rsa_keypair(2048).unwrap())
+    }
+
+    #[test]
+    fn rsa_host_keys_sign_with_rsa_sha2() {
+        let mut config = Config::default();
+        config.preferred.set_key("rsa-sha2-512,rsa-sha2-256").unwrap();
+        config.keys.push(rsa_key());
+        assert_eq!(&config.kex_preferred().key[..],
+                   &[key::RSA_SHA2_512, key::RSA_SHA2_256]);
+        let mut sconf = Config::default();
+        sconf.preferred = config.preferred.clone();
+        let mut cconf = client::Config::default();
+        cconf.preferred = config.preferred.clone();
+
+        let client_key = testing::client_key();
+        let server_key = rsa_key();
+        let server = testing::Server { client_pubkey: client_key.clone_public_key() };
+        let client = testing::Client {
+            server_pk: server_key.clone_public_key(),
+            confirmed: Vec::new(),
+            received: Vec::new(),
+        };
+        let mut p = testing::Pair::new(server, sconf, server_key, client, cconf);
+        p.client_connection.set_auth_public_key(client_key);
+        p.authenticate();
+        p.echo(b"rsa");
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn modern_profile_uses_the_hybrid_kex() {
+        let mut sconf = Config::default();
+        sconf.preferred = ::MODERN;
+        let mut cconf = client::Config::default();
+        cconf.preferred = ::MODERN;
+        let mut p = testing::pair(sconf, cconf, testing::client_key());
+        p.authenticate();
+        p.echo(b"modern");
+        match p.server_connection.session.0.encrypted.as_ref().map(|e| &e.kex) {
+            Some(&kex::Algorithm::MlKem768X25519(_)) => {}
+            _ => panic!("wrong kex"),
+        }
+    }
+
+    #[test]
+    fn fips_profile_uses_ecdsa_keys() {
+        for &(host, user) in &[(key::ECDSA_SHA2_NISTP256, key::ECDSA_SHA2_NISTP384),
+                               (key::ECDSA_SHA2_NISTP521, key::ECDSA_SHA2_NISTP256)] {
+            let mut sconf = Config::default();
+            sconf.preferred = ::FIPS;
+            let mut cconf = client::Config::default();
+            cconf.preferred = ::FIPS;
+            let client_key = key::Algorithm::generate_keypair(user).unwrap();
+            let server_key = key::Algorithm::generate_keypair(host).unwrap();
+            let server = testing::Server { client_pubkey: client_key.clone_public_key() };
+            let client = testing::Client {
+                server_pk: server_key.clone_public_key(),
+                confirmed: Vec::new(),
+                received: Vec::new(),
+            };
+            let mut p = testing::Pair::new(server, sconf, server_key, client, cconf);
+            p.client_connection.set_auth_public_key(client_key);
+            p.authenticate();
+            p.echo(b"fips");
+        }
+    }
+
+    #[test]
+    fn server_sig_algs_lists_the_auth_key_algorithms() {
+        let mut p = testing::pair(Default::default(), Default::default(), rsa_key());
+        p.authenticate();
+        let names: Vec<_> = key::AUTH_DEFAULT.iter().map(|n| n.as_ref().to_string()).collect();
+        assert_eq!(p.client_connection.session.ext_info().unwrap().server_sig_algs,
+                   Some(names));
+
+        // The client follows the server's list.
+        let mut config = Config::default();
+        config.auth_key_algorithms = Cow::Borrowed(&[key::RSA_SHA2_256]);
+        let mut p = testing::pair(config, Default::default(), rsa_key());
+        p.authenticate();
+        p.echo(b"rsa-sha2-256");
+    }
+
+    #[test]
+    fn auth_key_algorithms_are_enforced() {
+        let mut config = Config::default();
+        config.auth_key_algorithms = Cow::Borrowed(&[key::RSA_SHA2_512]);
+        let mut p = testing::pair(config, Default::default(), testing::client_key());
+        p.run_until(|p| !p.client_connection.has_auth_method());
+        assert!(!p.client_connection.session.is_authenticated());
+    }
+
+    /// A client answering keyboard-interactive prompts with `answers`.
+    struct Interactive {
+        server_pk: key::PublicKey,
+        answers: Vec<&'static str>,
+        prompts: Vec<String>,
+    }
+
+    impl client::Handler for Interactive {
+        fn check_server_key(&mut self,
+                            server_pk: &key::PublicKey)
+                            -> Result<client::KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(client::KeyCheck::Accept)
+        }
+        fn auth_keyboard_interactive(&mut self,
+                                     _: &str,
+                                     _: &str,
+                                     prompts: &[auth::Prompt])
+                                     -> Result<Vec<String>, Error> {
+            let mut responses = Vec::new();
+            for prompt in prompts {
+                self.prompts.push(prompt.prompt.to_string());
+                responses.push(self.answers.remove(0).to_string())
+            }
+            Ok(responses)
+        }
+    }
+
+    fn auth_failures<S: Handler, C: client::Handler>(p: &testing::Pair<S, C>) -> usize {
+        match p.server_connection.session.0.encrypted.as_ref().and_then(|e| e.state.as_ref()) {
+            Some(&EncryptedState::WaitingAuthRequest(ref a)) => a.failures,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn keyboard_interactive_rounds() {
+        for &(code, accepted) in &[("42", true), ("43", false)] {
+            let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+            let server = testing::Server { client_pubkey: testing::client_key().clone_public_key() };
+            let client = Interactive {
+                server_pk: server_key.clone_public_key(),
+                answers: vec!["pw", code],
+                prompts: Vec::new(),
+            };
+            let mut p = testing::Pair::new(server,
+                                           Default::default(),
+                                           server_key,
+                                           client,
+                                           Default::default());
+            p.client_connection.set_auth_keyboard_interactive("");
+            if accepted {
+                p.authenticate()
+            } else {
+                p.run_until(|p| auth_failures(p) > 0);
+                assert!(!p.client_connection.session.is_authenticated())
+            }
+            assert_eq!(p.client.prompts, vec!["Password: ", "Code: "]);
+        }
+    }
+
+    /// A server requiring public key `client_pubkey`, and then
+    /// password "pw".
+    struct TwoFactor {
+        client_pubkey: key::PublicKey,
+    }
+
+    impl Handler for TwoFactor {
+        fn auth_publickey(&mut self, _: &str, public_key: &key::PublicKey) -> auth::Auth {
+            if public_key == &self.client_pubkey {
+                auth::Auth::Partial { methods: auth::PASSWORD }
+            } else {
+                auth::Auth::Reject
+            }
+        }
+        fn auth_password(&mut self, _: &str, password: &str) -> auth::Password {
+            if password == "pw" {
+                auth::Password::Accept
+            } else {
+                auth::Password::Reject
+            }
+        }
+    }
+
+    #[test]
+    fn partial_success() {
+        for &user in &["pe", "other"] {
+            let client_key = testing::client_key();
+            let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+            let server = TwoFactor { client_pubkey: client_key.clone_public_key() };
+            let client = testing::Client {
+                server_pk: server_key.clone_public_key(),
+                confirmed: Vec::new(),
+                received: Vec::new(),
+            };
+            let mut p = testing::Pair::new(server,
+                                           Default::default(),
+                                           server_key,
+                                           client,
+                                           Default::default());
+            p.client_connection.set_auth_public_key(client_key);
+            p.run_until(|p| p.client_connection.is_partially_authenticated());
+            assert!(!p.client_connection.is_authenticated());
+            assert_eq!(p.client_connection.valid_auth_methods(), Some(auth::PASSWORD));
+
+            p.client_connection.set_auth_user(user);
+            p.client_connection.set_auth_password("pw".to_string());
+            if user == "pe" {
+                p.authenticate()
+            } else {
+                // The user cannot change after a partial success.
+                p.client_step().unwrap();
+                match p.server_step() {
+                    Err(Error::Inconsistent) => {}
+                    x => panic!("{:?}", x),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hostbased() {
+        for &(host_name, accepted) in &[("client.example.", true), ("other.example.", false)] {
+            let host_key = rsa_key();
+            let mut p = testing::pair(Default::default(),
+                                      Default::default(),
+                                      host_key.clone());
+            p.client_connection.set_auth_hostbased(host_key, host_name, "pe");
+            if accepted {
+                p.authenticate();
+                p.echo(b"hostbased")
+            } else {
+                p.run_until(|p| auth_failures(p) > 0);
+                assert!(!p.client_connection.session.is_authenticated())
+            }
+        }
+        // Keys other than the host key are rejected.
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        p.client_connection.set_auth_hostbased(testing::client_key(), "client.example.", "pe");
+        p.run_until(|p| auth_failures(p) > 0);
+        assert!(!p.client_connection.session.is_authenticated())
+    }
+
+    /// A server accepting user "guest" with the "none" method.
+    struct Guest;
+
+    impl Handler for Guest {
+        fn auth_none(&mut self, user: &str) -> auth::Auth {
+            if user == "guest" {
+                auth::Auth::Accept
+            } else {
+                auth::Auth::Reject
+            }
+        }
+    }
+
+    #[test]
+    fn none_requests_are_not_failures() {
+        let mut config = Config::default();
+        config.max_auth_attempts = 1;
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let client = testing::Client {
+            server_pk: server_key.clone_public_key(),
+            confirmed: Vec::new(),
+            received: Vec::new(),
+        };
+        let mut p = testing::Pair::new(Guest, config, server_key, client, Default::default());
+        for _ in 0..3 {
+            p.client_connection.set_auth_none();
+            p.run_until(|p| !p.client_connection.has_auth_method());
+            assert_eq!(auth_failures(&p), 0);
+        }
+        assert!(p.client_connection.valid_auth_methods().unwrap().contains(auth::PASSWORD));
+        p.client_connection.set_auth_user("guest");
+        p.client_connection.set_auth_none();
+        p.authenticate();
+    }
+
+    /// A server where user "pe" has the expired password "old", which
+    /// can only be changed to "new".
+    struct Expired;
+
+    impl Handler for Expired {
+        fn auth_password(&mut self, _: &str, password: &str) -> auth::Password {
+            if password == "old" {
+                auth::Password::ChangeRequest { prompt: "Expired".into() }
+            } else {
+                auth::Password::Reject
+            }
+        }
+        fn auth_password_change(&mut self, _: &str, old: &str, new: &str) -> auth::Password {
+            if old != "old" {
+                auth::Password::Reject
+            } else if new == "new" {
+                auth::Password::Accept
+            } else {
+                auth::Password::ChangeRequest { prompt: "Too weak".into() }
+            }
+        }
+    }
+
+    /// A client trying the new passwords of `passwords`, in order.
+    struct Changing {
+        server_pk: key::PublicKey,
+        passwords: Vec<&'static str>,
+        prompts: Vec<String>,
+    }
+
+    impl client::Handler for Changing {
+        fn check_server_key(&mut self,
+                            server_pk: &key::PublicKey)
+                            -> Result<client::KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(client::KeyCheck::Accept)
+        }
+        fn auth_password_change(&mut self,
+                                prompt: &str)
+                                -> Result<Option<(String, String)>, Error> {
+            self.prompts.push(prompt.to_string());
+            if self.passwords.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(("old".to_string(), self.passwords.remove(0).to_string())))
+            }
+        }
+    }
+
+    #[test]
+    fn password_change() {
+        for &(ref passwords, accepted) in &[(vec!["weak", "new"], true), (vec!["weak"], false)] {
+            let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+            let client = Changing {
+                server_pk: server_key.clone_public_key(),
+                passwords: passwords.clone(),
+                prompts: Vec::new(),
+            };
+            let mut p = testing::Pair::new(Expired,
+                                           Default::default(),
+                                           server_key,
+                                           client,
+                                           Default::default());
+            p.client_connection.set_auth_password("old".to_string());
+            if accepted {
+                p.authenticate();
+                assert_eq!(p.client.prompts, vec!["Expired", "Too weak"]);
+            } else {
+                // The client gives up after the second prompt.
+                p.run_until(|p| {
+                    p.client.prompts.len() == 2 && !p.client_connection.has_auth_method()
+                });
+                assert!(!p.client_connection.is_authenticated());
+            }
+        }
+    }
+
+    #[test]
+    fn max_auth_attempts() {
+        let mut config = Config::default();
+        config.max_auth_attempts = 2;
+        let mut p = testing::pair(config, Default::default(), testing::client_key());
+        // Probes with unknown keys are not attempts.
+        for _ in 0..3 {
+            p.client_connection.set_auth_public_key(testing::client_key());
+            p.run_until(|p| !p.client_connection.has_auth_method());
+        }
+        assert_eq!(auth_failures(&p), 0);
+        p.client_connection.set_auth_password("x".to_string());
+        p.run_until(|p| !p.client_connection.has_auth_method());
+        assert_eq!(auth_failures(&p), 1);
+
+        p.client_connection.set_auth_password("x".to_string());
+        p.client_step().unwrap();
+        p.server_step().unwrap();
+        match p.server_step() {
+            Err(Error::Disconnect) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn login_deadline() {
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        assert!(p.server_connection.login_deadline().is_some());
+        p.authenticate();
+        assert!(p.server_connection.login_deadline().is_none());
+
+        let mut config = Config::default();
+        config.login_grace_time = std::time::Duration::from_millis(0);
+        let mut p = testing::pair(config, Default::default(), testing::client_key());
+        p.step().unwrap();
+        assert!(p.server_connection.login_deadline().is_none());
+        match p.server_step() {
+            Err(Error::Disconnect) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn write_deadline_is_not_shortened() {
+        let mut p = testing::pair(Default::default(), Default::default(), testing::client_key());
+        p.client_connection.set_auth_password("x".to_string());
+        p.run_until(|p| !p.client_connection.has_auth_method());
+
+        let later = std::time::Instant::now() + std::time::Duration::from_secs(3600);
+        p.server_connection.session.0.encrypted.as_mut().unwrap().write_deadline = Some(later);
+        p.client_connection.set_auth_password("y".to_string());
+        for _ in 0..3 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.server_connection.write_deadline(), Some(later));
+        // The rejection is still held back.
+        assert!(p.client_connection.has_auth_method());
+        assert_eq!(auth_failures(&p), 2);
+    }
+
+    /// A server deferring all password and public key decisions, and
+    /// recording the passwords.
+    struct Deferred {
+        passwords: Vec<String>,
+    }
+
+    impl Handler for Deferred {
+        fn auth_password(&mut self, _: &str, password: &str) -> auth::Password {
+            self.passwords.push(password.to_string());
+            auth::Password::Pending
+        }
+        fn auth_publickey(&mut self, _: &str, _: &key::PublicKey) -> auth::Auth {
+            auth::Auth::Pending
+        }
+    }
+
+    fn is_pending<S: Handler, C: client::Handler>(p: &testing::Pair<S, C>) -> bool {
+        match p.server_connection.session.0.encrypted.as_ref().and_then(|e| e.state.as_ref()) {
+            Some(&EncryptedState::WaitingAuthRequest(ref a)) => {
+                match a.current {
+                    Some(auth::CurrentRequest::Pending { .. }) => true,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn deferred_pair() -> testing::Pair<Deferred, testing::Client> {
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let client = testing::Client {
+            server_pk: server_key.clone_public_key(),
+            confirmed: Vec::new(),
+            received: Vec::new(),
+        };
+        testing::Pair::new(Deferred { passwords: Vec::new() },
+                           Default::default(),
+                           server_key,
+                           client,
+                           Default::default())
+    }
+
+    #[test]
+    fn pending_decisions_are_typed() {
+        let mut p = deferred_pair();
+        p.client_connection.set_auth_public_key(testing::client_key());
+        p.run_until(|p| is_pending(p));
+        // The probe can only be completed as a public key request.
+        for result in &[auth::Password::Accept,
+                        auth::Password::ChangeRequest { prompt: "".into() }] {
+            match p.server_connection.session.complete_password_auth(&mut p.server,
+                                                                     result.clone()) {
+                Err(Error::Inconsistent) => {}
+                x => panic!("{:?}", x),
+            }
+        }
+        match p.server_connection.session.complete_hostbased_auth(&mut p.server,
+                                                                  auth::Auth::Accept) {
+            Err(Error::Inconsistent) => {}
+            x => panic!("{:?}", x),
+        }
+        p.server_connection
+            .session
+            .complete_publickey_auth(&mut p.server, auth::Auth::Accept)
+            .unwrap();
+        // The signed request is pending too.
+        p.run_until(|p| is_pending(p));
+        p.server_connection.session.complete_auth(&mut p.server, auth::Auth::Accept).unwrap();
+        p.authenticate();
+        match p.server_connection.session.complete_auth(&mut p.server, auth::Auth::Accept) {
+            Err(Error::Inconsistent) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn requests_are_queued_while_pending() {
+        let mut p = deferred_pair();
+        p.client_connection.set_auth_password("pw".to_string());
+        p.run_until(|p| is_pending(p));
+        // A second request, as sent by a client not waiting for the
+        // answer to the first one.
+        let mut request = CryptoBuf::new();
+        request.push(msg::USERAUTH_REQUEST);
+        request.extend_ssh_string(b"pe");
+        request.extend_ssh_string(b"ssh-connection");
+        request.extend_ssh_string(b"password");
+        request.push(0);
+        request.extend_ssh_string(b"pw2");
+        {
+            let session = &mut p.server_connection.session.0;
+            let enc = session.encrypted.as_mut().unwrap();
+            let auth_request = match enc.state.take() {
+                Some(EncryptedState::WaitingAuthRequest(a)) => a,
+                _ => unreachable!(),
+            };
+            enc.server_read_auth_request(&session.config,
+                                         &mut p.server,
+                                         &request,
+                                         &mut CryptoBuf::new(),
+                                         &mut session.auth_user,
+                                         auth_request)
+                .unwrap();
+        }
+        assert_eq!(p.server.passwords, vec!["pw"]);
+        assert!(is_pending(&p));
+
+        // The first request is answered, and the second one read.
+        p.server_connection
+            .session
+            .complete_password_auth(&mut p.server, auth::Password::Reject)
+            .unwrap();
+        assert_eq!(p.server.passwords, vec!["pw", "pw2"]);
+        assert!(is_pending(&p));
+        assert_eq!(auth_failures(&p), 1);
+        p.server_connection
+            .session
+            .complete_password_auth(&mut p.server, auth::Password::Accept)
+            .unwrap();
+        assert!(p.server_connection.session.is_authenticated());
+    }
+
+    /// A server greeting each user with a banner.
+    struct Banner;
+
+    impl Handler for Banner {
+        fn auth_banner(&mut self, user: Option<&str>) -> Option<Cow<'static, str>> {
+            user.map(|user| format!("Hello {}", user).into())
+        }
+    }
+
+    /// A client recording the banners it receives.
+    struct Banners {
+        server_pk: key::PublicKey,
+        banners: Vec<String>,
+    }
+
+    impl client::Handler for Banners {
+        fn check_server_key(&mut self,
+                            server_pk: &key::PublicKey)
+                            -> Result<client::KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(client::KeyCheck::Accept)
+        }
+        fn auth_banner(&mut self, banner: &str) {
+            self.banners.push(banner.to_string())
+        }
+    }
+
+    #[test]
+    fn auth_banners() {
+        let mut config = Config::default();
+        config.auth_banner = Some("Legal notice");
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let client = Banners {
+            server_pk: server_key.clone_public_key(),
+            banners: Vec::new(),
+        };
+        let mut p = testing::Pair::new(Banner, config, server_key, client, Default::default());
+        p.client_connection.set_auth_password("x".to_string());
+        p.run_until(|p| p.client.banners.len() == 2);
+        assert_eq!(p.client.banners, vec!["Legal notice", "Hello pe"]);
+        // The banner of a user is only sent once.
+        p.client_connection.set_auth_password("y".to_string());
+        p.run_until(|p| auth_failures(p) > 1);
+        p.client_step().unwrap();
+        assert_eq!(p.client.banners.len(), 2)
+    }
+
+    /// A server accepting the key of `testing::client_key`, and
+    /// echoing the messages of its custom services.
+    struct EchoService {
+        client_pubkey: key::PublicKey,
+        started: Vec<String>,
+    }
+
+    impl Handler for EchoService {
+        fn auth_publickey(&mut self, user: &str, public_key: &key::PublicKey) -> auth::Auth {
+            if user == "pe" && public_key == &self.client_pubkey {
+                auth::Auth::Accept
+            } else {
+                auth::Auth::Reject
+            }
+        }
+        fn service_started(&mut self, service: &str, _: &mut Session) -> Result<(), Error> {
+            self.started.push(service.to_string());
+            Ok(())
+        }
+        fn service_message(&mut self,
+                           _: &str,
+                           message: &[u8],
+                           session: &mut Session)
+                           -> Result<(), Error> {
+            assert!(session.send_service_message(message));
+            Ok(())
+        }
+    }
+
+    /// A client recording the messages of its custom service.
+    struct ServiceClient {
+        server_pk: key::PublicKey,
+        started: Vec<String>,
+        received: Vec<Vec<u8>>,
+    }
+
+    impl client::Handler for ServiceClient {
+        fn check_server_key(&mut self,
+                            server_pk: &key::PublicKey)
+                            -> Result<client::KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(client::KeyCheck::Accept)
+        }
+        fn service_started(&mut self, service: &str, _: &mut client::Session) -> Result<(), Error> {
+            self.started.push(service.to_string());
+            Ok(())
+        }
+        fn service_message(&mut self,
+                           _: &str,
+                           message: &[u8],
+                           _: &mut client::Session)
+                           -> Result<(), Error> {
+            self.received.push(message.to_vec());
+            Ok(())
+        }
+    }
+
+    /// A pair where the client requests `service`, and the server
+    /// offers `services`.
+    fn service_pair(service: Service,
+                    services: Vec<Service>)
+                    -> testing::Pair<EchoService, ServiceClient> {
+        let mut config = Config::default();
+        config.services = services;
+        let mut client_config = client::Config::default();
+        client_config.service = Some(service);
+        let client_key = testing::client_key();
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let server = EchoService {
+            client_pubkey: client_key.clone_public_key(),
+            started: Vec::new(),
+        };
+        let client = ServiceClient {
+            server_pk: server_key.clone_public_key(),
+            started: Vec::new(),
+            received: Vec::new(),
+        };
+        let mut p = testing::Pair::new(server, config, server_key, client, client_config);
+        p.client_connection.set_auth_public_key(client_key);
+        p
+    }
+
+    #[test]
+    fn custom_services() {
+        for &authenticated in &[false, true] {
+            let service = Service {
+                name: "echo@example.com".to_string(),
+                authenticated: authenticated,
+            };
+            let mut p = service_pair(service.clone(), vec![service]);
+            p.run_until(|p| !p.client.started.is_empty());
+            assert_eq!(p.client.started, vec!["echo@example.com"]);
+            assert_eq!(p.server.started, vec!["echo@example.com"]);
+            assert_eq!(p.server_connection.session.service(), Some("echo@example.com"));
+            // Neither channels nor transport layer messages are allowed.
+            let session = &mut p.client_connection.session;
+            assert!(!session.is_authenticated());
+            assert!(session.channel_open_session().is_none());
+            assert!(!session.send_service_message(&[msg::IGNORE]));
+            assert!(session.send_service_message(&[200, 1, 2]));
+            p.run_until(|p| !p.client.received.is_empty());
+            assert_eq!(p.client.received, vec![vec![200, 1, 2]]);
+        }
+    }
+
+    #[test]
+    fn unknown_service() {
+        let service = Service {
+            name: "echo@example.com".to_string(),
+            authenticated: true,
+        };
+        let mut p = service_pair(service, Vec::new());
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = p.step();
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Err(Error::Inconsistent) => {}
+            x => panic!("{:?}", x),
+        }
+        assert!(p.server.started.is_empty())
+    }
+
+    /// A client recording the forwarded channels opened by the
+    /// server, and rejecting those to port 9999.
+    struct Forwards {
+        server_pk: key::PublicKey,
+        opened: Vec<(u32, String, u32)>,
+    }
+
+    impl client::Handler for Forwards {
+        fn check_server_key(&mut self,
+                            server_pk: &key::PublicKey)
+                            -> Result<client::KeyCheck, Error> {
+            assert_eq!(&self.server_pk, server_pk);
+            Ok(client::KeyCheck::Accept)
+        }
+        fn channel_open_forwarded_tcpip(&mut self,
+                                        channel: u32,
+                                        connected_address: &str,
+                                        connected_port: u32,
+                                        originator_address: &str,
+                                        originator_port: u32,
+                                        _: &mut client::Session)
+                                        -> Result<bool, Error> {
+            assert_eq!((originator_address, originator_port), ("192.0.2.1", 5555));
+            self.opened.push((channel, connected_address.to_string(), connected_port));
+            Ok(connected_port != 9999)
+        }
+    }
+
+    /// An authenticated pair, where the client forwards port 8022 of
+    /// 127.0.0.1 and all ports of ::1.
+    fn forwards_pair() -> testing::Pair<testing::Server, Forwards> {
+        let client_key = testing::client_key();
+        let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+        let server = testing::Server { client_pubkey: client_key.clone_public_key() };
+        let client = Forwards {
+            server_pk: server_key.clone_public_key(),
+            opened: Vec::new(),
+        };
+        let mut p = testing::Pair::new(server,
+                                       Default::default(),
+                                       server_key,
+                                       client,
+                                       Default::default());
+        p.client_connection.set_auth_public_key(client_key);
+        p.authenticate();
+        p.client_connection.session.tcpip_forward(false, "127.0.0.1", 8022);
+        p.client_connection.session.tcpip_forward(false, "::1", 0);
+        p.step().unwrap();
+        p
+    }
+
+    /// Open a forwarded channel from the server, and return the
+    /// client's number for it, if the client accepted it.
+    fn open_forwarded(p: &mut testing::Pair<testing::Server, Forwards>,
+                      address: &str,
+                      port: u32)
+                      -> Option<u32> {
+        let n = p.client.opened.len();
+        p.server_connection
+            .session
+            .channel_open_forwarded_tcpip(address, port, "192.0.2.1", 5555)
+            .unwrap();
+        p.server_step().unwrap();
+        p.client_step().unwrap();
+        match p.client.opened.get(n) {
+            Some(&(channel, _, _)) if p.client_connection.session.channel_is_open(channel) => {
+                Some(channel)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn forwarded_tcpip() {
+        let mut p = forwards_pair();
+        // The handler is only called for the forwarded ports.
+        for &(address, port, called, accepted) in &[("127.0.0.1", 8022, true, true),
+                                                   ("127.0.0.1", 8023, false, false),
+                                                   ("::1", 9000, true, true),
+                                                   ("::1", 9999, true, false),
+                                                   ("192.0.2.2", 8022, false, false)] {
+            let n = p.client.opened.len();
+            assert_eq!(open_forwarded(&mut p, address, port).is_some(), accepted);
+            assert_eq!(p.client.opened.len() > n, called);
+        }
+        p.client_connection.session.cancel_tcpip_forward(false, "127.0.0.1", 8022);
+        p.step().unwrap();
+        assert!(open_forwarded(&mut p, "127.0.0.1", 8022).is_none());
+        assert!(open_forwarded(&mut p, "::1", 22).is_some())
+    }
+
+    #[test]
+    fn unknown_channel_requests() {
+        let mut p = forwards_pair();
+        let channel = open_forwarded(&mut p, "127.0.0.1", 8022).unwrap();
+        // Only the requests that want a reply get one, a failure.
+        for &want_reply in &[false, true] {
+            if let Some(ref mut enc) = p.server_connection.session.0.encrypted {
+                push_packet!(enc.write, {
+                    enc.write.push(msg::CHANNEL_REQUEST);
+                    enc.write.push_u32_be(channel);
+                    enc.write.extend_ssh_string(b"unknown@example.com");
+                    enc.write.push(want_reply as u8);
+                })
+            }
+            p.server_connection.session.flush();
+            p.server_step().unwrap();
+            p.client_step().unwrap();
+            assert_eq!(!p.to_server.is_empty(), want_reply);
+            p.server_step().unwrap();
+        }
+    }
+}
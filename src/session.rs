@@ -23,7 +23,7 @@ use msg;
 use key;
 use {Error, Channel, Disconnect};
 use cryptobuf::CryptoBuf;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use Limits;
 use sshbuffer::SSHBuffer;
 use byteorder::{BigEndian, ByteOrder};
@@ -37,14 +37,21 @@ pub struct Encrypted {
     pub exchange: Option<Exchange>, // It's always Some, except when we std::mem::replace it temporarily.
     pub kex: kex::Algorithm,
     pub key: usize,
-    pub mac: &'static str,
     pub session_id: kex::Digest,
     pub rekey: Option<Kex>,
     pub channels: HashMap<u32, Channel>,
+    /// Remote port forwardings requested by the client, as (address,
+    /// port) pairs.
+    pub tcpip_forwards: Vec<(String, u32)>,
     pub wants_reply: bool,
     pub write: CryptoBuf,
     pub write_cursor: usize,
     pub last_rekey: std::time::Instant,
+    /// Extensions received in `SSH_MSG_EXT_INFO`.
+    pub ext_info: Option<negociation::ExtInfo>,
+    /// Nothing is sent before this time, which is used to delay
+    /// authentication rejections without blocking.
+    pub write_deadline: Option<std::time::Instant>,
 }
 
 #[derive(Debug)]
@@ -53,11 +60,15 @@ pub struct CommonSession<Config> {
     pub config: Arc<Config>,
     pub encrypted: Option<Encrypted>,
     pub auth_method: Option<auth::Method<key::Algorithm>>,
+    /// Methods to try after `auth_method`.
+    pub auth_chain: VecDeque<auth::Authenticator<key::Algorithm>>,
     pub write_buffer: SSHBuffer,
     pub kex: Option<Kex>,
     pub cipher: cipher::CipherPair,
     pub wants_reply: bool,
     pub disconnected: bool,
+    /// Whether strict key exchange was negotiated during the initial key exchange.
+    pub strict_kex: bool,
 }
 
 impl<C> CommonSession<C> {
@@ -66,22 +77,31 @@ impl<C> CommonSession<C> {
             enc.exchange = Some(newkeys.exchange);
             enc.kex = newkeys.kex;
             enc.key = newkeys.key;
-            enc.mac = newkeys.names.mac;
             self.cipher = newkeys.cipher;
+            // Compression is restarted after each key exchange.
+            match enc.state {
+                Some(EncryptedState::Authenticated) |
+                Some(EncryptedState::Service { authenticated: true, .. }) => {
+                    self.cipher.start_delayed_compression()
+                }
+                _ => {}
+            }
         } else {
             self.encrypted = Some(Encrypted {
                 exchange: Some(newkeys.exchange),
                 kex: newkeys.kex,
                 key: newkeys.key,
-                mac: newkeys.names.mac,
                 session_id: newkeys.session_id,
                 state: Some(state),
                 rekey: None,
                 channels: HashMap::new(),
+                tcpip_forwards: Vec::new(),
                 wants_reply: false,
                 write: CryptoBuf::new(),
                 write_cursor: 0,
                 last_rekey: std::time::Instant::now(),
+                ext_info: None,
+                write_deadline: None,
             });
             self.cipher = newkeys.cipher;
         }
@@ -102,6 +122,32 @@ impl<C> CommonSession<C> {
         }
     }
 
+    /// With strict key exchange, only key exchange messages are
+    /// allowed before the first NEWKEYS. Disconnect otherwise.
+    pub fn check_strict_kex(&mut self, message: u8) -> Result<(), Error> {
+        if self.strict_kex && self.encrypted.is_none() &&
+           !(message == msg::KEXINIT || message == msg::NEWKEYS ||
+             (message >= msg::KEX_FIRST && message <= msg::KEX_LAST)) {
+            self.disconnect(Disconnect::ProtocolError, "Unexpected packet during strict key exchange", "en");
+            return Err(Error::StrictKex);
+        }
+        Ok(())
+    }
+
+    /// Called after reading the remote side's first KEXINIT. With
+    /// strict key exchange, it must be the first packet of the
+    /// connection.
+    pub fn set_strict_kex(&mut self, names: &negociation::Names, read_seqn: usize) -> Result<(), Error> {
+        if self.encrypted.is_none() && names.strict_kex {
+            self.strict_kex = true;
+            if read_seqn != 1 {
+                self.disconnect(Disconnect::ProtocolError, "KEXINIT was not the first packet", "en");
+                return Err(Error::StrictKex);
+            }
+        }
+        Ok(())
+    }
+
     pub fn byte(&mut self, channel: u32, msg: u8) {
         if let Some(ref mut enc) = self.encrypted {
             if let Some(channel) = enc.channels.get(&channel) {
@@ -115,6 +161,38 @@ impl<C> CommonSession<C> {
 }
 
 impl Encrypted {
+    /// Read an `SSH_MSG_EXT_INFO` packet. It is only allowed before
+    /// authentication is complete.
+    pub fn read_ext_info(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if let Some(EncryptedState::Authenticated) = self.state {
+            return Err(Error::Inconsistent);
+        }
+        let mut ext_info = self.ext_info.take().unwrap_or_default();
+        try!(ext_info.read(buf));
+        self.ext_info = Some(ext_info);
+        Ok(())
+    }
+
+    /// The name of the custom service running, if any.
+    pub fn service(&self) -> Option<&str> {
+        if let Some(EncryptedState::Service { ref name, .. }) = self.state {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Write a message of the custom service running, if any. The
+    /// message numbers of the transport layer are not allowed
+    /// ([RFC4251](https://tools.ietf.org/html/rfc4251#section-7)).
+    pub fn write_service_message(&mut self, message: &[u8]) -> bool {
+        if self.service().is_none() || message.is_empty() || message[0] < msg::USERAUTH_REQUEST {
+            return false;
+        }
+        push_packet!(self.write, self.write.extend(message));
+        true
+    }
+
     pub fn adjust_window_size(&mut self, channel: u32, data: &[u8], target: u32) {
         if let Some(ref mut channel) = self.channels.get_mut(&channel) {
             channel.sender_window_size -= data.len() as u32;
@@ -200,6 +278,11 @@ impl Encrypted {
                     debug!("flushing len {:?}", len);
                     let packet = &self.write[(self.write_cursor + 4)..(self.write_cursor + 4 + len)];
                     cipher.write(packet, write_buffer);
+                    if packet[0] == msg::USERAUTH_SUCCESS {
+                        // Only servers send this message, and start
+                        // delayed compression right after it.
+                        cipher.start_delayed_compression()
+                    }
                     self.write_cursor += 4 + len
                 }
             }
@@ -232,6 +315,8 @@ pub enum EncryptedState {
     WaitingServiceRequest,
     WaitingAuthRequest(auth::AuthRequest),
     Authenticated,
+    /// A custom service is running.
+    Service { name: String, authenticated: bool },
 }
 
 
@@ -273,6 +358,9 @@ pub struct KexInit {
     pub exchange: Exchange,
     pub session_id: Option<kex::Digest>,
     pub sent: bool,
+    /// Client only: the kex packet sent right after KEXINIT, guessing
+    /// the server's choice of algorithms.
+    pub guessed: Option<kex::Algorithm>,
 }
 
 
@@ -286,6 +374,7 @@ impl KexInit {
             algo: Some(algo),
             sent: false,
             session_id: Some(session_id.clone()),
+            guessed: None,
         };
         kexinit.exchange.client_kex_init.clear();
         kexinit.exchange.server_kex_init.clear();
@@ -300,6 +389,7 @@ impl KexInit {
             algo: None,
             sent: true,
             session_id: Some(session_id.clone()),
+            guessed: None,
         };
         kexinit.exchange.client_kex_init.clear();
         kexinit.exchange.server_kex_init.clear();
@@ -343,7 +433,7 @@ impl KexDhDone {
                                            &hash,
                                            buffer,
                                            buffer2,
-                                           self.names.cipher,
+                                           &self.names,
                                            is_server));
         Ok(NewKeys {
             exchange: self.exchange,
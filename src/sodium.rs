@@ -15,22 +15,26 @@
 
 // Some parts of this module come from sodiumoxide, (c) 2013 Daniel Ashhami, under an MIT licence.
 
-use super::libsodium_sys;
+// Typed wrappers around the primitives of the selected backend (see
+// the `backend` module).
+
+use backend::{Backend, Selected};
+use libc::{size_t, c_void};
+
 pub fn init() -> bool {
-    unsafe { libsodium_sys::sodium_init() != -1 }
+    Selected::init()
 }
 
 pub fn memcmp(x: &[u8], y: &[u8]) -> bool {
-    if x.len() != y.len() {
-        return false;
-    }
-    unsafe { libsodium_sys::sodium_memcmp(x.as_ptr(), y.as_ptr(), x.len()) == 0 }
+    Selected::memcmp(x, y)
+}
+
+pub unsafe fn sodium_mlock(p: *mut c_void, len: size_t) {
+    Selected::mlock(p as *mut u8, len)
 }
-use super::libc::{size_t, c_void};
 
-extern "C" {
-    pub fn sodium_mlock(p: *mut c_void, len: size_t);
-    pub fn sodium_munlock(p: *mut c_void, len: size_t);
+pub unsafe fn sodium_munlock(p: *mut c_void, len: size_t) {
+    Selected::munlock(p as *mut u8, len)
 }
 
 
@@ -79,53 +83,27 @@ macro_rules! clone (($newtype:ident) => (
 
 
 pub mod chacha20 {
-    use super::super::libc::{c_ulonglong, c_int};
-    use super::super::libsodium_sys;
-    pub const KEYBYTES: usize = libsodium_sys::crypto_stream_chacha20_KEYBYTES;
-    pub const NONCEBYTES: usize = libsodium_sys::crypto_stream_chacha20_NONCEBYTES;
+    use backend::{Backend, Selected};
+    pub const KEYBYTES: usize = 32;
+    pub const NONCEBYTES: usize = 8;
     use std;
     newtype!(Key,KEYBYTES);
     from_slice!(Key,KEYBYTES);
     newtype!(Nonce,NONCEBYTES);
     from_slice!(Nonce,NONCEBYTES);
 
-    pub fn stream_xor_inplace(m: &mut [u8], &Nonce(ref n): &Nonce, &Key(ref k): &Key) {
-        unsafe {
-            libsodium_sys::crypto_stream_chacha20_xor(m.as_mut_ptr(),
-                                                      m.as_ptr(),
-                                                      m.len() as c_ulonglong,
-                                                      n,
-                                                      k);
-        }
-    }
-
-    extern "C" {
-        fn crypto_stream_chacha20_xor_ic(c: *mut u8,
-                                         m: *mut u8,
-                                         mlen: c_ulonglong,
-                                         n: *const u8,
-                                         ic: u64,
-                                         k: *const u8)
-                                         -> c_int;
+    pub fn stream_xor_inplace(m: &mut [u8], nonce: &Nonce, key: &Key) {
+        Selected::chacha20_xor(m, &nonce.0, 0, &key.0)
     }
 
     pub fn xor_inplace(x: &mut [u8], nonce: &Nonce, ic: u64, key: &Key) {
-        unsafe {
-            let p = x.as_mut_ptr();
-            crypto_stream_chacha20_xor_ic(p,
-                                          p,
-                                          x.len() as c_ulonglong,
-                                          nonce.0.as_ptr(),
-                                          ic,
-                                          key.0.as_ptr());
-        }
+        Selected::chacha20_xor(x, &nonce.0, ic, &key.0)
     }
 }
 pub mod poly1305 {
-    use super::super::libsodium_sys;
-    use super::super::libc::c_ulonglong;
-    pub const KEYBYTES: usize = libsodium_sys::crypto_onetimeauth_poly1305_KEYBYTES;
-    pub const TAGBYTES: usize = libsodium_sys::crypto_onetimeauth_poly1305_BYTES;
+    use backend::{Backend, Selected};
+    pub const KEYBYTES: usize = 32;
+    pub const TAGBYTES: usize = 16;
     use std;
 
     newtype!(Key,KEYBYTES);
@@ -136,31 +114,23 @@ pub mod poly1305 {
     as_bytes!(Tag);
 
     pub fn authenticate(tag: &mut Tag, m: &[u8], k: &Key) {
-        unsafe {
-            libsodium_sys::crypto_onetimeauth_poly1305(&mut tag.0,
-                                                       m.as_ptr(),
-                                                       m.len() as c_ulonglong,
-                                                       &(k.0));
-        }
+        Selected::poly1305(&mut tag.0, m, &k.0)
     }
 }
 
 pub mod randombytes {
-    use super::super::libsodium_sys;
+    use backend::{Backend, Selected};
 
     pub fn into(buf: &mut [u8]) {
-        unsafe {
-            libsodium_sys::randombytes_buf(buf.as_mut_ptr(), buf.len());
-        }
+        Selected::randombytes(buf)
     }
 }
 
 
 pub mod sha256 {
-    use super::super::libsodium_sys;
-    use super::super::libc::c_ulonglong;
+    use backend::{Backend, Selected};
     use std;
-    pub const DIGESTBYTES: usize = libsodium_sys::crypto_hash_sha256_BYTES;
+    pub const DIGESTBYTES: usize = 32;
 
     newtype!(Digest, DIGESTBYTES);
     as_bytes!(Digest);
@@ -169,17 +139,15 @@ pub mod sha256 {
     new_blank!(Digest, DIGESTBYTES);
 
     pub fn hash(digest: &mut Digest, m: &[u8]) {
-        unsafe {
-            libsodium_sys::crypto_hash_sha256(&mut digest.0, m.as_ptr(), m.len() as c_ulonglong);
-        }
+        Selected::sha256(&mut digest.0, m)
     }
 }
 
 pub mod curve25519 {
-    use super::super::libsodium_sys;
+    use backend::{Backend, Selected};
 
-    pub const GROUPELEMENTBYTES: usize = libsodium_sys::crypto_scalarmult_curve25519_BYTES;
-    pub const SCALARBYTES: usize = libsodium_sys::crypto_scalarmult_curve25519_SCALARBYTES;
+    pub const GROUPELEMENTBYTES: usize = 32;
+    pub const SCALARBYTES: usize = 32;
 
     use std;
     newtype!(Scalar, SCALARBYTES);
@@ -193,26 +161,21 @@ pub mod curve25519 {
     pub fn scalarmult(q: &mut GroupElement,
                       &Scalar(ref n): &Scalar,
                       &GroupElement(ref p): &GroupElement) {
-        unsafe {
-            libsodium_sys::crypto_scalarmult_curve25519(&mut q.0, n, p);
-        }
+        Selected::curve25519(&mut q.0, n, p)
     }
 
     pub fn scalarmult_base(q: &mut GroupElement, &Scalar(ref n): &Scalar) {
-        unsafe {
-            libsodium_sys::crypto_scalarmult_curve25519_base(&mut q.0, n);
-        }
+        Selected::curve25519_base(&mut q.0, n)
     }
 }
 
 pub mod ed25519 {
-    use super::super::libsodium_sys;
-    use super::super::libc::c_ulonglong;
+    use backend::{Backend, Selected};
     use std;
 
-    pub const PUBLICKEYBYTES: usize = libsodium_sys::crypto_sign_ed25519_PUBLICKEYBYTES;
-    pub const SECRETKEYBYTES: usize = libsodium_sys::crypto_sign_ed25519_SECRETKEYBYTES;
-    pub const SIGNATUREBYTES: usize = libsodium_sys::crypto_sign_ed25519_BYTES;
+    pub const PUBLICKEYBYTES: usize = 32;
+    pub const SECRETKEYBYTES: usize = 64;
+    pub const SIGNATUREBYTES: usize = 64;
 
     #[derive(Debug, PartialEq, Eq)]
     pub struct PublicKey([u8; PUBLICKEYBYTES]);
@@ -233,40 +196,24 @@ pub mod ed25519 {
     clone!(SecretKey);
 
     pub fn generate_keypair() -> Option<(PublicKey, SecretKey)> {
-        unsafe {
-            let mut pk = [0; PUBLICKEYBYTES];
-            let mut sk = [0; SECRETKEYBYTES];
-            if libsodium_sys::crypto_sign_ed25519_keypair(&mut pk, &mut sk) == 0 {
-                Some((PublicKey(pk), SecretKey(sk)))
-            } else {
-                None
-            }
+        let mut pk = [0; PUBLICKEYBYTES];
+        let mut sk = [0; SECRETKEYBYTES];
+        if Selected::ed25519_keypair(&mut pk, &mut sk) {
+            Some((PublicKey(pk), SecretKey(sk)))
+        } else {
+            None
         }
     }
 
     pub fn sign_detached(signature: &mut Signature, m: &[u8], &SecretKey(ref sk): &SecretKey) {
-        unsafe {
-            let mut siglen: c_ulonglong = 0;
-            libsodium_sys::crypto_sign_ed25519_detached(&mut signature.0,
-                                                        &mut siglen,
-                                                        m.as_ptr(),
-                                                        m.len() as c_ulonglong,
-                                                        sk);
-            assert_eq!(siglen, SIGNATUREBYTES as c_ulonglong);
-        }
+        Selected::ed25519_sign(&mut signature.0, m, sk)
     }
 
     pub fn verify_detached(signature: &Signature,
                            m: &[u8],
                            &PublicKey(ref pk): &PublicKey)
                            -> bool {
-        unsafe {
-            let ret = libsodium_sys::crypto_sign_ed25519_verify_detached(&signature.0,
-                                                                         m.as_ptr(),
-                                                                         m.len() as c_ulonglong,
-                                                                         pk);
-            ret == 0
-        }
+        Selected::ed25519_verify(&signature.0, m, pk)
     }
 
 }
@@ -17,11 +17,15 @@ use std;
 use super::*;
 use std::io::BufRead;
 
+/// A buffer of packets read from or written to the network, along
+/// with the state of the packet stream.
 #[derive(Debug)]
 pub struct SSHBuffer {
     pub buffer: CryptoBuf,
     pub len: usize, // next packet length.
+    /// Total number of bytes read or written.
     pub bytes: usize,
+    /// Sequence number of the next packet.
     pub seqn: usize,
 }
 impl SSHBuffer {
@@ -0,0 +1,246 @@
+// Copyright 2016 Pierre-Étienne Meunier
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A client and a server connected in memory, for the tests of this
+//! crate.
+
+use std::sync::Arc;
+use {auth, client, key, server, CryptoBuf, Error};
+
+/// A server accepting user "pe" with password "pw" or public key
+/// `client_pubkey`, and echoing the data it receives. With
+/// keyboard-interactive, it asks for the password and then for code
+/// "42", in two rounds. Hostbased requests are accepted from user
+/// "pe" of host "client.example.", with host key `client_pubkey`.
+pub struct Server {
+    pub client_pubkey: key::PublicKey,
+}
+
+impl server::Handler for Server {
+    fn auth_publickey(&mut self, user: &str, public_key: &key::PublicKey) -> auth::Auth {
+        if user == "pe" && public_key == &self.client_pubkey {
+            auth::Auth::Accept
+        } else {
+            auth::Auth::Reject
+        }
+    }
+    fn auth_password(&mut self, user: &str, password: &str) -> auth::Password {
+        if user == "pe" && password == "pw" {
+            auth::Password::Accept
+        } else {
+            auth::Password::Reject
+        }
+    }
+    fn auth_hostbased(&mut self,
+                      user: &str,
+                      host_name: &str,
+                      client_user: &str,
+                      public_key: &key::PublicKey)
+                      -> auth::Auth {
+        if user == "pe" && host_name == "client.example." && client_user == "pe" &&
+           public_key == &self.client_pubkey {
+            auth::Auth::Accept
+        } else {
+            auth::Auth::Reject
+        }
+    }
+    fn auth_keyboard_interactive(&mut self,
+                                 user: &str,
+                                 _: &str,
+                                 response: Option<&[&str]>)
+                                 -> auth::KeyboardInteractive {
+        let prompt = match response {
+            None => "Password: ",
+            Some(r) if r == &["pw"][..] => "Code: ",
+            Some(r) if r == &["42"][..] && user == "pe" => {
+                return auth::KeyboardInteractive::Accept
+            }
+            _ => return auth::KeyboardInteractive::Reject,
+        };
+        auth::KeyboardInteractive::Prompts {
+            name: "".into(),
+            instructions: "".into(),
+            prompts: vec![auth::Prompt {
+                              prompt: prompt.into(),
+                              echo: false,
+                          }],
+        }
+    }
+    fn data(&mut self,
+            channel: u32,
+            data: &[u8],
+            session: &mut server::Session)
+            -> Result<(), Error> {
+        try!(session.data(channel, None, data));
+        Ok(())
+    }
+}
+
+/// A client accepting server key `server_pk`, and recording the
+/// channels confirmed and the data received.
+pub struct Client {
+    pub server_pk: key::PublicKey,
+    pub confirmed: Vec<u32>,
+    pub received: Vec<u8>,
+}
+
+impl client::Handler for Client {
+    fn check_server_key(&mut self, server_pk: &key::PublicKey) -> Result<client::KeyCheck, Error> {
+        Ok(if &self.server_pk == server_pk {
+            client::KeyCheck::Accept
+        } else {
+            client::KeyCheck::Reject
+        })
+    }
+    fn channel_open_confirmation(&mut self,
+                                 channel: u32,
+                                 _: &mut client::Session)
+                                 -> Result<(), Error> {
+        self.confirmed.push(channel);
+        Ok(())
+    }
+    fn data(&mut self,
+            _: u32,
+            _: Option<u32>,
+            data: &[u8],
+            _: &mut client::Session)
+            -> Result<(), Error> {
+        self.received.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A client and a server, and the bytes in flight between them.
+pub struct Pair<S, C> {
+    pub server: S,
+    pub client: C,
+    pub server_connection: server::Connection,
+    pub client_connection: client::Connection,
+    /// Bytes written by the client, not yet read by the server.
+    pub to_server: Vec<u8>,
+    /// Bytes written by the server, not yet read by the client.
+    pub to_client: Vec<u8>,
+    buffer0: CryptoBuf,
+    buffer1: CryptoBuf,
+}
+
+/// The client key of `pair`, an ed25519 key.
+pub fn client_key() -> key::Algorithm {
+    key::Algorithm::generate_keypair(key::ED25519).unwrap()
+}
+
+/// A `Server` and a `Client` for user "pe", authenticating with
+/// public key `client_key`.
+pub fn pair(server_config: server::Config,
+            client_config: client::Config,
+            client_key: key::Algorithm)
+            -> Pair<Server, Client> {
+    let server_key = key::Algorithm::generate_keypair(key::ED25519).unwrap();
+    let server = Server { client_pubkey: client_key.clone_public_key() };
+    let client = Client {
+        server_pk: server_key.clone_public_key(),
+        confirmed: Vec::new(),
+        received: Vec::new(),
+    };
+    let mut p = Pair::new(server, server_config, server_key, client, client_config);
+    p.client_connection.set_auth_public_key(client_key);
+    p
+}
+
+impl<S: server::Handler, C: client::Handler> Pair<S, C> {
+    /// Connect `client` to `server`, with host key `server_key`. The
+    /// client authenticates as user "pe", with the "none" method
+    /// until another one is set. Rejections are not delayed.
+    pub fn new(server: S,
+               server_config: server::Config,
+               server_key: key::Algorithm,
+               client: C,
+               client_config: client::Config)
+               -> Self {
+        let mut server_config = server_config;
+        server_config.keys.push(server_key);
+        server_config.auth_rejection_time = ::std::time::Duration::from_millis(0);
+        let mut client_connection = client::Connection::new(Arc::new(client_config));
+        client_connection.set_auth_user("pe");
+        Pair {
+            server: server,
+            client: client,
+            server_connection: server::Connection::new(Arc::new(server_config)),
+            client_connection: client_connection,
+            to_server: Vec::new(),
+            to_client: Vec::new(),
+            buffer0: CryptoBuf::new(),
+            buffer1: CryptoBuf::new(),
+        }
+    }
+
+    /// Let the client read and write, then the server.
+    pub fn step(&mut self) -> Result<(), Error> {
+        try!(self.client_step());
+        self.server_step()
+    }
+
+    /// Let the client read what the server wrote, and write.
+    pub fn client_step(&mut self) -> Result<(), Error> {
+        {
+            let mut s = &self.to_client[..];
+            try!(self.client_connection
+                .read(&mut self.client, &mut s, &mut self.buffer0, &mut self.buffer1));
+        }
+        self.to_client.clear();
+        try!(self.client_connection.write(&mut self.to_server));
+        Ok(())
+    }
+
+    /// Let the server read what the client wrote, and write.
+    pub fn server_step(&mut self) -> Result<(), Error> {
+        {
+            let mut s = &self.to_server[..];
+            try!(self.server_connection
+                .read(&mut self.server, &mut s, &mut self.buffer0, &mut self.buffer1));
+        }
+        self.to_server.clear();
+        try!(self.server_connection.write(&mut self.to_client));
+        Ok(())
+    }
+
+    /// Step until `f` returns `true`, panicking after 100 steps.
+    pub fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, mut f: F) {
+        let mut n = 0;
+        while !f(self) {
+            self.step().unwrap();
+            n += 1;
+            assert!(n < 100, "condition not reached");
+        }
+    }
+
+    pub fn authenticate(&mut self) {
+        self.run_until(|p| p.client_connection.session.is_authenticated())
+    }
+}
+
+impl<S: server::Handler> Pair<S, Client> {
+    /// Open a session channel, send `data` and check that it is
+    /// echoed back. `data` must fit in the server's window (100 bytes
+    /// by default).
+    pub fn echo(&mut self, data: &[u8]) {
+        let channel = self.client_connection.session.channel_open_session().unwrap();
+        self.run_until(|p| p.client.confirmed.contains(&channel));
+        self.client_connection.session.data(channel, None, data).unwrap();
+        self.run_until(|p| p.client.received.len() >= data.len());
+        assert_eq!(&self.client.received[..], data);
+        self.client.received.clear();
+    }
+}